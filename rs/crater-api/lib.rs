@@ -1,16 +1,344 @@
 extern crate rustc_serialize;
 
+use rustc_serialize::{Decodable, Decoder, Encodable, Encoder};
+use std::error::Error as StdError;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+/// A Rust toolchain. Release toolchains are named like multirust
+/// toolchain specs, e.g. `nightly-2015-06-06`; custom builds by the
+/// 40-character commit sha they were built from, optionally prefixed
+/// by the repo and a '#'.
+///
+/// Serializes to and from the same string form.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Toolchain {
+    Dist {
+        channel: Channel,
+        /// The archive date, as `YYYY-MM-DD`
+        date: String
+    },
+    Custom {
+        sha: String,
+        repo: Option<String>
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Channel {
+    Nightly,
+    Beta,
+    Stable
+}
+
+/// How a build task ended. `Unknown` covers results recorded before
+/// the status was known, e.g. by old versions of monitor.js.
+///
+/// Serializes to and from the lowercase name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BuildStatus {
+    Success,
+    Failure,
+    /// The task didn't run to completion, e.g. because of an
+    /// infrastructure problem
+    Exception,
+    Unknown
+}
+
+/// What a user may do. Each role can do everything the ones before it
+/// can.
+///
+/// Serializes to and from the lowercase name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Role {
+    /// Reads reports and experiments
+    Reporter,
+    /// Schedules builds, spending TaskCluster capacity
+    Scheduler,
+    /// Manages users and syncs the crates.io index
+    Admin
+}
+
+/// Something notable in a build log
+#[derive(RustcEncodable, RustcDecodable)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Diagnostic {
+    /// A compiler error with a code, e.g. `E0277`
+    ErrorCode(String),
+    /// An error without a code, by its message
+    Error(String),
+    /// An internal compiler error, by its panic message
+    Ice(String),
+    /// Any other panic, e.g. from a build script, by its message
+    Panic(String)
+}
+
+#[derive(Debug)]
+pub struct ParseToolchainError(String);
+
+#[derive(Debug)]
+pub struct ParseBuildStatusError(String);
+
+#[derive(Debug)]
+pub struct ParseRoleError(String);
+
+impl Toolchain {
+    /// A custom toolchain, checking that `sha` is a full commit sha
+    pub fn custom(sha: &str, repo: Option<&str>) -> Result<Toolchain, ParseToolchainError> {
+        if !is_commit_sha(sha) {
+            return Err(ParseToolchainError(String::from(sha)));
+        }
+
+        Ok(Toolchain::Custom {
+            sha: String::from(sha),
+            repo: repo.map(String::from)
+        })
+    }
+
+    /// How results are keyed in the database. Custom toolchains are
+    /// keyed by the sha alone, as monitor.js does, so results don't
+    /// depend on which repo the commit was fetched from.
+    pub fn key(&self) -> String {
+        match *self {
+            Toolchain::Custom { ref sha, .. } => sha.clone(),
+            ref t => t.to_string()
+        }
+    }
+}
+
+impl FromStr for Toolchain {
+    type Err = ParseToolchainError;
+
+    fn from_str(s: &str) -> Result<Toolchain, ParseToolchainError> {
+        if let Some(i) = s.rfind('#') {
+            return Toolchain::custom(&s[i + 1..], Some(&s[..i]))
+                .map_err(|_| ParseToolchainError(String::from(s)));
+        }
+
+        for &channel in &[Channel::Nightly, Channel::Beta, Channel::Stable] {
+            let prefix = format!("{}-", channel);
+            if s.starts_with(&prefix) {
+                let date = &s[prefix.len()..];
+                if !is_archive_date(date) {
+                    return Err(ParseToolchainError(String::from(s)));
+                }
+                return Ok(Toolchain::Dist {
+                    channel: channel,
+                    date: String::from(date)
+                });
+            }
+        }
+
+        Toolchain::custom(s, None)
+    }
+}
+
+impl Display for Toolchain {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            Toolchain::Dist { channel, ref date } => write!(f, "{}-{}", channel, date),
+            Toolchain::Custom { ref sha, repo: None } => f.write_str(sha),
+            Toolchain::Custom { ref sha, repo: Some(ref repo) } => write!(f, "{}#{}", repo, sha)
+        }
+    }
+}
+
+impl Encodable for Toolchain {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        s.emit_str(&self.to_string())
+    }
+}
+
+impl Decodable for Toolchain {
+    fn decode<D: Decoder>(d: &mut D) -> Result<Toolchain, D::Error> {
+        let s = try!(d.read_str());
+        s.parse().map_err(|e: ParseToolchainError| d.error(&e.to_string()))
+    }
+}
+
+impl Display for Channel {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        f.write_str(match *self {
+            Channel::Nightly => "nightly",
+            Channel::Beta => "beta",
+            Channel::Stable => "stable"
+        })
+    }
+}
+
+impl BuildStatus {
+    pub fn all() -> &'static [BuildStatus] {
+        static ALL: [BuildStatus; 4] = [BuildStatus::Success, BuildStatus::Failure,
+                                        BuildStatus::Exception, BuildStatus::Unknown];
+        &ALL
+    }
+}
+
+impl FromStr for BuildStatus {
+    type Err = ParseBuildStatusError;
+
+    fn from_str(s: &str) -> Result<BuildStatus, ParseBuildStatusError> {
+        BuildStatus::all().iter()
+            .find(|status| status.to_string() == s)
+            .cloned()
+            .ok_or_else(|| ParseBuildStatusError(String::from(s)))
+    }
+}
+
+impl Display for BuildStatus {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        f.write_str(match *self {
+            BuildStatus::Success => "success",
+            BuildStatus::Failure => "failure",
+            BuildStatus::Exception => "exception",
+            BuildStatus::Unknown => "unknown"
+        })
+    }
+}
+
+impl Encodable for BuildStatus {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        s.emit_str(&self.to_string())
+    }
+}
+
+impl Decodable for BuildStatus {
+    fn decode<D: Decoder>(d: &mut D) -> Result<BuildStatus, D::Error> {
+        let s = try!(d.read_str());
+        s.parse().map_err(|e: ParseBuildStatusError| d.error(&e.to_string()))
+    }
+}
+
+impl Role {
+    pub fn all() -> &'static [Role] {
+        static ALL: [Role; 3] = [Role::Reporter, Role::Scheduler, Role::Admin];
+        &ALL
+    }
+}
+
+impl FromStr for Role {
+    type Err = ParseRoleError;
+
+    fn from_str(s: &str) -> Result<Role, ParseRoleError> {
+        Role::all().iter()
+            .find(|role| role.to_string() == s)
+            .cloned()
+            .ok_or_else(|| ParseRoleError(String::from(s)))
+    }
+}
+
+impl Display for Role {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        f.write_str(match *self {
+            Role::Reporter => "reporter",
+            Role::Scheduler => "scheduler",
+            Role::Admin => "admin"
+        })
+    }
+}
+
+impl Encodable for Role {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        s.emit_str(&self.to_string())
+    }
+}
+
+impl Decodable for Role {
+    fn decode<D: Decoder>(d: &mut D) -> Result<Role, D::Error> {
+        let s = try!(d.read_str());
+        s.parse().map_err(|e: ParseRoleError| d.error(&e.to_string()))
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            Diagnostic::ErrorCode(ref code) => write!(f, "error[{}]", code),
+            Diagnostic::Error(ref msg) => write!(f, "error: {}", msg),
+            Diagnostic::Ice(ref msg) => write!(f, "internal compiler error: {}", msg),
+            Diagnostic::Panic(ref msg) => write!(f, "panic: {}", msg)
+        }
+    }
+}
+
+impl StdError for ParseBuildStatusError {
+    fn description(&self) -> &str {
+        "invalid build status"
+    }
+}
+
+impl Display for ParseBuildStatusError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        write!(f, "invalid build status `{}`", self.0)
+    }
+}
+
+impl StdError for ParseRoleError {
+    fn description(&self) -> &str {
+        "invalid role"
+    }
+}
+
+impl Display for ParseRoleError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        write!(f, "invalid role `{}`, expected reporter, scheduler or admin", self.0)
+    }
+}
+
+impl StdError for ParseToolchainError {
+    fn description(&self) -> &str {
+        "invalid toolchain"
+    }
+}
+
+impl Display for ParseToolchainError {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        write!(f, "invalid toolchain `{}`, expected e.g. `nightly-2015-06-06` \
+                   or a 40-character commit sha", self.0)
+    }
+}
+
+/// Whether `name` can name a user or experiment: up to 64 ASCII
+/// letters, digits, `-`, `_` and `.`, not starting with `.`. These
+/// are safe to put in URL paths as they are.
+pub fn is_valid_name(name: &str) -> bool {
+    name.len() > 0 && name.len() <= 64 && !name.starts_with(".") && name.chars().all(|c| {
+        match c {
+            'a'...'z' | 'A'...'Z' | '0'...'9' | '-' | '_' | '.' => true,
+            _ => false
+        }
+    })
+}
+
+fn is_commit_sha(s: &str) -> bool {
+    s.len() == 40 && s.chars().all(|c| c.is_digit(16))
+}
+
+fn is_archive_date(s: &str) -> bool {
+    s.len() == 10 && s.char_indices().all(|(i, c)| {
+        if i == 4 || i == 7 { c == '-' } else { c.is_digit(10) }
+    })
+}
+
 pub mod v1 {
     use std::error::Error as StdError;
     use std::fmt::{self, Display, Formatter};
+    use {BuildStatus, Diagnostic, Role, Toolchain};
 
+    /// Credentials, sent in every v1 request. Debug output leaves out
+    /// the token.
     #[derive(RustcEncodable, RustcDecodable)]
-    #[derive(Debug)]
     pub struct Auth {
         pub name: String,
         pub token: String
     }
 
+    impl fmt::Debug for Auth {
+        fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+            write!(f, "Auth {{ name: {:?}, token: <redacted> }}", self.name)
+        }
+    }
+
     #[derive(RustcEncodable, RustcDecodable)]
     #[derive(Debug)]
     pub struct SelfTestRequest {
@@ -26,11 +354,69 @@ pub mod v1 {
         pub commit_sha: String
     }
     
+    /// Build crates from the index with a toolchain
     #[derive(RustcEncodable, RustcDecodable)]
     #[derive(Debug)]
     pub struct CrateBuildRequest {
         pub auth: Auth,
-        pub toolchain: String
+        pub toolchain: Toolchain,
+        /// Left out by clients from before it existed, which get the
+        /// default
+        pub selection: Option<CrateSelection>
+    }
+
+    /// Narrows down the crates to build. With the default the most
+    /// recent version of every crate in the index is built.
+    #[derive(RustcEncodable, RustcDecodable)]
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct CrateSelection {
+        /// Only the crates with the most reverse dependencies, keeping
+        /// every version of each
+        pub top: Option<u64>,
+        /// Only the most recent version of each crate
+        pub most_recent_only: bool,
+        /// Only crates with this name
+        pub name: Option<String>,
+        /// Skip crate versions that already have a success or failure
+        /// result for the toolchain
+        pub skip_existing: bool
+    }
+
+    /// Building every version multiplies the cost, so it has to be
+    /// asked for
+    impl Default for CrateSelection {
+        fn default() -> CrateSelection {
+            CrateSelection {
+                top: None,
+                most_recent_only: true,
+                name: None,
+                skip_existing: false
+            }
+        }
+    }
+
+    /// Tasks are submitted in the background after this is returned
+    #[derive(RustcEncodable, RustcDecodable)]
+    #[derive(Debug)]
+    pub struct CrateBuildResponse {
+        pub experiment_id: String,
+        /// The number of crate builds being scheduled
+        pub jobs: u64
+    }
+
+    /// Reload the crate tables in the database from the crates.io index
+    #[derive(RustcEncodable, RustcDecodable)]
+    #[derive(Debug)]
+    pub struct SyncIndexRequest {
+        pub auth: Auth
+    }
+
+    #[derive(RustcEncodable, RustcDecodable)]
+    #[derive(Debug)]
+    pub struct SyncIndexResponse {
+        pub crate_versions: u64,
+        pub dep_edges: u64,
+        pub ranked_crates: u64
     }
 
     #[derive(RustcEncodable, RustcDecodable)]
@@ -44,10 +430,307 @@ pub mod v1 {
     #[derive(Debug)]
     pub enum ReportKind {
         Comparison {
-            toolchain_from: String,
-            toolchain_to: String
+            toolchain_from: Toolchain,
+            toolchain_to: Toolchain
         },
-        Toolchain(String)
+        Toolchain(Toolchain),
+        Ices {
+            toolchain: Toolchain
+        }
+    }
+
+    /// The result of comparing the builds of every crate under two
+    /// toolchains
+    #[derive(RustcEncodable, RustcDecodable)]
+    #[derive(Debug)]
+    pub struct ComparisonReport {
+        pub toolchain_from: Toolchain,
+        pub toolchain_to: Toolchain,
+        pub summary: StatusSummary,
+        pub crates: Vec<CrateComparison>,
+        /// Regressed crates that don't depend on any other regressed
+        /// crate
+        pub root_regressions: Vec<String>,
+        /// Regressed crates that depend, possibly transitively, on
+        /// another regressed crate
+        pub dependent_regressions: Vec<DependentRegression>,
+        /// Regressed crates grouped by the new error or ICE they hit,
+        /// largest group first
+        pub regression_groups: Vec<RegressionGroup>
+    }
+
+    /// The internal compiler errors hit while building crates with one
+    /// toolchain
+    #[derive(RustcEncodable, RustcDecodable)]
+    #[derive(Debug)]
+    pub struct IceReport {
+        pub toolchain: Toolchain,
+        /// Most widespread first
+        pub ices: Vec<Ice>
+    }
+
+    /// One ICE, identified by where rustc panicked or, failing that,
+    /// its message
+    #[derive(RustcEncodable, RustcDecodable)]
+    #[derive(Debug, PartialEq)]
+    pub struct Ice {
+        pub message: String,
+        pub location: Option<String>,
+        /// Most popular first
+        pub crates: Vec<IceCrate>
+    }
+
+    #[derive(RustcEncodable, RustcDecodable)]
+    #[derive(Debug, PartialEq)]
+    pub struct IceCrate {
+        pub crate_name: String,
+        pub crate_vers: String,
+        pub task_id: String
+    }
+
+    #[derive(RustcEncodable, RustcDecodable)]
+    #[derive(Debug, PartialEq)]
+    pub struct RegressionGroup {
+        pub diagnostic: Diagnostic,
+        pub crates: Vec<RegressedCrate>
+    }
+
+    #[derive(RustcEncodable, RustcDecodable)]
+    #[derive(Debug, PartialEq)]
+    pub struct RegressedCrate {
+        pub crate_name: String,
+        pub crate_vers: String
+    }
+
+    #[derive(RustcEncodable, RustcDecodable)]
+    #[derive(Debug, PartialEq)]
+    pub struct DependentRegression {
+        pub crate_name: String,
+        /// A regressed crate in the dependency graph of `crate_name`
+        pub regressed_dep: String
+    }
+
+    #[derive(RustcEncodable, RustcDecodable)]
+    #[derive(Debug, Default)]
+    pub struct StatusSummary {
+        pub working: u64,
+        pub broken: u64,
+        pub regressed: u64,
+        pub fixed: u64,
+        pub exception: u64,
+        pub unknown: u64
+    }
+
+    #[derive(RustcEncodable, RustcDecodable)]
+    #[derive(Debug)]
+    pub struct CrateComparison {
+        pub crate_name: String,
+        pub crate_vers: String,
+        pub status: ComparisonStatus,
+        pub from: TaskResult,
+        pub to: TaskResult
+    }
+
+    /// The outcome of a single build task
+    #[derive(RustcEncodable, RustcDecodable)]
+    #[derive(Debug)]
+    pub struct TaskResult {
+        pub status: BuildStatus,
+        pub task_id: String
+    }
+
+    #[derive(RustcEncodable, RustcDecodable)]
+    #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+    pub enum ComparisonStatus {
+        /// Built with both toolchains
+        Working,
+        /// Failed with both toolchains
+        Broken,
+        /// Built with the 'from' toolchain but not the 'to' toolchain
+        Regressed,
+        /// Failed with the 'from' toolchain but built with the 'to' toolchain
+        Fixed,
+        /// One of the builds ended in an exception, so there's nothing
+        /// to compare
+        Exception,
+        /// One of the builds has no known status
+        Unknown
+    }
+
+    /// Starts tracking a named run comparing two toolchains
+    #[derive(RustcEncodable, RustcDecodable)]
+    #[derive(Debug)]
+    pub struct CreateExperimentRequest {
+        pub auth: Auth,
+        pub name: String,
+        pub toolchain_from: Toolchain,
+        pub toolchain_to: Toolchain,
+        pub selection: CrateSelection
+    }
+
+    #[derive(RustcEncodable, RustcDecodable)]
+    #[derive(Debug)]
+    pub struct ListExperimentsRequest {
+        pub auth: Auth
+    }
+
+    /// Names an experiment to show, delete, run or get the status
+    /// of. Running an experiment schedules crate builds with both
+    /// toolchains.
+    #[derive(RustcEncodable, RustcDecodable)]
+    #[derive(Debug)]
+    pub struct ExperimentRequest {
+        pub auth: Auth,
+        pub name: String
+    }
+
+    #[derive(RustcEncodable, RustcDecodable)]
+    #[derive(Debug)]
+    pub struct Experiment {
+        pub name: String,
+        /// The user that created it
+        pub owner: String,
+        pub toolchain_from: Toolchain,
+        pub toolchain_to: Toolchain,
+        pub selection: CrateSelection,
+        /// Seconds since the Unix epoch
+        pub created_at: i64,
+        pub state: ExperimentState
+    }
+
+    /// How many of an experiment's crate builds have results
+    #[derive(RustcEncodable, RustcDecodable)]
+    #[derive(Debug)]
+    pub struct ExperimentProgress {
+        pub experiment: String,
+        pub scheduled: u64,
+        /// Built successfully or failed
+        pub finished: u64,
+        /// Need rebuilding
+        pub exception: u64,
+        /// Still waiting for a result
+        pub outstanding: u64
+    }
+
+    #[derive(RustcEncodable, RustcDecodable)]
+    #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+    pub enum ExperimentState {
+        /// Nothing has been scheduled yet
+        Created,
+        /// Crate builds have been scheduled
+        Running
+    }
+
+    impl Display for ExperimentState {
+        fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+            f.write_str(match *self {
+                ExperimentState::Created => "created",
+                ExperimentState::Running => "running"
+            })
+        }
+    }
+
+    /// Creates a user with a fresh token. Admins only.
+    #[derive(RustcEncodable, RustcDecodable)]
+    #[derive(Debug)]
+    pub struct CreateUserRequest {
+        pub auth: Auth,
+        pub name: String,
+        pub role: Role
+    }
+
+    /// Revokes a user's token, or replaces it with a fresh one. Admins
+    /// only.
+    #[derive(RustcEncodable, RustcDecodable)]
+    #[derive(Debug)]
+    pub struct UserRequest {
+        pub auth: Auth,
+        pub name: String
+    }
+
+    /// A user's new token. It is only ever shown here; the server
+    /// keeps just a hash.
+    #[derive(RustcEncodable, RustcDecodable)]
+    #[derive(Debug)]
+    pub struct UserTokenResponse {
+        pub name: String,
+        pub role: Role,
+        pub token: String
+    }
+
+    #[derive(RustcEncodable, RustcDecodable)]
+    #[derive(Debug)]
+    pub struct RevokeUserResponse {
+        pub name: String,
+        pub revoked: bool
+    }
+
+    /// The stored excerpt of a crate build's log, from
+    /// `POST /results/:toolchain/:crate/:version/log`. A `POST` rather
+    /// than a `GET` so the credentials have a body to go in.
+    #[derive(RustcEncodable, RustcDecodable)]
+    #[derive(Debug)]
+    pub struct BuildLogRequest {
+        pub auth: Auth
+    }
+
+    /// Pages through the audit log, newest first. Admins only.
+    #[derive(RustcEncodable, RustcDecodable)]
+    #[derive(Debug)]
+    pub struct AuditRequest {
+        pub auth: Auth,
+        pub offset: u64,
+        pub limit: u64
+    }
+
+    /// A call that changed something
+    #[derive(RustcEncodable, RustcDecodable)]
+    #[derive(Debug)]
+    pub struct AuditEntry {
+        pub id: i64,
+        pub user: String,
+        pub endpoint: String,
+        /// The request, as JSON
+        pub params: String,
+        /// What came of it, e.g. the experiment or task ids
+        pub result: String,
+        /// Seconds since the Unix epoch
+        pub created_at: i64
+    }
+
+    #[derive(RustcEncodable, RustcDecodable)]
+    #[derive(Debug)]
+    pub struct QuotaRequest {
+        pub auth: Auth
+    }
+
+    /// How much of their limits a user has used. Daily counts start
+    /// again at midnight UTC.
+    #[derive(RustcEncodable, RustcDecodable)]
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct QuotaResponse {
+        pub name: String,
+        /// Running named experiments that still have outstanding jobs.
+        /// Ad-hoc crate builds don't count.
+        pub concurrent_experiments: QuotaUsage,
+        /// Crate build tasks scheduled today
+        pub tasks_today: QuotaUsage,
+        pub custom_builds_today: QuotaUsage
+    }
+
+    #[derive(RustcEncodable, RustcDecodable)]
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub struct QuotaUsage {
+        pub used: u64,
+        /// `None` is unlimited
+        pub limit: Option<u64>
+    }
+
+    impl QuotaUsage {
+        /// `None` is unlimited
+        pub fn remaining(&self) -> Option<u64> {
+            self.limit.map(|limit| limit.saturating_sub(self.used))
+        }
     }
 
     /// Responses from running one of the v1 nodejs scripts
@@ -78,5 +761,269 @@ pub mod v1 {
             }
         }
     }
+
+    /// The body of every failed request
+    #[derive(RustcEncodable, RustcDecodable)]
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct ApiError {
+        pub code: ErrorCode,
+        pub message: String,
+        /// More about what went wrong, e.g. the underlying error
+        /// behind an `Internal` one
+        pub details: Option<String>
+    }
+
+    #[derive(RustcEncodable, RustcDecodable)]
+    #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+    pub enum ErrorCode {
+        /// The request was malformed or its contents are invalid
+        BadRequest,
+        /// Unknown user or wrong token
+        AuthenticationFailed,
+        /// The user may not do this
+        Forbidden,
+        NotFound,
+        /// One of the user's limits has been reached
+        QuotaExceeded,
+        /// Something went wrong on the server
+        Internal
+    }
+
+    impl ApiError {
+        pub fn new(code: ErrorCode, message: &str) -> ApiError {
+            ApiError {
+                code: code,
+                message: String::from(message),
+                details: None
+            }
+        }
+    }
+
+    impl StdError for ApiError {
+        fn description(&self) -> &str { &self.message }
+    }
+
+    impl Display for ApiError {
+        fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+            match self.details {
+                Some(ref details) => write!(f, "{}: {}", self.message, details),
+                None => f.write_str(&self.message)
+            }
+        }
+    }
 }
 
+
+/// The v2 API. Requests carry credentials in an `Authorization: Bearer`
+/// header rather than in the body, so reads can be plain GETs. Bodies
+/// are the v1 requests without `auth`, and responses are the same as
+/// v1's.
+pub mod v2 {
+    use std::fmt::{self, Formatter};
+    use {Role, Toolchain};
+    use v1::{CrateSelection, ReportKind};
+
+    /// A user's name and token. Debug output leaves out the token.
+    #[derive(Clone, PartialEq)]
+    pub struct Credentials {
+        pub name: String,
+        pub token: String
+    }
+
+    impl Credentials {
+        /// The value of the `Authorization` header, `Bearer name:token`
+        pub fn header_value(&self) -> String {
+            format!("Bearer {}:{}", self.name, self.token)
+        }
+
+        /// Parses the value of an `Authorization` header
+        pub fn from_header(value: &str) -> Option<Credentials> {
+            let value = value.trim();
+            if !value.starts_with("Bearer ") {
+                return None;
+            }
+            let credentials = value["Bearer ".len()..].trim_left();
+            credentials.find(':').and_then(|i| {
+                let (name, token) = (&credentials[..i], &credentials[i + 1..]);
+                if name.is_empty() || token.is_empty() {
+                    return None;
+                }
+                Some(Credentials {
+                    name: String::from(name),
+                    token: String::from(token)
+                })
+            })
+        }
+    }
+
+    impl fmt::Debug for Credentials {
+        fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+            write!(f, "Credentials {{ name: {:?}, token: <redacted> }}", self.name)
+        }
+    }
+
+    /// Build a compiler from a git repo and a commit sha
+    #[derive(RustcEncodable, RustcDecodable)]
+    #[derive(Debug)]
+    pub struct CustomBuildRequest {
+        pub repo_url: String,
+        pub commit_sha: String
+    }
+
+    /// Build crates from the index with a toolchain
+    #[derive(RustcEncodable, RustcDecodable)]
+    #[derive(Debug)]
+    pub struct CrateBuildRequest {
+        pub toolchain: Toolchain,
+        pub selection: CrateSelection
+    }
+
+    #[derive(RustcEncodable, RustcDecodable)]
+    #[derive(Debug)]
+    pub struct ReportRequest {
+        pub kind: ReportKind
+    }
+
+    /// Starts tracking a named run comparing two toolchains
+    #[derive(RustcEncodable, RustcDecodable)]
+    #[derive(Debug)]
+    pub struct CreateExperimentRequest {
+        pub name: String,
+        pub toolchain_from: Toolchain,
+        pub toolchain_to: Toolchain,
+        pub selection: CrateSelection
+    }
+
+    /// Creates a user with a fresh token. Admins only.
+    #[derive(RustcEncodable, RustcDecodable)]
+    #[derive(Debug)]
+    pub struct CreateUserRequest {
+        pub name: String,
+        pub role: Role
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_dist_toolchain() {
+        let t: Toolchain = "nightly-2015-06-06".parse().unwrap();
+        assert_eq!(t, Toolchain::Dist { channel: Channel::Nightly,
+                                        date: String::from("2015-06-06") });
+        assert_eq!(t.to_string(), "nightly-2015-06-06");
+        assert!("beta-2015-6-6".parse::<Toolchain>().is_err());
+        assert!("nightly".parse::<Toolchain>().is_err());
+    }
+
+    #[test]
+    fn parse_custom_toolchain() {
+        let sha = "0123456789abcdef0123456789abcdef01234567";
+        let t: Toolchain = sha.parse().unwrap();
+        assert_eq!(t, Toolchain::Custom { sha: String::from(sha), repo: None });
+        assert_eq!(t.to_string(), sha);
+
+        let s = format!("https://github.com/rust-lang/rust#{}", sha);
+        let t: Toolchain = s.parse().unwrap();
+        assert_eq!(t, Toolchain::Custom {
+            sha: String::from(sha),
+            repo: Some(String::from("https://github.com/rust-lang/rust"))
+        });
+        assert_eq!(t.to_string(), s);
+
+        assert_eq!(t.key(), sha);
+
+        assert!("0123456789abcdef".parse::<Toolchain>().is_err());
+        assert!("https://github.com/rust-lang/rust#master".parse::<Toolchain>().is_err());
+    }
+
+    #[test]
+    fn valid_names() {
+        assert!(is_valid_name("brson"));
+        assert!(is_valid_name("beta-1.6_regressions"));
+        assert!(!is_valid_name(""));
+        assert!(!is_valid_name(".."));
+        assert!(!is_valid_name("a/run"));
+        assert!(!is_valid_name("a b"));
+        assert!(!is_valid_name("é"));
+        assert!(!is_valid_name(&(0..65).map(|_| "a").collect::<String>()));
+    }
+
+    #[test]
+    fn api_error_round_trip() {
+        use rustc_serialize::json;
+
+        let e = v1::ApiError {
+            code: v1::ErrorCode::AuthenticationFailed,
+            message: String::from("authentication failed"),
+            details: None
+        };
+        let decoded: v1::ApiError = json::decode(&json::encode(&e).unwrap()).unwrap();
+        assert_eq!(decoded, e);
+        assert_eq!(e.to_string(), "authentication failed");
+
+        let e = v1::ApiError {
+            details: Some(String::from("connection refused")),
+            ..v1::ApiError::new(v1::ErrorCode::Internal, "database error")
+        };
+        assert_eq!(e.to_string(), "database error: connection refused");
+    }
+
+    #[test]
+    fn bearer_credentials() {
+        let c = v2::Credentials {
+            name: String::from("brson"),
+            token: String::from("0123abcd")
+        };
+        assert_eq!(c.header_value(), "Bearer brson:0123abcd");
+        assert!(v2::Credentials::from_header(&c.header_value()) == Some(c.clone()));
+        assert!(v2::Credentials::from_header("Basic YnJzb246MDEyMw==").is_none());
+        assert!(v2::Credentials::from_header("Bearer brson").is_none());
+        assert!(v2::Credentials::from_header("Bearer :0123abcd").is_none());
+    }
+
+    #[test]
+    fn debug_redacts_tokens() {
+        let c = v2::Credentials {
+            name: String::from("brson"),
+            token: String::from("0123abcd")
+        };
+        assert!(!format!("{:?}", c).contains("0123abcd"));
+        let a = v1::Auth {
+            name: String::from("brson"),
+            token: String::from("0123abcd")
+        };
+        assert!(!format!("{:?}", v1::SelfTestRequest { auth: a }).contains("0123abcd"));
+    }
+
+    #[test]
+    fn roles_are_ordered() {
+        assert!(Role::Reporter < Role::Scheduler);
+        assert!(Role::Scheduler < Role::Admin);
+        for &role in Role::all() {
+            assert_eq!(role.to_string().parse::<Role>().unwrap(), role);
+        }
+        assert!("root".parse::<Role>().is_err());
+    }
+
+    #[test]
+    fn quota_remaining() {
+        let usage = v1::QuotaUsage { used: 3, limit: Some(5) };
+        assert_eq!(usage.remaining(), Some(2));
+        let usage = v1::QuotaUsage { used: 7, limit: Some(5) };
+        assert_eq!(usage.remaining(), Some(0));
+        let usage = v1::QuotaUsage { used: 7, limit: None };
+        assert_eq!(usage.remaining(), None);
+    }
+
+    #[test]
+    fn parse_build_status() {
+        for &status in BuildStatus::all() {
+            assert_eq!(status.to_string().parse::<BuildStatus>().unwrap(), status);
+        }
+        assert_eq!("failure".parse::<BuildStatus>().unwrap(), BuildStatus::Failure);
+        assert!("Success".parse::<BuildStatus>().is_err());
+        assert!("".parse::<BuildStatus>().is_err());
+    }
+}
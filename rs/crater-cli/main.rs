@@ -3,7 +3,9 @@ extern crate rustc_serialize;
 #[macro_use]
 extern crate log;
 extern crate env_logger;
+extern crate time;
 extern crate crater_api as api;
+extern crate crater_db as db;
 
 use rustc_serialize::json;
 use std::convert::From;
@@ -12,14 +14,61 @@ use std::error::Error as StdError;
 use std::fmt::{self, Display, Formatter};
 use std::fs::File;
 use std::io::{self, Read};
-use api::v1;
+use api::{v1, Role, Toolchain};
 use std::io::Write;
+use std::thread;
+use std::time::Duration;
+
+/// Audit log entries printed per page
+const AUDIT_PAGE_SIZE: u64 = 50;
 
 enum Opts {
+    /// Sent to the server
+    Server(ServerCmd),
+    /// Talks to the database directly rather than through the server
+    Db(DbCmd)
+}
+
+enum ServerCmd {
     CustomBuild { repo_url: String, commit_sha: String },
-    CrateBuild { toolchain: String },
+    CrateBuild { toolchain: Toolchain, selection: v1::CrateSelection },
     Report { kind: v1::ReportKind },
-    SelfTest
+    SyncIndex,
+    SelfTest,
+    Experiment(ExperimentCmd),
+    /// With `wait`, polls until every job is done or the timeout
+    /// passes
+    Status { experiment: String, wait: bool, timeout_mins: Option<u64> },
+    User(UserCmd),
+    /// Pages through the audit log, newest first
+    Audit { offset: u64, limit: u64 },
+    /// Prints the stored excerpt of a crate build's log
+    Log { toolchain: Toolchain, crate_name: String, crate_vers: String }
+}
+
+enum DbCmd {
+    Migrate { dry_run: bool },
+    /// Adds a user straight to the database, e.g. the first admin
+    AddUser { name: String, role: Role }
+}
+
+enum ExperimentCmd {
+    Create {
+        name: String,
+        toolchain_from: Toolchain,
+        toolchain_to: Toolchain,
+        selection: v1::CrateSelection
+    },
+    List,
+    Show { name: String },
+    Delete { name: String },
+    Run { name: String }
+}
+
+enum UserCmd {
+    Create { name: String, role: Role },
+    Revoke { name: String },
+    Rotate { name: String }
 }
 
 #[derive(RustcEncodable, RustcDecodable)]
@@ -30,20 +79,24 @@ pub struct Config {
 }
 
 fn main() {
-    run().unwrap();
+    if let Err(e) = run() {
+        let _ = writeln!(std::io::stderr(), "error: {}", e);
+        std::process::exit(1);
+    }
 }
 
 fn run() -> Result<(), Error> {
     try!(env_logger::init());
 
-    let config = try!(load_config());
-
     let ref args: Vec<String> = env::args().collect();
-    let opts = try!(parse_opts(args));
-
-    try!(run_run(config, opts));
-
-    Ok(())
+    match try!(parse_opts(args)) {
+        Opts::Db(DbCmd::Migrate { dry_run }) => run_db_migrate(dry_run),
+        Opts::Db(DbCmd::AddUser { ref name, role }) => run_db_add_user(name, role),
+        Opts::Server(cmd) => {
+            let config = try!(load_config());
+            run_run(config, cmd)
+        }
+    }
 }
 
 fn load_config() -> Result<Config, Error> {
@@ -58,56 +111,331 @@ fn load_config() -> Result<Config, Error> {
     return Ok(try!(json::decode(&s)));
 }
 
+fn load_db_config() -> Result<db::Config, Error> {
+    let mut path = try!(::std::env::current_dir());
+    path.push("crater-db-config.json");
+
+    let mut file = try!(File::open(path));
+
+    let mut s = String::new();
+    try!(file.read_to_string(&mut s));
+
+    return Ok(try!(json::decode(&s)));
+}
+
 fn parse_opts(args: &[String]) -> Result<Opts, Error> {
     if args.len() < 2 { return Err(Error::OptParse) }
 
+    if args[1] == "db" {
+        let ref cmd = try!(args.get(2).ok_or(Error::OptParse));
+        Ok(Opts::Db(try!(parse_db_cmd(cmd, &args[3..]))))
+    } else {
+        Ok(Opts::Server(try!(parse_server_cmd(args))))
+    }
+}
+
+fn parse_server_cmd(args: &[String]) -> Result<ServerCmd, Error> {
     if args[1] == "custom-build" {
         let repo_url = try!(args.get(2).ok_or(Error::OptParse));
         let commit_sha = try!(args.get(3).ok_or(Error::OptParse));
-        Ok(Opts::CustomBuild { repo_url: repo_url.clone(),
+        try!(Toolchain::custom(commit_sha, Some(&repo_url[..])));
+        Ok(ServerCmd::CustomBuild { repo_url: repo_url.clone(),
                                commit_sha: commit_sha.clone() })
     } else if args[1] == "crate-build" {
         let toolchain = try!(args.get(2).ok_or(Error::OptParse));
-        Ok(Opts::CrateBuild { toolchain: toolchain.clone() })
+        let selection = try!(parse_crate_selection(&args[3..]));
+        Ok(ServerCmd::CrateBuild { toolchain: try!(toolchain.parse()),
+                              selection: selection })
     } else if args[1] == "report" {
         let ref kind = try!(args.get(2).ok_or(Error::OptParse));
         let kind = try!(parse_report_kind(kind, &args[3..]));
-        Ok(Opts::Report { kind: kind })
+        Ok(ServerCmd::Report { kind: kind })
+    } else if args[1] == "sync-index" {
+        Ok(ServerCmd::SyncIndex)
     } else if args[1] == "self-test" {
-        Ok(Opts::SelfTest)
+        Ok(ServerCmd::SelfTest)
+    } else if args[1] == "status" {
+        let experiment = try!(args.get(2).ok_or(Error::OptParse)).clone();
+        let mut wait = false;
+        let mut timeout_mins = None;
+        let mut args = args[3..].iter();
+        while let Some(arg) = args.next() {
+            if arg == "--wait" {
+                wait = true;
+            } else if arg == "--timeout" {
+                let mins = try!(args.next().ok_or(Error::OptParse));
+                timeout_mins = Some(try!(mins.parse().map_err(|_| Error::OptParse)));
+            } else {
+                return Err(Error::OptParse);
+            }
+        }
+        Ok(ServerCmd::Status { experiment: experiment, wait: wait, timeout_mins: timeout_mins })
+    } else if args[1] == "user" {
+        let ref cmd = try!(args.get(2).ok_or(Error::OptParse));
+        let cmd = try!(parse_user_cmd(cmd, &args[3..]));
+        Ok(ServerCmd::User(cmd))
+    } else if args[1] == "audit" {
+        let mut offset = 0;
+        let mut limit = AUDIT_PAGE_SIZE;
+        let mut args = args[2..].iter();
+        while let Some(arg) = args.next() {
+            if arg == "--offset" {
+                let n = try!(args.next().ok_or(Error::OptParse));
+                offset = try!(n.parse().map_err(|_| Error::OptParse));
+            } else if arg == "--limit" {
+                let n = try!(args.next().ok_or(Error::OptParse));
+                limit = try!(n.parse().map_err(|_| Error::OptParse));
+            } else {
+                return Err(Error::OptParse);
+            }
+        }
+        Ok(ServerCmd::Audit { offset: offset, limit: limit })
+    } else if args[1] == "log" {
+        let toolchain = try!(args.get(2).ok_or(Error::OptParse));
+        let crate_name = try!(args.get(3).ok_or(Error::OptParse)).clone();
+        let crate_vers = try!(args.get(4).ok_or(Error::OptParse)).clone();
+        Ok(ServerCmd::Log { toolchain: try!(toolchain.parse()),
+                            crate_name: crate_name,
+                            crate_vers: crate_vers })
+    } else if args[1] == "experiment" {
+        let ref cmd = try!(args.get(2).ok_or(Error::OptParse));
+        let cmd = try!(parse_experiment_cmd(cmd, &args[3..]));
+        Ok(ServerCmd::Experiment(cmd))
+    } else {
+        Err(Error::OptParse)
+    }
+}
+
+fn parse_experiment_cmd(cmd: &str, args: &[String]) -> Result<ExperimentCmd, Error> {
+    if cmd == "list" {
+        return Ok(ExperimentCmd::List);
+    }
+
+    let name = try!(args.get(0).ok_or(Error::OptParse)).clone();
+    if cmd == "create" {
+        if !api::is_valid_name(&name) { return Err(Error::OptParse) }
+        let from = try!(args.get(1).ok_or(Error::OptParse));
+        let to = try!(args.get(2).ok_or(Error::OptParse));
+        let selection = try!(parse_crate_selection(&args[3..]));
+        Ok(ExperimentCmd::Create { name: name,
+                                   toolchain_from: try!(from.parse()),
+                                   toolchain_to: try!(to.parse()),
+                                   selection: selection })
+    } else if cmd == "show" {
+        Ok(ExperimentCmd::Show { name: name })
+    } else if cmd == "delete" {
+        Ok(ExperimentCmd::Delete { name: name })
+    } else if cmd == "run" {
+        Ok(ExperimentCmd::Run { name: name })
+    } else {
+        Err(Error::OptParse)
+    }
+}
+
+fn parse_db_cmd(cmd: &str, args: &[String]) -> Result<DbCmd, Error> {
+    if cmd == "migrate" {
+        let dry_run = match args.get(0).map(|s| &s[..]) {
+            None => false,
+            Some("--dry-run") => true,
+            Some(_) => return Err(Error::OptParse)
+        };
+        Ok(DbCmd::Migrate { dry_run: dry_run })
+    } else if cmd == "add-user" {
+        let name = try!(args.get(0).ok_or(Error::OptParse)).clone();
+        if !api::is_valid_name(&name) { return Err(Error::OptParse) }
+        let role = try!(args.get(1).ok_or(Error::OptParse));
+        Ok(DbCmd::AddUser { name: name, role: try!(role.parse()) })
+    } else {
+        Err(Error::OptParse)
+    }
+}
+
+fn parse_user_cmd(cmd: &str, args: &[String]) -> Result<UserCmd, Error> {
+    let name = try!(args.get(0).ok_or(Error::OptParse)).clone();
+    if cmd == "create" {
+        if !api::is_valid_name(&name) { return Err(Error::OptParse) }
+        let role = try!(args.get(1).ok_or(Error::OptParse));
+        Ok(UserCmd::Create { name: name, role: try!(role.parse()) })
+    } else if cmd == "revoke" {
+        Ok(UserCmd::Revoke { name: name })
+    } else if cmd == "rotate" {
+        Ok(UserCmd::Rotate { name: name })
     } else {
         Err(Error::OptParse)
     }
 }
 
+/// Parses the `--top`, `--all-versions`, `--name` and
+/// `--skip-existing` flags of crate-build. `--most-recent-only` is the
+/// default, and still accepted.
+fn parse_crate_selection(args: &[String]) -> Result<v1::CrateSelection, Error> {
+    let mut selection = v1::CrateSelection::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--top" {
+            let top = try!(args.next().ok_or(Error::OptParse));
+            selection.top = Some(try!(top.parse().map_err(|_| Error::OptParse)));
+        } else if arg == "--most-recent-only" {
+            selection.most_recent_only = true;
+        } else if arg == "--all-versions" {
+            selection.most_recent_only = false;
+        } else if arg == "--name" {
+            let name = try!(args.next().ok_or(Error::OptParse));
+            selection.name = Some(name.clone());
+        } else if arg == "--skip-existing" {
+            selection.skip_existing = true;
+        } else {
+            return Err(Error::OptParse);
+        }
+    }
+
+    Ok(selection)
+}
+
 fn parse_report_kind(kind: &str, args: &[String]) -> Result<v1::ReportKind, Error> {
     if kind == "comparison" {
         let from = try!(args.get(0).ok_or(Error::OptParse));
         let to = try!(args.get(1).ok_or(Error::OptParse));
-        Ok(v1::ReportKind::Comparison { toolchain_from: from.clone(),
-                                        toolchain_to: to.clone() })
+        Ok(v1::ReportKind::Comparison { toolchain_from: try!(from.parse()),
+                                        toolchain_to: try!(to.parse()) })
     } else if kind == "toolchain" {
         let toolchain = try!(args.get(0).ok_or(Error::OptParse));
-        Ok(v1::ReportKind::Toolchain(toolchain.clone()))
+        Ok(v1::ReportKind::Toolchain(try!(toolchain.parse())))
+    } else if kind == "ices" {
+        let toolchain = try!(args.get(0).ok_or(Error::OptParse));
+        Ok(v1::ReportKind::Ices { toolchain: try!(toolchain.parse()) })
     } else {
         Err(Error::OptParse)
     }
 }
 
-fn run_run(config: Config, opts: Opts) -> Result<(), Error> {
-    let client_v1 = client_v1::Ctxt::new(config);
-    let res = match opts {
-        Opts::CustomBuild { repo_url, commit_sha } => {
-            client_v1.custom_build(repo_url, commit_sha)
+/// Brings the database schema up to date, or with `dry_run` just
+/// prints the SQL that would be run
+fn run_db_migrate(dry_run: bool) -> Result<(), Error> {
+    let config = try!(load_db_config());
+    let db = try!(db::Database::connect_without_migrating(&config).map_err(db_error));
+
+    let migrations = if dry_run {
+        try!(db.pending_migrations().map_err(db_error))
+    } else {
+        try!(db.migrate().map_err(db_error))
+    };
+
+    if migrations.is_empty() {
+        println!("schema is up to date");
+    }
+    for migration in migrations {
+        println!("-- {} {}: {}",
+                 if dry_run { "pending" } else { "applied" },
+                 migration.version, migration.name);
+        for q in migration.sql {
+            println!("{};", q);
+        }
+    }
+
+    Ok(())
+}
+
+/// Prints the new user's token, which can't be recovered later
+fn run_db_add_user(name: &str, role: Role) -> Result<(), Error> {
+    let config = try!(load_db_config());
+    let db = try!(db::Database::connect(&config).map_err(db_error));
+
+    match try!(db.add_user(name, role).map_err(db_error)) {
+        Some(token) => println!("{}", format_user_token(name, role, &token)),
+        None => return Err(Error::DbError(format!("user {} already exists", name)))
+    }
+
+    Ok(())
+}
+
+fn db_error(e: Box<StdError>) -> Error {
+    Error::DbError(e.description().to_string())
+}
+
+fn run_run(config: Config, cmd: ServerCmd) -> Result<(), Error> {
+    let client_v2 = client_v2::Ctxt::new(config);
+    let res = match cmd {
+        ServerCmd::CustomBuild { repo_url, commit_sha } => {
+            client_v2.custom_build(repo_url, commit_sha)
+        }
+        ServerCmd::CrateBuild { toolchain, selection } => {
+            client_v2.crate_build(toolchain, selection).map(|r| {
+                format!("scheduling {} crate builds as experiment {}",
+                        r.jobs, r.experiment_id)
+            })
         }
-        Opts::CrateBuild { toolchain } => {
-            client_v1.crate_build(toolchain)
+        ServerCmd::Report { kind: v1::ReportKind::Comparison {
+            toolchain_from, toolchain_to
+        } } => {
+            client_v2.comparison_report(toolchain_from, toolchain_to)
+                .map(|ref r| format_comparison_report(r))
         }
-        Opts::Report { kind } => {
-            client_v1.report(kind)
+        ServerCmd::Report { kind: v1::ReportKind::Ices { toolchain } } => {
+            client_v2.ice_report(toolchain).map(|ref r| format_ice_report(r))
         }
-        Opts::SelfTest => {
-            client_v1.self_test()
+        ServerCmd::Report { kind } => {
+            client_v2.report(kind)
+        }
+        ServerCmd::SyncIndex => {
+            client_v2.sync_index().map(|r| {
+                format!("synced {} crate versions, {} dependency edges, {} ranked crates",
+                        r.crate_versions, r.dep_edges, r.ranked_crates)
+            })
+        }
+        ServerCmd::SelfTest => {
+            client_v2.self_test().and_then(|s| {
+                let ref quota = try!(client_v2.quota());
+                Ok(format!("{}\n\n{}", s, format_quota(quota)))
+            })
+        }
+        ServerCmd::Experiment(ExperimentCmd::Create { name, toolchain_from, toolchain_to, selection }) => {
+            client_v2.create_experiment(name, toolchain_from, toolchain_to, selection)
+                .map(|ref e| format_experiment(e))
+        }
+        ServerCmd::Experiment(ExperimentCmd::List) => {
+            client_v2.list_experiments().map(|experiments| {
+                experiments.iter().map(format_experiment).collect::<Vec<_>>().join("\n")
+            })
+        }
+        ServerCmd::Experiment(ExperimentCmd::Show { name }) => {
+            client_v2.show_experiment(name).map(|ref e| format_experiment(e))
+        }
+        ServerCmd::Experiment(ExperimentCmd::Delete { name }) => {
+            client_v2.delete_experiment(name).map(|e| format!("deleted experiment {}", e.name))
+        }
+        ServerCmd::Status { experiment, wait, timeout_mins } => {
+            wait_for_experiment(&client_v2, experiment, wait, timeout_mins)
+        }
+        ServerCmd::User(UserCmd::Create { name, role }) => {
+            client_v2.create_user(name, role)
+                .map(|r| format_user_token(&r.name, r.role, &r.token))
+        }
+        ServerCmd::User(UserCmd::Revoke { name }) => {
+            client_v2.revoke_user(name).map(|r| {
+                if r.revoked {
+                    format!("revoked {}", r.name)
+                } else {
+                    format!("{} was not an active user", r.name)
+                }
+            })
+        }
+        ServerCmd::User(UserCmd::Rotate { name }) => {
+            client_v2.rotate_token(name)
+                .map(|r| format_user_token(&r.name, r.role, &r.token))
+        }
+        ServerCmd::Audit { offset, limit } => {
+            client_v2.audit_log(offset, limit)
+                .map(|ref entries| format_audit_log(entries, offset, limit))
+        }
+        ServerCmd::Log { toolchain, crate_name, crate_vers } => {
+            client_v2.build_log(toolchain, crate_name, crate_vers)
+        }
+        ServerCmd::Experiment(ExperimentCmd::Run { name }) => {
+            client_v2.run_experiment(name).map(|r| {
+                format!("scheduling {} crate builds for experiment {}",
+                        r.jobs, r.experiment_id)
+            })
         }
     };
 
@@ -127,11 +455,241 @@ fn run_run(config: Config, opts: Opts) -> Result<(), Error> {
     }
 }
 
+/// Prints progress until nothing is outstanding, returning the final
+/// status. Without `wait` just returns the current status.
+fn wait_for_experiment(client_v2: &client_v2::Ctxt, experiment: String, wait: bool,
+                       timeout_mins: Option<u64>) -> Result<String, Error> {
+    let poll_secs = 60;
+    let deadline = timeout_mins.map(|mins| time::get_time().sec + mins as i64 * 60);
+
+    loop {
+        let progress = try!(client_v2.experiment_status(experiment.clone()));
+        let done = progress.outstanding == 0;
+        if !wait || done {
+            return Ok(format_progress(&progress, done));
+        }
+
+        if let Some(deadline) = deadline {
+            if time::get_time().sec + poll_secs >= deadline {
+                return Ok(format!("{}\ngave up waiting", format_progress(&progress, done)));
+            }
+        }
+
+        println!("{}", format_progress(&progress, done));
+        thread::sleep(Duration::from_secs(poll_secs as u64));
+    }
+}
+
+fn format_progress(p: &v1::ExperimentProgress, done: bool) -> String {
+    format!("{}: {} scheduled / {} finished / {} exception / {} outstanding{}",
+            p.experiment, p.scheduled, p.finished, p.exception, p.outstanding,
+            if done { " (done)" } else { "" })
+}
+
+fn format_quota(q: &v1::QuotaResponse) -> String {
+    fn usage(what: &str, u: &v1::QuotaUsage) -> String {
+        match (u.limit, u.remaining()) {
+            (Some(limit), Some(remaining)) => {
+                format!("* {}: {} of {} used, {} left", what, u.used, limit, remaining)
+            }
+            _ => format!("* {}: {} used, unlimited", what, u.used)
+        }
+    }
+
+    format!("quota for {}:\n{}\n{}\n{}", q.name,
+            usage("concurrent experiments", &q.concurrent_experiments),
+            usage("crate builds today", &q.tasks_today),
+            usage("custom builds today", &q.custom_builds_today))
+}
+
+fn format_user_token(name: &str, role: Role, token: &str) -> String {
+    format!("{} ({})\ntoken: {}\n\n\
+             The token is only shown once; put it in {}'s crater-cli-config.json.",
+            name, role, token, name)
+}
+
+/// One entry per line, with a hint for fetching the next page if this
+/// one was full
+fn format_audit_log(entries: &[v1::AuditEntry], offset: u64, limit: u64) -> String {
+    let mut lines: Vec<String> = entries.iter().map(|e| {
+        let created_at = time::at_utc(time::Timespec::new(e.created_at, 0));
+        format!("{} {} {} {} -> {}",
+                created_at.rfc3339(), e.user, e.endpoint, e.params, e.result)
+    }).collect();
+
+    if entries.is_empty() {
+        lines.push(String::from("no audit entries"));
+    } else if entries.len() as u64 == limit {
+        lines.push(format!("\nmore with: crater-cli audit --offset {} --limit {}",
+                           offset + limit, limit));
+    }
+
+    lines.join("\n")
+}
+
+fn format_experiment(e: &v1::Experiment) -> String {
+    use std::fmt::Write;
+
+    let created_at = time::at_utc(time::Timespec::new(e.created_at, 0));
+
+    let sel = &e.selection;
+    let mut crates = Vec::new();
+    if let Some(top) = sel.top {
+        crates.push(format!("top {}", top));
+    }
+    if let Some(ref name) = sel.name {
+        crates.push(format!("named {}", name));
+    }
+    if !sel.most_recent_only {
+        crates.push(String::from("all versions"));
+    }
+    if sel.skip_existing {
+        crates.push(String::from("skipping existing results"));
+    }
+    if crates.is_empty() {
+        crates.push(String::from("all, most recent versions"));
+    }
+
+    let mut s = String::new();
+    let _ = writeln!(s, "# Experiment {}", e.name);
+    let _ = writeln!(s, "");
+    let _ = writeln!(s, "* Owner: {}", e.owner);
+    let _ = writeln!(s, "* From: {}", e.toolchain_from);
+    let _ = writeln!(s, "* To: {}", e.toolchain_to);
+    let _ = writeln!(s, "* Created: {}", created_at.rfc3339());
+    let _ = writeln!(s, "* State: {}", e.state);
+    let _ = writeln!(s, "* Crates: {}", crates.join(", "));
+
+    s
+}
+
+fn format_comparison_report(report: &v1::ComparisonReport) -> String {
+    use std::fmt::Write;
+
+    let mut s = String::new();
+    let ref summary = report.summary;
+    let from = &report.toolchain_from;
+    let to = &report.toolchain_to;
+
+    let _ = writeln!(s, "# Regression report {} vs. {}", from, to);
+    let _ = writeln!(s, "");
+    let _ = writeln!(s, "* From: {}", from);
+    let _ = writeln!(s, "* To: {}", to);
+    let _ = writeln!(s, "");
+    let _ = writeln!(s, "## Coverage");
+    let _ = writeln!(s, "");
+    let _ = writeln!(s, "* {} crates tested: {} working / {} broken / {} regressed / \
+                         {} fixed / {} exception / {} unknown.",
+                     report.crates.len(), summary.working, summary.broken,
+                     summary.regressed, summary.fixed, summary.exception, summary.unknown);
+    let _ = writeln!(s, "");
+    let _ = writeln!(s, "## Regressions");
+    let _ = writeln!(s, "");
+    let _ = writeln!(s, "* There are {} root regressions", report.root_regressions.len());
+    let _ = writeln!(s, "* There are {} regressions", summary.regressed);
+    let _ = writeln!(s, "");
+
+    let regressions: Vec<&v1::CrateComparison> = report.crates.iter()
+        .filter(|c| c.status == v1::ComparisonStatus::Regressed)
+        .collect();
+
+    let _ = writeln!(s, "## Regressions by error");
+    let _ = writeln!(s, "");
+    for g in &report.regression_groups {
+        let _ = writeln!(s, "* {} crates: {}", g.crates.len(), g.diagnostic);
+        let crates: Vec<String> = g.crates.iter()
+            .map(|c| format!("{}-{}", c.crate_name, c.crate_vers))
+            .collect();
+        let _ = writeln!(s, "  * {}", crates.join(", "));
+    }
+    let _ = writeln!(s, "");
+
+    let _ = writeln!(s, "## Root regressions");
+    let _ = writeln!(s, "");
+    for c in &regressions {
+        if report.root_regressions.contains(&c.crate_name) {
+            format_crate_comparison(&mut s, c);
+        }
+    }
+    let _ = writeln!(s, "");
+
+    let _ = writeln!(s, "## Non-root regressions");
+    let _ = writeln!(s, "");
+    for d in &report.dependent_regressions {
+        for c in regressions.iter().filter(|c| c.crate_name == d.crate_name) {
+            format_crate_comparison(&mut s, c);
+            let _ = writeln!(s, "  * depends on regressed {}", d.regressed_dep);
+        }
+    }
+    let _ = writeln!(s, "");
+
+    let sections = [("Broken", v1::ComparisonStatus::Broken),
+                    ("Fixed", v1::ComparisonStatus::Fixed),
+                    ("Working", v1::ComparisonStatus::Working),
+                    ("Exceptions", v1::ComparisonStatus::Exception)];
+    for &(title, status) in &sections {
+        let _ = writeln!(s, "## {}", title);
+        let _ = writeln!(s, "");
+        for c in report.crates.iter().filter(|c| c.status == status) {
+            format_crate_comparison(&mut s, c);
+        }
+        let _ = writeln!(s, "");
+    }
+
+    s
+}
+
+fn format_ice_report(report: &v1::IceReport) -> String {
+    use std::fmt::Write;
+
+    let mut s = String::new();
+    let inspector_root = "https://tools.taskcluster.net/task-inspector/#";
+
+    let _ = writeln!(s, "# ICE report for {}", report.toolchain);
+    let _ = writeln!(s, "");
+    let _ = writeln!(s, "* There are {} distinct ICEs", report.ices.len());
+    let _ = writeln!(s, "");
+    for ice in &report.ices {
+        let _ = writeln!(s, "## {}", ice.message);
+        let _ = writeln!(s, "");
+        if let Some(ref location) = ice.location {
+            let _ = writeln!(s, "At {}, hit by {} crates:", location, ice.crates.len());
+        } else {
+            let _ = writeln!(s, "Hit by {} crates:", ice.crates.len());
+        }
+        let _ = writeln!(s, "");
+        for c in &ice.crates {
+            let _ = writeln!(s, "* [{}-{}](https://crates.io/crates/{}) ([log]({}{}))",
+                             c.crate_name, c.crate_vers, c.crate_name,
+                             inspector_root, c.task_id);
+        }
+        let _ = writeln!(s, "");
+    }
+
+    s
+}
+
+fn format_crate_comparison(s: &mut String, c: &v1::CrateComparison) {
+    use std::fmt::Write;
+
+    let inspector_root = "https://tools.taskcluster.net/task-inspector/#";
+    let _ = writeln!(s, "* [{}-{}](https://crates.io/crates/{}) \
+                         ([before]({}{})) ([after]({}{}))",
+                     c.crate_name, c.crate_vers, c.crate_name,
+                     inspector_root, c.from.task_id,
+                     inspector_root, c.to.task_id);
+}
+
 #[derive(Debug)]
 pub enum Error {
     OptParse,
     StdError(Box<StdError + Send>),
-    StdIoError(v1::StdIoResponse)
+    StdIoError(v1::StdIoResponse),
+    DbError(String),
+    /// The server refused the request
+    ApiError(v1::ApiError),
+    /// The server failed without saying why, e.g. from a proxy
+    HttpError(String)
 }
 
 impl StdError for Error {
@@ -139,7 +697,10 @@ impl StdError for Error {
         match *self {
             Error::OptParse => "bad arguments",
             Error::StdError(ref e) => e.description(),
-            Error::StdIoError(ref e) => &*e.stderr
+            Error::StdIoError(ref e) => &*e.stderr,
+            Error::DbError(ref s) => s,
+            Error::ApiError(ref e) => &e.message,
+            Error::HttpError(ref s) => s
         }
     }
 
@@ -153,7 +714,10 @@ impl StdError for Error {
 
 impl Display for Error {
     fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
-        f.write_str(self.description())
+        match *self {
+            Error::ApiError(ref e) => write!(f, "{}", e),
+            _ => f.write_str(self.description())
+        }
     }
 }
 
@@ -188,19 +752,33 @@ impl From<log::SetLoggerError> for Error {
     }
 }
 
+impl From<api::ParseToolchainError> for Error {
+    fn from(e: api::ParseToolchainError) -> Error {
+        Error::StdError(Box::new(e))
+    }
+}
+
+impl From<api::ParseRoleError> for Error {
+    fn from(e: api::ParseRoleError) -> Error {
+        Error::StdError(Box::new(e))
+    }
+}
+
 impl From<v1::StdIoResponse> for Error {
     fn from(e: v1::StdIoResponse) -> Error {
         Error::StdIoError(e)
     }
 }
 
-mod client_v1 {
+mod client_v2 {
     use super::{Config, Error};
     use hyper::Client;
-    use api::v1;
+    use hyper::header::Authorization;
+    use hyper::method::Method;
+    use api::{v1, v2, Role, Toolchain};
     use rustc_serialize::json;
     use std::io::Read;
-    use rustc_serialize::Encodable;
+    use rustc_serialize::{Decodable, Encodable};
 
     pub struct Ctxt {
         config: Config
@@ -213,58 +791,180 @@ mod client_v1 {
 
         /// Returns the stdout from `node schedule-tasks.js custom-build`
         pub fn custom_build(&self, repo_url: String, commit_sha: String) -> Result<String, Error> {
-            let req = v1::CustomBuildRequest {
-                auth: self.auth(),
+            let req = v2::CustomBuildRequest {
                 repo_url: repo_url, commit_sha: commit_sha
             };
-            stdio_req(&self.config, "custom_build", req)
+            stdio_res(self.post("custom_build", req))
         }
 
-        pub fn crate_build(&self, toolchain: String) -> Result<String, Error> {
-            let req = v1::CrateBuildRequest {
-                auth: self.auth(),
-                toolchain: toolchain
+        pub fn crate_build(&self, toolchain: Toolchain, selection: v1::CrateSelection
+                           ) -> Result<v1::CrateBuildResponse, Error> {
+            let req = v2::CrateBuildRequest {
+                toolchain: toolchain,
+                selection: selection
             };
-            stdio_req(&self.config, "crate_build", req)
+            self.post("crate_build", req)
         }
 
         pub fn report(&self, kind: v1::ReportKind) -> Result<String, Error> {
-            let req = v1::ReportRequest {
-                auth: self.auth(),
-                kind: kind
+            stdio_res(self.post("report", v2::ReportRequest { kind: kind }))
+        }
+
+        pub fn comparison_report(&self, toolchain_from: Toolchain, toolchain_to: Toolchain
+                                 ) -> Result<v1::ComparisonReport, Error> {
+            let req = v2::ReportRequest {
+                kind: v1::ReportKind::Comparison {
+                    toolchain_from: toolchain_from,
+                    toolchain_to: toolchain_to
+                }
             };
-            stdio_req(&self.config, "report", req)
+            self.post("report", req)
+        }
+
+        pub fn ice_report(&self, toolchain: Toolchain) -> Result<v1::IceReport, Error> {
+            let req = v2::ReportRequest {
+                kind: v1::ReportKind::Ices { toolchain: toolchain }
+            };
+            self.post("report", req)
+        }
+
+        pub fn sync_index(&self) -> Result<v1::SyncIndexResponse, Error> {
+            self.send(Method::Post, "sync_index", None)
         }
 
         pub fn self_test(&self) -> Result<String, Error> {
-            let req = v1::SelfTestRequest {
-                auth: self.auth()
+            stdio_res(self.send(Method::Get, "self-test", None))
+        }
+
+        pub fn create_experiment(&self, name: String, toolchain_from: Toolchain,
+                                 toolchain_to: Toolchain, selection: v1::CrateSelection
+                                 ) -> Result<v1::Experiment, Error> {
+            let req = v2::CreateExperimentRequest {
+                name: name,
+                toolchain_from: toolchain_from,
+                toolchain_to: toolchain_to,
+                selection: selection
+            };
+            self.post("experiments", req)
+        }
+
+        pub fn list_experiments(&self) -> Result<Vec<v1::Experiment>, Error> {
+            self.send(Method::Get, "experiments", None)
+        }
+
+        pub fn show_experiment(&self, name: String) -> Result<v1::Experiment, Error> {
+            self.send(Method::Get, &format!("experiments/{}", path_segment(&name)), None)
+        }
+
+        pub fn delete_experiment(&self, name: String) -> Result<v1::Experiment, Error> {
+            self.send(Method::Delete, &format!("experiments/{}", path_segment(&name)), None)
+        }
+
+        pub fn run_experiment(&self, name: String) -> Result<v1::CrateBuildResponse, Error> {
+            self.send(Method::Post, &format!("experiments/{}/run", path_segment(&name)), None)
+        }
+
+        pub fn experiment_status(&self, name: String) -> Result<v1::ExperimentProgress, Error> {
+            self.send(Method::Get, &format!("experiments/{}/status", path_segment(&name)), None)
+        }
+
+        pub fn create_user(&self, name: String, role: Role) -> Result<v1::UserTokenResponse, Error> {
+            let req = v2::CreateUserRequest {
+                name: name,
+                role: role
             };
-            stdio_req(&self.config, "self-test", req)
+            self.post("users", req)
         }
 
-        fn auth(&self) -> v1::Auth {
-            v1::Auth {
+        pub fn revoke_user(&self, name: String) -> Result<v1::RevokeUserResponse, Error> {
+            self.send(Method::Delete, &format!("users/{}", path_segment(&name)), None)
+        }
+
+        pub fn rotate_token(&self, name: String) -> Result<v1::UserTokenResponse, Error> {
+            self.send(Method::Post, &format!("users/{}/rotate", path_segment(&name)), None)
+        }
+
+        pub fn quota(&self) -> Result<v1::QuotaResponse, Error> {
+            self.send(Method::Get, "quota", None)
+        }
+
+        pub fn audit_log(&self, offset: u64, limit: u64) -> Result<Vec<v1::AuditEntry>, Error> {
+            self.send(Method::Get, &format!("audit?offset={}&limit={}", offset, limit), None)
+        }
+
+        /// Returns the log excerpt as is, since it's plain text
+        pub fn build_log(&self, toolchain: Toolchain, crate_name: String, crate_vers: String
+                         ) -> Result<String, Error> {
+            let path = format!("results/{}/{}/{}/log", path_segment(&toolchain.to_string()),
+                               path_segment(&crate_name), path_segment(&crate_vers));
+            self.send_text(Method::Get, &path, None)
+        }
+
+        fn credentials(&self) -> v2::Credentials {
+            v2::Credentials {
                 name: self.config.username.clone(),
                 token: self.config.auth_token.clone()
             }
         }
-    }
 
-    fn stdio_req<T>(config: &Config, name: &str, ref req: T) -> Result<String, Error>
-        where T: Encodable {
-        let ref api_url = format!("{}/api/v1/{}", config.server_url, name);
-        info!("api endpoint: {}", api_url);
-        let ref req_str = try!(json::encode(req));
+        fn post<T, R>(&self, path: &str, ref req: T) -> Result<R, Error>
+            where T: Encodable, R: Decodable {
+            let req_str = try!(json::encode(req));
+            self.send(Method::Post, path, Some(req_str))
+        }
 
-        let mut client = Client::new();
-        let mut http_res = try!(client.post(api_url).body(req_str).send());
-        let ref mut res_str = String::new();
-        try!(http_res.read_to_string(res_str));
+        fn send<R>(&self, method: Method, path: &str, body: Option<String>) -> Result<R, Error>
+            where R: Decodable {
+            let ref res_str = try!(self.send_text(method, path, body));
+            Ok(try!(json::decode(res_str)))
+        }
 
-        let res: v1::StdIoResponse = try!(json::decode(res_str));
-        let stdout = try!(Result::from(res));
+        fn send_text(&self, method: Method, path: &str, body: Option<String>
+                     ) -> Result<String, Error> {
+            let ref api_url = format!("{}/api/v2/{}", self.config.server_url, path);
+            info!("api endpoint: {} {}", method, api_url);
+
+            let client = Client::new();
+            let mut req = client.request(method, api_url)
+                .header(Authorization(self.credentials().header_value()));
+            if let Some(ref body) = body {
+                req = req.body(&body[..]);
+            }
+            let mut http_res = try!(req.send());
+            let mut res_str = String::new();
+            try!(http_res.read_to_string(&mut res_str));
+
+            if !http_res.status.is_success() {
+                return Err(match json::decode::<v1::ApiError>(&res_str) {
+                    Ok(e) => Error::ApiError(e),
+                    Err(_) => Error::HttpError(format!("server returned {}", http_res.status))
+                });
+            }
+
+            Ok(res_str)
+        }
+    }
+
+    fn stdio_res(res: Result<v1::StdIoResponse, Error>) -> Result<String, Error> {
+        let stdout = try!(Result::from(try!(res)));
 
         Ok(stdout)
     }
+
+    /// Percent-encodes a name typed by the user so it stays one
+    /// segment of the path. Names made by crater never need it, but
+    /// anything else can be asked for. A leading dot is encoded so
+    /// `.` and `..` aren't taken as relative paths.
+    fn path_segment(name: &str) -> String {
+        let mut s = String::new();
+        for (i, b) in name.bytes().enumerate() {
+            match b {
+                b'a'...b'z' | b'A'...b'Z' | b'0'...b'9' | b'-' | b'_' | b'~' => s.push(b as char),
+                b'.' if i > 0 => s.push('.'),
+                _ => s.push_str(&format!("%{:02X}", b))
+            }
+        }
+
+        s
+    }
 }
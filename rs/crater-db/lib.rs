@@ -2,27 +2,201 @@
 
 extern crate postgres;
 extern crate rustc_serialize;
+#[macro_use]
+extern crate log;
+extern crate crypto;
+extern crate rand;
+extern crate crater_api as api;
+extern crate crater_index as index;
 
+use api::{BuildStatus, Diagnostic, Role, Toolchain};
+use api::v1::{CrateSelection, ExperimentState};
+use index::Index;
+use rustc_serialize::json;
+use std::collections::{BTreeMap, BTreeSet};
 use std::error::Error as StdError;
+use std::i64;
 
 use postgres::{Connection, SslMode};
+use postgres::types::ToSql;
+
+pub use migrations::{Migration, MIGRATIONS};
+
+mod migrations;
+mod tokens;
 
 #[derive(PartialEq, Debug)]
 pub struct BuildResult {
-    pub toolchain: String,
+    pub toolchain: Toolchain,
+    pub crate_name: String,
+    pub crate_vers: String,
+    pub status: BuildStatus,
+    pub task_id: String
+}
+
+/// The results of building the same crate revision with two toolchains
+#[derive(PartialEq, Debug)]
+pub struct BuildResultPair {
+    pub from: BuildResult,
+    pub to: BuildResult
+}
+
+/// The interesting parts of a crate build's log. Whole logs are too
+/// big to keep for every build.
+#[derive(PartialEq, Debug)]
+pub struct BuildLog {
+    pub toolchain: Toolchain,
+    pub crate_name: String,
+    pub crate_vers: String,
+    pub task_id: String,
+    /// The first lines that look like errors or panics
+    pub errors: String,
+    /// The last lines of the log
+    pub tail: String
+}
+
+/// A direct dependency of one crate on another
+#[derive(PartialEq, Debug)]
+pub struct DepEdge {
+    pub name: String,
+    pub dep: String
+}
+
+/// A compiler built by a custom-build task. Keyed by the commit
+/// sha alone, which is how crate builds refer to it.
+#[derive(PartialEq, Debug)]
+pub struct CustomToolchain {
+    pub toolchain: Toolchain,
+    pub status: BuildStatus,
+    pub task_id: String
+}
+
+/// A named comparison of two toolchains over a set of crates
+#[derive(PartialEq, Debug)]
+pub struct Experiment {
+    pub name: String,
+    pub owner: String,
+    pub toolchain_from: Toolchain,
+    pub toolchain_to: Toolchain,
+    pub crate_selection: CrateSelection,
+    /// Seconds since the Unix epoch
+    pub created_at: i64,
+    pub state: ExperimentState
+}
+
+/// A crate build submitted to TaskCluster. Results are matched to
+/// jobs by task id.
+#[derive(PartialEq, Debug)]
+pub struct ScheduledJob {
+    /// An experiment name, or the id given to an ad-hoc crate build
+    pub experiment: String,
+    pub toolchain: Toolchain,
     pub crate_name: String,
     pub crate_vers: String,
-    pub status: String,
     pub task_id: String
 }
 
+/// Job counts for an experiment. Finished jobs built successfully
+/// or failed; jobs with exceptions will need rebuilding.
+#[derive(PartialEq, Debug)]
+pub struct ExperimentProgress {
+    pub scheduled: u64,
+    pub finished: u64,
+    pub exception: u64,
+    pub outstanding: u64
+}
+
+/// An experiment to add. The database records the creation time and
+/// the initial state.
+pub struct NewExperiment {
+    pub name: String,
+    pub owner: String,
+    pub toolchain_from: Toolchain,
+    pub toolchain_to: Toolchain,
+    pub crate_selection: CrateSelection
+}
+
+/// Someone who can use the API. Revoked users are never returned.
+#[derive(Clone, PartialEq, Debug)]
+pub struct User {
+    pub name: String,
+    pub role: Role
+}
+
+/// A record of an API call that changed something
+#[derive(PartialEq, Debug)]
+pub struct AuditEntry {
+    /// Counts up, so later entries have bigger ids
+    pub id: i64,
+    pub user: String,
+    pub endpoint: String,
+    /// The request, as JSON
+    pub params: String,
+    /// What came of it, e.g. the experiment or task ids
+    pub result: String,
+    /// Seconds since the Unix epoch
+    pub created_at: i64
+}
+
+/// An audit entry to add. The database assigns the id and time.
+pub struct NewAuditEntry {
+    pub user: String,
+    pub endpoint: String,
+    pub params: String,
+    pub result: String
+}
+
+/// What a user has scheduled in a day, counted against their quota
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct Usage {
+    pub tasks: u64,
+    pub custom_builds: u64
+}
+
+/// Row counts from `Database::sync_index`
+#[derive(Debug)]
+pub struct IndexSyncStats {
+    pub crate_versions: u64,
+    pub dep_edges: u64,
+    pub ranked_crates: u64
+}
+
+/// A window onto the rows of a query, in the query's order
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Page {
+    pub offset: u64,
+    /// `None` returns every row after `offset`
+    pub limit: Option<u64>
+}
+
+impl Page {
+    pub fn all() -> Page {
+        Page { offset: 0, limit: None }
+    }
+
+    pub fn new(offset: u64, limit: u64) -> Page {
+        Page { offset: offset, limit: Some(limit) }
+    }
+
+    /// Postgres takes `i64`s. Past the end is past the end, however
+    /// far, so larger values are clamped rather than wrapped.
+    fn offset(&self) -> i64 {
+        if self.offset > i64::MAX as u64 { i64::MAX } else { self.offset as i64 }
+    }
+
+    fn limit(&self) -> Option<i64> {
+        self.limit.map(|l| if l > i64::MAX as u64 { i64::MAX } else { l as i64 })
+    }
+}
+
 pub struct BuildResultKey {
-    pub toolchain: String,
+    pub toolchain: Toolchain,
     pub crate_name: String,
     pub crate_vers: String
 }
 
 #[derive(RustcEncodable, RustcDecodable)]
+#[derive(Clone)]
 pub struct Config {
     pub dbname: String,
     pub username: String,
@@ -39,6 +213,17 @@ impl Database {
     /// Connects and updates the db to the correct scheme
     pub fn connect(credentials: &Config
                    ) -> Result<Database, Box<StdError>> {
+        let db = try!(Database::connect_without_migrating(credentials));
+
+        try!(db.migrate());
+
+        Ok(db)
+    }
+
+    /// Connects without touching the schema, e.g. to see what
+    /// `migrate` would do
+    pub fn connect_without_migrating(credentials: &Config
+                                     ) -> Result<Database, Box<StdError>> {
         let url = make_url(&credentials.dbname,
                            &credentials.username,
                            &credentials.password,
@@ -46,51 +231,80 @@ impl Database {
                            credentials.port);
         let conn = try!(Connection::connect(&url[..], &SslMode::None));
 
-        let db = Database { conn: conn };
+        Ok(Database { conn: conn })
+    }
 
-        try!(db.create_or_upgrade_tables());
+    /// The version of the last migration applied, or 0. Doesn't
+    /// create the version table, so it's safe for dry runs.
+    pub fn schema_version(&self) -> Result<i32, Box<StdError>> {
+        if !try!(self.has_schema_version_table()) {
+            return Ok(0);
+        }
 
-        Ok(db)
+        let q = "select coalesce(max(version), 0) from schema_version";
+        let stmt = try!(self.conn.prepare(q));
+        for row in try!(stmt.query(&[])) {
+            return Ok(row.get(0));
+        }
+
+        Err(Box::from(Error::DbEmptyResultFailure))
     }
 
-    fn create_or_upgrade_tables(&self) -> Result<(), Box<StdError>> {
-        let q = "create table if not exists \
-                 build_results ( \
-                 toolchain text not null, \
-                 crate_name text not null, crate_vers text not null, \
-                 status text not null, \
-                 task_id text not null, \
-                 primary key ( \
-                 toolchain, crate_name, crate_vers ) )";
-        try!(self.conn.execute(q, &[]));
+    /// The migrations `migrate` would apply, in order
+    pub fn pending_migrations(&self) -> Result<Vec<&'static Migration>, Box<StdError>> {
+        let version = try!(self.schema_version());
 
-        let q = "create table if not exists \
-                 custom_toolchains ( \
-                 toolchain text not null, \
-                 status text not null, \
-                 task_id text not null, \
-                 primary key (toolchain) )";
-        try!(self.conn.execute(q, &[]));
+        Ok(MIGRATIONS.iter().filter(|m| m.version > version).collect())
+    }
 
-        let q = "create table if not exists \
-                 crate_versions ( \
-                 name text not null, \
-                 version text not null, \
-                 primary key (name, version) )";
-        try!(self.conn.execute(q, &[]));
+    /// Applies any pending migrations in a single transaction,
+    /// returning those applied. The version table is locked so
+    /// concurrent connections don't both apply them.
+    pub fn migrate(&self) -> Result<Vec<&'static Migration>, Box<StdError>> {
+        try!(self.create_schema_version_table());
 
-        let q = "create table if not exists \
-                 crate_rank ( \
-                 name text not null, \
-                 rank integer not null, \
-                 primary key (name) )";
-        try!(self.conn.execute(q, &[]));
+        let trans = try!(self.conn.transaction());
+        try!(trans.execute("lock table schema_version in exclusive mode", &[]));
+
+        let version: i32 = {
+            let stmt = try!(trans.prepare("select coalesce(max(version), 0) from schema_version"));
+            let rows = try!(stmt.query(&[]));
+            try!(rows.iter().next().map(|r| r.get(0)).ok_or(Error::DbEmptyResultFailure))
+        };
+
+        let pending: Vec<&'static Migration> = MIGRATIONS.iter()
+            .filter(|m| m.version > version)
+            .collect();
+        for migration in &pending {
+            for q in migration.sql {
+                try!(trans.execute(q, &[]));
+            }
+            let q = "insert into schema_version (version, name) values ($1, $2)";
+            try!(trans.execute(q, &[&migration.version, &migration.name]));
+        }
+        try!(trans.commit());
+
+        Ok(pending)
+    }
 
+    fn has_schema_version_table(&self) -> Result<bool, Box<StdError>> {
+        let q = "select exists (select 1 from information_schema.tables \
+                 where table_schema = current_schema() and table_name = 'schema_version')";
+        let stmt = try!(self.conn.prepare(q));
+        for row in try!(stmt.query(&[])) {
+            return Ok(row.get(0));
+        }
+
+        Err(Box::from(Error::DbEmptyResultFailure))
+    }
+
+    fn create_schema_version_table(&self) -> Result<(), Box<StdError>> {
         let q = "create table if not exists \
-                 dep_edges ( \
+                 schema_version ( \
+                 version integer not null, \
                  name text not null, \
-                 dep text not null, \
-                 primary key (name, dep) )";
+                 applied_at timestamp with time zone not null default now(), \
+                 primary key (version) )";
         try!(self.conn.execute(q, &[]));
 
         Ok(())
@@ -103,79 +317,778 @@ impl Database {
         let q = "drop table if exists custom_toolchains";
         try!(self.conn.execute(q, &[]));
 
-        let q = "drop table if exists crate_versions";
-        try!(self.conn.execute(q, &[]));
+        let q = "drop table if exists crate_versions";
+        try!(self.conn.execute(q, &[]));
+
+        let q = "drop table if exists crate_rank";
+        try!(self.conn.execute(q, &[]));
+
+        let q = "drop table if exists dep_edges";
+        try!(self.conn.execute(q, &[]));
+
+        let q = "drop table if exists experiments";
+        try!(self.conn.execute(q, &[]));
+
+        let q = "drop table if exists scheduled_jobs";
+        try!(self.conn.execute(q, &[]));
+
+        let q = "drop table if exists build_diagnostics";
+        try!(self.conn.execute(q, &[]));
+
+        let q = "drop table if exists build_logs";
+        try!(self.conn.execute(q, &[]));
+
+        let q = "drop table if exists users";
+        try!(self.conn.execute(q, &[]));
+
+        let q = "drop table if exists audit_log";
+        try!(self.conn.execute(q, &[]));
+
+        let q = "drop table if exists user_usage";
+        try!(self.conn.execute(q, &[]));
+
+        let q = "drop table if exists schema_version";
+        try!(self.conn.execute(q, &[]));
+
+        Ok(())
+    }
+
+    pub fn add_build_result(&self, build_result: &BuildResult) -> Result<(), Box<StdError>> {
+        let q = format!("insert into build_results values ($1, $2, $3, $4, $5) {}",
+                        UPSERT_BUILD_RESULTS);
+        try!(self.conn.execute(&q, &[
+            &build_result.toolchain.key(),
+            &build_result.crate_name,
+            &build_result.crate_vers,
+            &build_result.status.to_string(),
+            &build_result.task_id]));
+
+        Ok(())
+    }
+
+    /// Records many results at once, in a single transaction. If the
+    /// same crate and toolchain appear more than once the last result
+    /// wins.
+    pub fn add_build_results(&self, build_results: &[BuildResult]) -> Result<(), Box<StdError>> {
+        self.add_build_results_in_chunks(build_results, BUILD_RESULTS_PER_INSERT)
+    }
+
+    /// `add_build_results`, inserting at most `per_insert` rows per
+    /// statement
+    fn add_build_results_in_chunks(&self, build_results: &[BuildResult], per_insert: usize
+                                   ) -> Result<(), Box<StdError>> {
+        // Postgres won't update the same row twice in one statement
+        let mut latest = BTreeMap::new();
+        for r in build_results {
+            latest.insert((r.toolchain.key(), &*r.crate_name, &*r.crate_vers), r);
+        }
+        let rows: Vec<(String, String, &BuildResult)> = latest.into_iter()
+            .map(|((toolchain, _, _), r)| (toolchain, r.status.to_string(), r))
+            .collect();
+
+        let trans = try!(self.conn.transaction());
+        for chunk in rows.chunks(per_insert) {
+            let mut values = Vec::new();
+            let mut params: Vec<&ToSql> = Vec::new();
+            for (i, &(ref toolchain, ref status, r)) in chunk.iter().enumerate() {
+                let n = i * 5;
+                values.push(format!("(${}, ${}, ${}, ${}, ${})", n + 1, n + 2, n + 3, n + 4, n + 5));
+                params.push(toolchain);
+                params.push(&r.crate_name);
+                params.push(&r.crate_vers);
+                params.push(status);
+                params.push(&r.task_id);
+            }
+            let q = format!("insert into build_results values {} {}",
+                            values.join(", "), UPSERT_BUILD_RESULTS);
+            try!(trans.execute(&q, &params));
+        }
+        try!(trans.commit());
+
+        Ok(())
+    }
+
+    pub fn get_build_result(&self, key: &BuildResultKey) -> Result<BuildResult, Box<StdError>> {
+        let q = format!("{} where toolchain = $1 and crate_name = $2 and crate_vers = $3",
+                        SELECT_BUILD_RESULTS);
+        let stmt = try!(self.conn.prepare(&q));
+        for row in try!(stmt.query(&[&key.toolchain.key(), &key.crate_name, &key.crate_vers])) {
+            return build_result_from_row(&key.toolchain, &row);
+        }
+
+        Err(Box::from(Error::DbEmptyResultFailure))
+    }
+
+    /// Records the log excerpt for a build, replacing any earlier one
+    pub fn add_build_log(&self, log: &BuildLog) -> Result<(), Box<StdError>> {
+        let q = "insert into build_logs values ($1, $2, $3, $4, $5, $6) \
+                 on conflict (toolchain, crate_name, crate_vers) do update \
+                 set task_id = excluded.task_id, errors = excluded.errors, tail = excluded.tail";
+        try!(self.conn.execute(q, &[
+            &log.toolchain.key(),
+            &log.crate_name,
+            &log.crate_vers,
+            &log.task_id,
+            &log.errors,
+            &log.tail]));
+
+        Ok(())
+    }
+
+    pub fn get_build_log(&self, key: &BuildResultKey) -> Result<Option<BuildLog>, Box<StdError>> {
+        let q = "select toolchain, crate_name, crate_vers, task_id, errors, tail from build_logs \
+                 where toolchain = $1 and crate_name = $2 and crate_vers = $3";
+        let stmt = try!(self.conn.prepare(q));
+        for row in try!(stmt.query(&[&key.toolchain.key(), &key.crate_name, &key.crate_vers])) {
+            return Ok(Some(build_log_from_row(&key.toolchain, &row)));
+        }
+
+        Ok(None)
+    }
+
+    /// Forgets the log of a build, e.g. when a rebuild's log couldn't
+    /// be fetched and the old one would be misleading
+    pub fn delete_build_log(&self, key: &BuildResultKey) -> Result<bool, Box<StdError>> {
+        let q = "delete from build_logs \
+                 where toolchain = $1 and crate_name = $2 and crate_vers = $3";
+        let rows = try!(self.conn.execute(q, &[&key.toolchain.key(), &key.crate_name,
+                                               &key.crate_vers]));
+
+        Ok(rows > 0)
+    }
+
+    /// The logs of one toolchain's builds that match a POSIX regular
+    /// expression, most popular crates first
+    pub fn search_build_logs(&self, toolchain: &Toolchain, pattern: &str
+                             ) -> Result<Vec<BuildLog>, Box<StdError>> {
+        let q = "select l.toolchain, l.crate_name, l.crate_vers, l.task_id, l.errors, l.tail \
+                 from build_logs l left join crate_rank r on r.name = l.crate_name \
+                 where l.toolchain = $1 and (l.errors ~ $2 or l.tail ~ $2) \
+                 order by r.rank nulls last, l.crate_name, l.crate_vers";
+        let stmt = try!(self.conn.prepare(q));
+        let mut logs = Vec::new();
+        for row in try!(stmt.query(&[&toolchain.key(), &pattern])) {
+            logs.push(build_log_from_row(toolchain, &row));
+        }
+
+        Ok(logs)
+    }
+
+    /// Replaces the diagnostics found in a build's log
+    pub fn set_diagnostics(&self, key: &BuildResultKey, diagnostics: &[Diagnostic]
+                           ) -> Result<(), Box<StdError>> {
+        let toolchain = key.toolchain.key();
+        let trans = try!(self.conn.transaction());
+        {
+            let q = "delete from build_diagnostics where \
+                     toolchain = $1 and crate_name = $2 and crate_vers = $3";
+            try!(trans.execute(q, &[&toolchain, &key.crate_name, &key.crate_vers]));
+
+            let q = "insert into build_diagnostics values ($1, $2, $3, $4, $5, $6)";
+            let stmt = try!(trans.prepare(q));
+            for (i, diagnostic) in diagnostics.iter().enumerate() {
+                let (kind, message) = diagnostic_to_row(diagnostic);
+                try!(stmt.execute(&[&toolchain, &key.crate_name, &key.crate_vers,
+                                    &(i as i32), &kind, &message]));
+            }
+        }
+        try!(trans.commit());
+
+        Ok(())
+    }
+
+    /// The diagnostics of one build, in the order they appeared
+    pub fn get_diagnostics(&self, key: &BuildResultKey) -> Result<Vec<Diagnostic>, Box<StdError>> {
+        let q = "select kind, message from build_diagnostics where \
+                 toolchain = $1 and crate_name = $2 and crate_vers = $3 \
+                 order by position";
+        let stmt = try!(self.conn.prepare(q));
+        let mut diagnostics = Vec::new();
+        for row in try!(stmt.query(&[&key.toolchain.key(), &key.crate_name, &key.crate_vers])) {
+            diagnostics.push(try!(diagnostic_from_row(row.get(0), row.get(1))));
+        }
+
+        Ok(diagnostics)
+    }
+
+    /// The diagnostics of every build with one toolchain, keyed by
+    /// crate name and version
+    pub fn get_toolchain_diagnostics(&self, toolchain: &Toolchain
+                                     ) -> Result<BTreeMap<(String, String), Vec<Diagnostic>>,
+                                                 Box<StdError>> {
+        let q = "select crate_name, crate_vers, kind, message from build_diagnostics \
+                 where toolchain = $1 order by crate_name, crate_vers, position";
+        let stmt = try!(self.conn.prepare(q));
+        let mut diagnostics = BTreeMap::new();
+        for row in try!(stmt.query(&[&toolchain.key()])) {
+            let diagnostic = try!(diagnostic_from_row(row.get(2), row.get(3)));
+            diagnostics.entry((row.get(0), row.get(1))).or_insert_with(Vec::new).push(diagnostic);
+        }
+
+        Ok(diagnostics)
+    }
+
+    /// Records the outcome of a custom toolchain build, replacing any
+    /// earlier build of the same toolchain
+    pub fn add_custom_toolchain(&self, custom: &CustomToolchain) -> Result<(), Box<StdError>> {
+        let toolchain = custom.toolchain.key();
+        let status = custom.status.to_string();
+        let q = "insert into custom_toolchains values ($1, $2, $3) \
+                 on conflict (toolchain) do update \
+                 set status = excluded.status, task_id = excluded.task_id";
+        try!(self.conn.execute(q, &[&toolchain, &status, &custom.task_id]));
+
+        Ok(())
+    }
+
+    pub fn get_custom_toolchain(&self, toolchain: &Toolchain
+                                ) -> Result<Option<CustomToolchain>, Box<StdError>> {
+        let q = "select toolchain, status, task_id from custom_toolchains where toolchain = $1";
+        let stmt = try!(self.conn.prepare(q));
+        for row in try!(stmt.query(&[&toolchain.key()])) {
+            let status: String = row.get(1);
+            return Ok(Some(CustomToolchain {
+                toolchain: toolchain.clone(),
+                status: try!(status.parse()),
+                task_id: row.get(2)
+            }));
+        }
+
+        Ok(None)
+    }
+
+    /// Returns every result for one toolchain, ordered by crate name
+    /// and version
+    pub fn get_results(&self, toolchain: &Toolchain, page: Page
+                       ) -> Result<Vec<BuildResult>, Box<StdError>> {
+        let q = format!("{} where toolchain = $1 \
+                         order by crate_name, crate_vers \
+                         offset $2 limit $3", SELECT_BUILD_RESULTS);
+        let stmt = try!(self.conn.prepare(&q));
+        let mut results = Vec::new();
+        for row in try!(stmt.query(&[&toolchain.key(), &page.offset(), &page.limit()])) {
+            results.push(try!(build_result_from_row(toolchain, &row)));
+        }
+
+        Ok(results)
+    }
+
+    /// Returns the results for every crate revision that has been built
+    /// with both toolchains, most popular crate first. Unranked crates
+    /// come last; ties are ordered by crate name and version.
+    pub fn get_result_pairs(&self, toolchain_from: &Toolchain, toolchain_to: &Toolchain,
+                            page: Page) -> Result<Vec<BuildResultPair>, Box<StdError>> {
+        let q = "select a.crate_name, a.crate_vers, \
+                 a.status, a.task_id, b.status, b.task_id \
+                 from build_results a join build_results b \
+                 on a.crate_name = b.crate_name and a.crate_vers = b.crate_vers \
+                 left join crate_rank r on r.name = a.crate_name \
+                 where a.toolchain = $1 and b.toolchain = $2 \
+                 order by r.rank nulls last, a.crate_name, a.crate_vers \
+                 offset $3 limit $4";
+        let stmt = try!(self.conn.prepare(q));
+        let mut pairs = Vec::new();
+        for row in try!(stmt.query(&[&toolchain_from.key(), &toolchain_to.key(),
+                                     &page.offset(), &page.limit()])) {
+            let crate_name: String = row.get(0);
+            let crate_vers: String = row.get(1);
+            let status_from: String = row.get(2);
+            let status_to: String = row.get(4);
+            pairs.push(BuildResultPair {
+                from: BuildResult {
+                    toolchain: toolchain_from.clone(),
+                    crate_name: crate_name.clone(),
+                    crate_vers: crate_vers.clone(),
+                    status: try!(status_from.parse()),
+                    task_id: row.get(3)
+                },
+                to: BuildResult {
+                    toolchain: toolchain_to.clone(),
+                    crate_name: crate_name,
+                    crate_vers: crate_vers,
+                    status: try!(status_to.parse()),
+                    task_id: row.get(5)
+                }
+            });
+        }
+
+        Ok(pairs)
+    }
+
+    /// Every toolchain with at least one result, in string order.
+    /// Toolchains that don't parse, e.g. from old versions of
+    /// monitor.js, are skipped.
+    pub fn list_toolchains(&self) -> Result<Vec<Toolchain>, Box<StdError>> {
+        let q = "select distinct toolchain from build_results order by toolchain";
+        let stmt = try!(self.conn.prepare(q));
+        let mut toolchains = Vec::new();
+        for row in try!(stmt.query(&[])) {
+            let toolchain: String = row.get(0);
+            if let Some(toolchain) = parse_stored_toolchain(&toolchain) {
+                toolchains.push(toolchain);
+            }
+        }
+
+        Ok(toolchains)
+    }
+
+    /// The history of one crate across every toolchain, ordered by
+    /// version then toolchain. Results for toolchains that don't parse
+    /// are skipped.
+    pub fn get_results_for_crate(&self, crate_name: &str, page: Page
+                                 ) -> Result<Vec<BuildResult>, Box<StdError>> {
+        let q = format!("{} where crate_name = $1 \
+                         order by crate_vers, toolchain \
+                         offset $2 limit $3", SELECT_BUILD_RESULTS);
+        let stmt = try!(self.conn.prepare(&q));
+        let mut results = Vec::new();
+        for row in try!(stmt.query(&[&crate_name, &page.offset(), &page.limit()])) {
+            let toolchain: String = row.get(0);
+            if let Some(ref toolchain) = parse_stored_toolchain(&toolchain) {
+                results.push(try!(build_result_from_row(toolchain, &row)));
+            }
+        }
+
+        Ok(results)
+    }
+
+    /// Records dependency edges, ignoring any that already exist.
+    /// Returns how many were new.
+    pub fn add_dep_edges(&self, edges: &[DepEdge]) -> Result<u64, Box<StdError>> {
+        let trans = try!(self.conn.transaction());
+        let mut added = 0;
+        {
+            let q = "insert into dep_edges values ($1, $2) on conflict do nothing";
+            let stmt = try!(trans.prepare(q));
+            for edge in edges {
+                added += try!(stmt.execute(&[&edge.name, &edge.dep]));
+            }
+        }
+        try!(trans.commit());
+
+        Ok(added)
+    }
+
+    /// Replaces the contents of the `crate_versions`, `dep_edges` and
+    /// `crate_rank` tables with data from the index, in a single
+    /// transaction
+    pub fn sync_index(&self, index: &Index) -> Result<IndexSyncStats, Box<StdError>> {
+        let trans = try!(self.conn.transaction());
+
+        try!(trans.execute("delete from crate_versions", &[]));
+        try!(trans.execute("delete from dep_edges", &[]));
+        try!(trans.execute("delete from crate_rank", &[]));
+
+        let mut stats = IndexSyncStats {
+            crate_versions: 0,
+            dep_edges: 0,
+            ranked_crates: 0
+        };
+
+        {
+            // The index occasionally lists a revision twice
+            let versions: BTreeSet<(&str, &str)> = index.versions().iter()
+                .map(|v| (&*v.name, &*v.vers))
+                .collect();
+            let stmt = try!(trans.prepare("insert into crate_versions values ($1, $2)"));
+            for (name, vers) in versions {
+                stats.crate_versions += try!(stmt.execute(&[&name, &vers]));
+            }
+        }
+
+        {
+            let mut edges = BTreeSet::new();
+            for (name, deps) in index.dag() {
+                for dep in deps {
+                    edges.insert((name, dep));
+                }
+            }
+            let stmt = try!(trans.prepare("insert into dep_edges values ($1, $2)"));
+            for (name, dep) in edges {
+                stats.dep_edges += try!(stmt.execute(&[&name, &dep]));
+            }
+        }
+
+        {
+            let stmt = try!(trans.prepare("insert into crate_rank values ($1, $2)"));
+            for (i, name) in index.popularity_ranking().into_iter().enumerate() {
+                let rank = i as i32 + 1;
+                stats.ranked_crates += try!(stmt.execute(&[&name, &rank]));
+            }
+        }
+
+        try!(trans.commit());
+
+        Ok(stats)
+    }
+
+    /// Adds an experiment in the `Created` state. Returns false if the
+    /// name is taken.
+    pub fn add_experiment(&self, experiment: &NewExperiment) -> Result<bool, Box<StdError>> {
+        let q = "insert into experiments \
+                 (name, owner, toolchain_from, toolchain_to, crate_selection, state) \
+                 values ($1, $2, $3, $4, $5, $6) on conflict (name) do nothing";
+        let selection = try!(json::encode(&experiment.crate_selection));
+        let rows = try!(self.conn.execute(q, &[&experiment.name,
+                                               &experiment.owner,
+                                               &experiment.toolchain_from.to_string(),
+                                               &experiment.toolchain_to.to_string(),
+                                               &selection,
+                                               &ExperimentState::Created.to_string()]));
+
+        Ok(rows > 0)
+    }
+
+    pub fn get_experiment(&self, name: &str) -> Result<Option<Experiment>, Box<StdError>> {
+        let q = format!("{} where name = $1", SELECT_EXPERIMENTS);
+        let stmt = try!(self.conn.prepare(&q));
+        for row in try!(stmt.query(&[&name])) {
+            return Ok(Some(try!(experiment_from_row(&row))));
+        }
+
+        Ok(None)
+    }
+
+    /// Returns every experiment, newest first
+    pub fn list_experiments(&self) -> Result<Vec<Experiment>, Box<StdError>> {
+        let q = format!("{} order by created_at desc, name", SELECT_EXPERIMENTS);
+        let stmt = try!(self.conn.prepare(&q));
+        let mut experiments = Vec::new();
+        for row in try!(stmt.query(&[])) {
+            experiments.push(try!(experiment_from_row(&row)));
+        }
+
+        Ok(experiments)
+    }
+
+    pub fn set_experiment_state(&self, name: &str, state: ExperimentState
+                                ) -> Result<(), Box<StdError>> {
+        let q = "update experiments set state = $2 where name = $1";
+        let rows = try!(self.conn.execute(q, &[&name, &state.to_string()]));
+        if rows == 0 {
+            return Err(Box::from(Error::DbEmptyResultFailure));
+        }
+
+        Ok(())
+    }
+
+    /// Moves an experiment from one state to another in a single
+    /// statement. Returns false if it wasn't in state `from`.
+    pub fn transition_experiment(&self, name: &str, from: ExperimentState, to: ExperimentState
+                                 ) -> Result<bool, Box<StdError>> {
+        let q = "update experiments set state = $3 where name = $1 and state = $2";
+        let rows = try!(self.conn.execute(q, &[&name, &from.to_string(), &to.to_string()]));
+
+        Ok(rows > 0)
+    }
+
+    /// Deletes an experiment and its jobs. Returns false if there was
+    /// no such experiment.
+    pub fn delete_experiment(&self, name: &str) -> Result<bool, Box<StdError>> {
+        let trans = try!(self.conn.transaction());
+        try!(trans.execute("delete from scheduled_jobs where experiment = $1", &[&name]));
+        let rows = try!(trans.execute("delete from experiments where name = $1", &[&name]));
+        try!(trans.commit());
+
+        Ok(rows > 0)
+    }
+
+    pub fn add_scheduled_jobs(&self, jobs: &[ScheduledJob]) -> Result<(), Box<StdError>> {
+        let trans = try!(self.conn.transaction());
+        {
+            let q = "insert into scheduled_jobs \
+                     (experiment, toolchain, crate_name, crate_vers, task_id) \
+                     values ($1, $2, $3, $4, $5)";
+            let stmt = try!(trans.prepare(q));
+            for job in jobs {
+                try!(stmt.execute(&[&job.experiment, &job.toolchain.to_string(),
+                                    &job.crate_name, &job.crate_vers, &job.task_id]));
+            }
+        }
+        try!(trans.commit());
+
+        Ok(())
+    }
+
+    /// Records how a scheduled job ended. Returns false if no job has
+    /// the task id, e.g. for tasks scheduled by scheduler.js.
+    pub fn set_job_status(&self, task_id: &str, status: BuildStatus
+                          ) -> Result<bool, Box<StdError>> {
+        let q = "update scheduled_jobs set status = $2 where task_id = $1";
+        let rows = try!(self.conn.execute(q, &[&task_id, &status.to_string()]));
+
+        Ok(rows > 0)
+    }
+
+    /// Counts an experiment's jobs by their status. The experiment
+    /// can be a named one or the id of an ad-hoc crate build.
+    pub fn get_experiment_progress(&self, experiment: &str
+                                   ) -> Result<ExperimentProgress, Box<StdError>> {
+        let q = "select count(*), \
+                 count(case when status in ('success', 'failure') then 1 end), \
+                 count(case when status = 'exception' then 1 end) \
+                 from scheduled_jobs where experiment = $1";
+        let stmt = try!(self.conn.prepare(q));
+        for row in try!(stmt.query(&[&experiment])) {
+            let scheduled: i64 = row.get(0);
+            let finished: i64 = row.get(1);
+            let exception: i64 = row.get(2);
+            return Ok(ExperimentProgress {
+                scheduled: scheduled as u64,
+                finished: finished as u64,
+                exception: exception as u64,
+                outstanding: (scheduled - finished - exception) as u64
+            });
+        }
+
+        Err(Box::from(Error::DbEmptyResultFailure))
+    }
+
+    /// Returns every dependency edge, ordered by crate name
+    pub fn get_dep_edges(&self) -> Result<Vec<DepEdge>, Box<StdError>> {
+        let q = "select name, dep from dep_edges order by name, dep";
+        let stmt = try!(self.conn.prepare(q));
+        let mut edges = Vec::new();
+        for row in try!(stmt.query(&[])) {
+            edges.push(DepEdge {
+                name: row.get(0),
+                dep: row.get(1)
+            });
+        }
+
+        Ok(edges)
+    }
+
+    /// Adds a user, returning their token. Returns `None` if the name
+    /// is taken. The name of a revoked user can be taken again.
+    pub fn add_user(&self, name: &str, role: Role) -> Result<Option<String>, Box<StdError>> {
+        let q = "insert into users (name, role, salt, token_hash) \
+                 values ($1, $2, $3, $4) \
+                 on conflict (name) do update \
+                 set role = excluded.role, salt = excluded.salt, \
+                 token_hash = excluded.token_hash, \
+                 created_at = now(), revoked_at = null \
+                 where users.revoked_at is not null";
+        let token = tokens::new_token();
+        let salt = tokens::new_salt();
+        let hash = tokens::hash_token(&token, &salt);
+        let rows = try!(self.conn.execute(q, &[&name, &role.to_string(), &salt, &hash]));
+
+        Ok(if rows > 0 { Some(token) } else { None })
+    }
+
+    pub fn get_user(&self, name: &str) -> Result<Option<User>, Box<StdError>> {
+        let q = "select name, role from users where name = $1 and revoked_at is null";
+        let stmt = try!(self.conn.prepare(q));
+        for row in try!(stmt.query(&[&name])) {
+            let role: String = row.get(1);
+            return Ok(Some(User {
+                name: row.get(0),
+                role: try!(role.parse())
+            }));
+        }
+
+        Ok(None)
+    }
+
+    /// Looks up the user with this name and token
+    pub fn authenticate(&self, name: &str, token: &str) -> Result<Option<User>, Box<StdError>> {
+        let q = "select role, salt, token_hash from users \
+                 where name = $1 and revoked_at is null";
+        let stmt = try!(self.conn.prepare(q));
+        let rows = try!(stmt.query(&[&name]));
+        let user: Option<(String, String, String)> = rows.iter().next().map(|row| {
+            (row.get(0), row.get(1), row.get(2))
+        });
+
+        // Unknown and revoked users are hashed too, so response times
+        // don't give away which names exist
+        let matches = match user {
+            Some((_, ref salt, ref hash)) => tokens::token_matches(token, salt, hash),
+            None => tokens::token_matches(token, tokens::DUMMY_SALT, tokens::DUMMY_HASH)
+        };
+        match user {
+            Some((role, _, _)) if matches => Ok(Some(User {
+                name: String::from(name),
+                role: try!(role.parse())
+            })),
+            _ => Ok(None)
+        }
+    }
+
+    /// Gives a user a new token, returning it. Returns `None` for
+    /// unknown or revoked users.
+    pub fn rotate_token(&self, name: &str) -> Result<Option<String>, Box<StdError>> {
+        let q = "update users set salt = $2, token_hash = $3 \
+                 where name = $1 and revoked_at is null";
+        let token = tokens::new_token();
+        let salt = tokens::new_salt();
+        let hash = tokens::hash_token(&token, &salt);
+        let rows = try!(self.conn.execute(q, &[&name, &salt, &hash]));
+
+        Ok(if rows > 0 { Some(token) } else { None })
+    }
+
+    /// Stops a user's token from working. Returns false if there was
+    /// no such user, or they were already revoked.
+    pub fn revoke_user(&self, name: &str) -> Result<bool, Box<StdError>> {
+        let q = "update users set revoked_at = now() \
+                 where name = $1 and revoked_at is null";
+        let rows = try!(self.conn.execute(q, &[&name]));
+
+        Ok(rows > 0)
+    }
+
+    pub fn add_audit_entry(&self, entry: &NewAuditEntry) -> Result<(), Box<StdError>> {
+        let q = "insert into audit_log (user_name, endpoint, params, result) \
+                 values ($1, $2, $3, $4)";
+        try!(self.conn.execute(q, &[&entry.user, &entry.endpoint,
+                                    &entry.params, &entry.result]));
+
+        Ok(())
+    }
+
+    /// Returns the audit log, newest first
+    pub fn get_audit_log(&self, page: Page) -> Result<Vec<AuditEntry>, Box<StdError>> {
+        let q = "select id, user_name, endpoint, params, result, \
+                 extract(epoch from created_at)::bigint from audit_log \
+                 order by id desc \
+                 offset $1 limit $2";
+        let stmt = try!(self.conn.prepare(q));
+        let mut entries = Vec::new();
+        for row in try!(stmt.query(&[&page.offset(), &page.limit()])) {
+            entries.push(AuditEntry {
+                id: row.get(0),
+                user: row.get(1),
+                endpoint: row.get(2),
+                params: row.get(3),
+                result: row.get(4),
+                created_at: row.get(5)
+            });
+        }
+
+        Ok(entries)
+    }
+
+    /// What the user has scheduled today, UTC
+    pub fn get_usage(&self, user_name: &str) -> Result<Usage, Box<StdError>> {
+        let q = "select tasks, custom_builds from user_usage \
+                 where user_name = $1 and day = (now() at time zone 'utc')::date";
+        let stmt = try!(self.conn.prepare(q));
+        for row in try!(stmt.query(&[&user_name])) {
+            let tasks: i64 = row.get(0);
+            let custom_builds: i64 = row.get(1);
+            return Ok(Usage {
+                tasks: tasks as u64,
+                custom_builds: custom_builds as u64
+            });
+        }
+
+        Ok(Usage::default())
+    }
+
+    /// Adds to the user's counts for today, UTC
+    pub fn add_usage(&self, user_name: &str, usage: &Usage) -> Result<(), Box<StdError>> {
+        let q = "insert into user_usage (user_name, day, tasks, custom_builds) \
+                 values ($1, (now() at time zone 'utc')::date, $2, $3) \
+                 on conflict (user_name, day) do update \
+                 set tasks = user_usage.tasks + excluded.tasks, \
+                 custom_builds = user_usage.custom_builds + excluded.custom_builds";
+        try!(self.conn.execute(q, &[&user_name, &(usage.tasks as i64),
+                                    &(usage.custom_builds as i64)]));
+
+        Ok(())
+    }
+
+    /// Counts a custom build against the user's usage for today unless
+    /// they've had `limit` already. One statement, so concurrent
+    /// requests can't both take the last one. Returns whether it was
+    /// counted.
+    pub fn reserve_custom_build(&self, user_name: &str, limit: Option<u64>
+                                ) -> Result<bool, Box<StdError>> {
+        let limit = match limit {
+            Some(0) => return Ok(false),
+            Some(limit) => limit,
+            None => {
+                try!(self.add_usage(user_name, &Usage { tasks: 0, custom_builds: 1 }));
+                return Ok(true);
+            }
+        };
+        let q = "insert into user_usage (user_name, day, tasks, custom_builds) \
+                 values ($1, (now() at time zone 'utc')::date, 0, 1) \
+                 on conflict (user_name, day) do update \
+                 set custom_builds = user_usage.custom_builds + 1 \
+                 where user_usage.custom_builds < $2";
+        let rows = try!(self.conn.execute(q, &[&user_name, &(limit as i64)]));
 
-        let q = "drop table if exists crate_rank";
-        try!(self.conn.execute(q, &[]));
+        Ok(rows > 0)
+    }
 
-        let q = "drop table if exists dep_edges";
-        try!(self.conn.execute(q, &[]));
+    /// Gives back a custom build reserved today that never ran
+    pub fn refund_custom_build(&self, user_name: &str) -> Result<(), Box<StdError>> {
+        let q = "update user_usage set custom_builds = custom_builds - 1 \
+                 where user_name = $1 and day = (now() at time zone 'utc')::date \
+                 and custom_builds > 0";
+        try!(self.conn.execute(q, &[&user_name]));
 
         Ok(())
     }
 
-    pub fn add_build_result(&self, build_result: &BuildResult) -> Result<(), Box<StdError>> {
-        let upsert_retry_limit = 10;
-        for _ in &[0 .. upsert_retry_limit] {
-            let update_q = "update build_results set status = $4, task_id = $5 where \
-                            toolchain = $1 and crate_name = $2 and crate_vers = $3";
-
-            let r = self.conn.execute(update_q, &[
-                &build_result.toolchain,
-                &build_result.crate_name,
-                &build_result.crate_vers,
-                &build_result.status,
-                &build_result.task_id]);
-            match r {
-                Ok(rows) if rows > 0 => return Ok(()),
-                Ok(_) => (/* pass */),
-                Err(err) => return Err(Box::new(err))
-            }
+    /// Counts the user's running experiments that still have
+    /// outstanding jobs
+    pub fn count_running_experiments(&self, owner: &str) -> Result<u64, Box<StdError>> {
+        let q = "select count(*) from experiments e \
+                 where e.owner = $1 and e.state = $2 and exists ( \
+                 select 1 from scheduled_jobs j where j.experiment = e.name \
+                 and (j.status is null or j.status = 'unknown') )";
+        let stmt = try!(self.conn.prepare(q));
+        for row in try!(stmt.query(&[&owner, &ExperimentState::Running.to_string()])) {
+            let count: i64 = row.get(0);
+            return Ok(count as u64);
+        }
+
+        Err(Box::from(Error::DbEmptyResultFailure))
+    }
 
-	    let insert_q = "insert into build_results values ($1, $2, $3, $4, $5)";
-            
-            let r = self.conn.execute(insert_q, &[
-                &build_result.toolchain,
-                &build_result.crate_name,
-                &build_result.crate_vers,
-                &build_result.status,
-                &build_result.task_id]);
-            if r.is_ok() { return Ok(()) }
+    /// A crate's place in the popularity ranking stored by
+    /// `sync_index`, starting at 1 for the most depended-on crate
+    pub fn get_rank(&self, crate_name: &str) -> Result<Option<u64>, Box<StdError>> {
+        let q = "select rank from crate_rank where name = $1";
+        let stmt = try!(self.conn.prepare(q));
+        for row in try!(stmt.query(&[&crate_name])) {
+            let rank: i32 = row.get(0);
+            return Ok(Some(rank as u64));
         }
 
-        Err(Box::from(Error::UpsertFailure))
+        Ok(None)
     }
 
-    pub fn get_build_result(&self, key: &BuildResultKey) -> Result<BuildResult, Box<StdError>> {
-        let q = "select * from build_results where \
-                 toolchain = $1 and crate_name = $2 and crate_vers = $3";
+    /// Every ranked crate name, most popular first
+    pub fn get_ranked_crates(&self) -> Result<Vec<String>, Box<StdError>> {
+        let q = "select name from crate_rank order by rank";
         let stmt = try!(self.conn.prepare(q));
-        for row in try!(stmt.query(&[&key.toolchain, &key.crate_name, &key.crate_vers])) {
-            return Ok(BuildResult {
-                toolchain: row.get(0),
-                crate_name: row.get(1),
-                crate_vers: row.get(2),
-                status: row.get(3),
-                task_id: row.get(4)
-            })
+        let mut names = Vec::new();
+        for row in try!(stmt.query(&[])) {
+            names.push(row.get(0));
         }
 
-        Err(Box::from(Error::DbEmptyResultFailure))
+        Ok(names)
     }
 }
 
 #[derive(Debug)]
 enum Error {
-    UpsertFailure,
-    DbEmptyResultFailure
+    DbEmptyResultFailure,
+    UnknownExperimentState,
+    UnknownDiagnosticKind
 }
 
 impl StdError for Error {
     fn description(&self) -> &str {
         match *self {
-            Error::UpsertFailure => "upsert failure",
             Error::DbEmptyResultFailure => "no results",
+            Error::UnknownExperimentState => "unknown experiment state",
+            Error::UnknownDiagnosticKind => "unknown diagnostic kind",
         }
     }
 }
@@ -187,6 +1100,97 @@ impl ::std::fmt::Display for Error {
     }
 }
 
+const UPSERT_BUILD_RESULTS: &'static str =
+    "on conflict (toolchain, crate_name, crate_vers) do update \
+     set status = excluded.status, task_id = excluded.task_id";
+
+/// As many rows as fit in one statement at 5 parameters each, under
+/// the Postgres limit of 65535, so a batch is usually one round trip
+const BUILD_RESULTS_PER_INSERT: usize = 13000;
+
+const SELECT_BUILD_RESULTS: &'static str =
+    "select toolchain, crate_name, crate_vers, status, task_id from build_results";
+
+/// Rows are read back with the toolchain they were looked up by, since
+/// custom toolchains are stored by sha alone
+fn build_result_from_row(toolchain: &Toolchain, row: &postgres::rows::Row
+                         ) -> Result<BuildResult, Box<StdError>> {
+    let status: String = row.get(3);
+    Ok(BuildResult {
+        toolchain: toolchain.clone(),
+        crate_name: row.get(1),
+        crate_vers: row.get(2),
+        status: try!(status.parse()),
+        task_id: row.get(4)
+    })
+}
+
+fn build_log_from_row(toolchain: &Toolchain, row: &postgres::rows::Row) -> BuildLog {
+    BuildLog {
+        toolchain: toolchain.clone(),
+        crate_name: row.get(1),
+        crate_vers: row.get(2),
+        task_id: row.get(3),
+        errors: row.get(4),
+        tail: row.get(5)
+    }
+}
+
+/// Logs and returns `None` for toolchains that don't parse
+fn parse_stored_toolchain(s: &str) -> Option<Toolchain> {
+    match s.parse() {
+        Ok(toolchain) => Some(toolchain),
+        Err(e) => {
+            warn!("skipping results for unknown toolchain: {}", e);
+            None
+        }
+    }
+}
+
+fn diagnostic_to_row(diagnostic: &Diagnostic) -> (&'static str, &str) {
+    match *diagnostic {
+        Diagnostic::ErrorCode(ref code) => ("error_code", &**code),
+        Diagnostic::Error(ref msg) => ("error", &**msg),
+        Diagnostic::Ice(ref msg) => ("ice", &**msg),
+        Diagnostic::Panic(ref msg) => ("panic", &**msg)
+    }
+}
+
+fn diagnostic_from_row(kind: String, message: String) -> Result<Diagnostic, Box<StdError>> {
+    match &*kind {
+        "error_code" => Ok(Diagnostic::ErrorCode(message)),
+        "error" => Ok(Diagnostic::Error(message)),
+        "ice" => Ok(Diagnostic::Ice(message)),
+        "panic" => Ok(Diagnostic::Panic(message)),
+        _ => Err(Box::from(Error::UnknownDiagnosticKind))
+    }
+}
+
+const SELECT_EXPERIMENTS: &'static str =
+    "select name, owner, toolchain_from, toolchain_to, crate_selection, \
+     extract(epoch from created_at)::bigint, state from experiments";
+
+fn experiment_from_row(row: &postgres::rows::Row) -> Result<Experiment, Box<StdError>> {
+    let toolchain_from: String = row.get(2);
+    let toolchain_to: String = row.get(3);
+    let crate_selection: String = row.get(4);
+    let state: String = row.get(6);
+
+    Ok(Experiment {
+        name: row.get(0),
+        owner: row.get(1),
+        toolchain_from: try!(toolchain_from.parse()),
+        toolchain_to: try!(toolchain_to.parse()),
+        crate_selection: try!(json::decode(&crate_selection)),
+        created_at: row.get(5),
+        state: match &*state {
+            "created" => ExperimentState::Created,
+            "running" => ExperimentState::Running,
+            _ => return Err(Box::from(Error::UnknownExperimentState))
+        }
+    })
+}
+
 fn make_url(dbname: &str, username: &str, password: &str, host: &str, port: u16) -> String {
     format!("postgres://{}:{}@{}:{}/{}", username, password, host, port, dbname)
 }
@@ -195,19 +1199,27 @@ fn make_url(dbname: &str, username: &str, password: &str, host: &str, port: u16)
 #[cfg(test)]
 mod test {
     use super::*;
+    use api::{BuildStatus, Diagnostic, Role, Toolchain};
+    use api::v1::{CrateSelection, ExperimentState};
+    use index::{CrateVersion, Dependency, Index};
+    use std::collections::BTreeMap;
+    use std::u64;
     use std::sync::{StaticMutex, MUTEX_INIT};
 
     static LOCK: StaticMutex = MUTEX_INIT;
     
-    fn connect() -> Database {
-        let credentials = Config {
+    fn config() -> Config {
+        Config {
             dbname: "crater-test".to_string(),
             username: "crater-test".to_string(),
             password: "crater-test".to_string(),
             host: "localhost".to_string(),
             port: 5432
-        };
-        Database::connect(&credentials).unwrap()
+        }
+    }
+
+    fn connect() -> Database {
+        Database::connect(&config()).unwrap()
     }
 
     fn dbtest(f: &Fn()) {
@@ -223,14 +1235,67 @@ mod test {
         })
     }
 
+    #[test]
+    fn migrate_new_db() {
+        dbtest(&|| {
+            let db = connect();
+            let latest = MIGRATIONS.last().unwrap().version;
+            assert_eq!(db.schema_version().unwrap(), latest);
+            assert!(db.pending_migrations().unwrap().is_empty());
+            assert!(db.migrate().unwrap().is_empty());
+        })
+    }
+
+    #[test]
+    fn migrate_db_without_schema_version() {
+        dbtest(&|| {
+            // Databases from before migrations have the original tables
+            // but no record of them, and whatever statuses monitor.js
+            // wrote
+            let db = Database::connect_without_migrating(&config()).unwrap();
+            for q in MIGRATIONS[0].sql {
+                db.conn.execute(q, &[]).unwrap();
+            }
+            let q = "insert into build_results values \
+                     ('nightly-2015-01-01', 'num', '1.0.0', ' Success', 't1'), \
+                     ('nightly-2015-01-01', 'toml', '1.0.0', 'bogus', 't2')";
+            db.conn.execute(q, &[]).unwrap();
+
+            assert_eq!(db.schema_version().unwrap(), 0);
+            assert_eq!(db.pending_migrations().unwrap().len(), MIGRATIONS.len());
+            // Checking is read-only
+            assert!(!db.has_schema_version_table().unwrap());
+            assert_eq!(db.migrate().unwrap().len(), MIGRATIONS.len());
+            assert!(db.pending_migrations().unwrap().is_empty());
+
+            let status = |name: &str| db.get_build_result(&BuildResultKey {
+                toolchain: "nightly-2015-01-01".parse().unwrap(),
+                crate_name: String::from(name),
+                crate_vers: String::from("1.0.0")
+            }).unwrap().status;
+            assert_eq!(status("num"), BuildStatus::Success);
+            assert_eq!(status("toml"), BuildStatus::Unknown);
+        })
+    }
+
+    #[test]
+    fn reject_unknown_statuses() {
+        dbtest(&|| {
+            let db = connect();
+            let q = "insert into build_results values \
+                     ('nightly-2015-01-01', 'num', '1.0.0', 'sucess', 't1')";
+            assert!(db.conn.execute(q, &[]).is_err());
+        })
+    }
+
     #[test]
     fn add_result_once() {
         dbtest(&|| {
             let expected = BuildResult {
-                toolchain: String::from("nightly-2015-01-01"),
+                toolchain: "nightly-2015-01-01".parse().unwrap(),
                 crate_name: String::from("num"),
                 crate_vers: String::from("1.0.0"),
-                status: String::from("success"),
+                status: BuildStatus::Success,
                 task_id: String::from("my-task-id")
             };
             let db = connect();
@@ -250,20 +1315,20 @@ mod test {
     fn add_result_twice() {
         dbtest(&|| {
             let expected = BuildResult {
-                toolchain: String::from("nightly-2015-01-01"),
+                toolchain: "nightly-2015-01-01".parse().unwrap(),
                 crate_name: String::from("num"),
                 crate_vers: String::from("1.0.0"),
-                status: String::from("success"),
+                status: BuildStatus::Success,
                 task_id: String::from("my-task-id")
             };
             let db = connect();
             assert!(db.add_build_result(&expected).is_ok());
 
             let expected = BuildResult {
-                toolchain: String::from("nightly-2015-01-01"),
+                toolchain: "nightly-2015-01-01".parse().unwrap(),
                 crate_name: String::from("num"),
                 crate_vers: String::from("1.0.0"),
-                status: String::from("failure"),
+                status: BuildStatus::Failure,
                 task_id: String::from("my-task-id-2")
             };
 
@@ -279,4 +1344,588 @@ mod test {
             assert_eq!(expected, actual);
         })
     }
+
+    #[test]
+    fn add_results_in_bulk() {
+        dbtest(&|| {
+            let result = |name: &str, status: &str| BuildResult {
+                toolchain: "nightly-2015-01-01".parse().unwrap(),
+                crate_name: String::from(name),
+                crate_vers: String::from("1.0.0"),
+                status: status.parse().unwrap(),
+                task_id: String::from("my-task-id")
+            };
+            // Several statements' worth at 100 rows each, plus a repeat
+            // of the first crate that ends up in a later chunk
+            let mut results: Vec<BuildResult> = (0..250)
+                .map(|i| result(&format!("crate-{}", i), "success"))
+                .collect();
+            results.push(result("crate-0", "failure"));
+
+            let db = connect();
+            db.add_build_result(&result("crate-1", "exception")).unwrap();
+            db.add_build_results_in_chunks(&results, 100).unwrap();
+
+            let get = |name: &str| db.get_build_result(&BuildResultKey {
+                toolchain: "nightly-2015-01-01".parse().unwrap(),
+                crate_name: String::from(name),
+                crate_vers: String::from("1.0.0")
+            }).unwrap();
+            assert_eq!(get("crate-0").status, BuildStatus::Failure);
+            assert_eq!(get("crate-1").status, BuildStatus::Success);
+            assert_eq!(get("crate-249").status, BuildStatus::Success);
+            let toolchain = "nightly-2015-01-01".parse().unwrap();
+            assert_eq!(db.get_results(&toolchain, Page::all()).unwrap().len(), 250);
+        })
+    }
+
+    #[test]
+    fn query_results() {
+        dbtest(&|| {
+            let result = |toolchain: &str, name: &str, vers: &str, status: &str| BuildResult {
+                toolchain: toolchain.parse().unwrap(),
+                crate_name: String::from(name),
+                crate_vers: String::from(vers),
+                status: status.parse().unwrap(),
+                task_id: format!("{}-{}-{}", toolchain, name, vers)
+            };
+            let from = "nightly-2015-01-01";
+            let to = "nightly-2015-01-02";
+            let results = vec![
+                result(from, "num", "1.0.0", "success"),
+                result(from, "num", "1.1.0", "success"),
+                result(from, "toml", "0.1.0", "failure"),
+                result(to, "num", "1.1.0", "failure"),
+                result(to, "toml", "0.1.0", "failure"),
+                result(to, "rand", "0.3.0", "success"),
+            ];
+            let db = connect();
+            db.add_build_results(&results).unwrap();
+
+            let from_tc: Toolchain = from.parse().unwrap();
+            let to_tc: Toolchain = to.parse().unwrap();
+
+            assert_eq!(db.list_toolchains().unwrap(), vec![from_tc.clone(), to_tc.clone()]);
+
+            assert_eq!(db.get_results(&from_tc, Page::all()).unwrap(),
+                       vec![result(from, "num", "1.0.0", "success"),
+                            result(from, "num", "1.1.0", "success"),
+                            result(from, "toml", "0.1.0", "failure")]);
+            assert_eq!(db.get_results(&from_tc, Page::new(1, 1)).unwrap(),
+                       vec![result(from, "num", "1.1.0", "success")]);
+
+            let pairs = db.get_result_pairs(&from_tc, &to_tc, Page::all()).unwrap();
+            assert_eq!(pairs, vec![
+                BuildResultPair { from: result(from, "num", "1.1.0", "success"),
+                                  to: result(to, "num", "1.1.0", "failure") },
+                BuildResultPair { from: result(from, "toml", "0.1.0", "failure"),
+                                  to: result(to, "toml", "0.1.0", "failure") },
+            ]);
+            assert_eq!(db.get_result_pairs(&from_tc, &to_tc, Page::new(1, 10)).unwrap().len(), 1);
+
+            assert_eq!(db.get_results_for_crate("num", Page::all()).unwrap(),
+                       vec![result(from, "num", "1.0.0", "success"),
+                            result(from, "num", "1.1.0", "success"),
+                            result(to, "num", "1.1.0", "failure")]);
+            assert!(db.get_results_for_crate("log", Page::all()).unwrap().is_empty());
+        })
+    }
+
+    #[test]
+    fn add_build_log() {
+        dbtest(&|| {
+            let key = BuildResultKey {
+                toolchain: "nightly-2015-01-01".parse().unwrap(),
+                crate_name: String::from("num"),
+                crate_vers: String::from("1.0.0")
+            };
+            let log = |task_id: &str, errors: &str| BuildLog {
+                toolchain: key.toolchain.clone(),
+                crate_name: key.crate_name.clone(),
+                crate_vers: key.crate_vers.clone(),
+                task_id: String::from(task_id),
+                errors: String::from(errors),
+                tail: String::from("error: aborting due to previous error")
+            };
+            let db = connect();
+            assert_eq!(db.get_build_log(&key).unwrap(), None);
+
+            db.add_build_log(&log("t1", "error: unresolved import")).unwrap();
+            db.add_build_log(&log("t2", "error[E0277]: the trait bound is not satisfied")).unwrap();
+            assert_eq!(db.get_build_log(&key).unwrap(),
+                       Some(log("t2", "error[E0277]: the trait bound is not satisfied")));
+
+            assert!(db.delete_build_log(&key).unwrap());
+            assert_eq!(db.get_build_log(&key).unwrap(), None);
+            assert!(!db.delete_build_log(&key).unwrap());
+        })
+    }
+
+    #[test]
+    fn search_build_logs() {
+        dbtest(&|| {
+            let toolchain: Toolchain = "nightly-2015-01-01".parse().unwrap();
+            let log = |name: &str, tail: &str| BuildLog {
+                toolchain: toolchain.clone(),
+                crate_name: String::from(name),
+                crate_vers: String::from("1.0.0"),
+                task_id: format!("task-{}", name),
+                errors: String::new(),
+                tail: String::from(tail)
+            };
+            let db = connect();
+            let index = Index::from_versions(vec![
+                crate_version("num", "0.1.0", &["rand"]),
+                crate_version("rand", "0.3.0", &[]),
+            ]);
+            db.sync_index(&index).unwrap();
+            db.add_build_log(&log("num", "thread 'rustc' panicked at 'oops'")).unwrap();
+            db.add_build_log(&log("rand", "thread 'rustc' panicked at 'oops'")).unwrap();
+            db.add_build_log(&log("toml", "thread 'rustc' panicked at 'oops'")).unwrap();
+            db.add_build_log(&log("time", "error: unresolved import")).unwrap();
+
+            let found: Vec<String> = db.search_build_logs(&toolchain, "thread 'rustc' panicked")
+                .unwrap().into_iter().map(|l| l.crate_name).collect();
+            assert_eq!(found, vec!["rand", "num", "toml"]);
+        })
+    }
+
+    #[test]
+    fn set_diagnostics() {
+        dbtest(&|| {
+            let key = |name: &str| BuildResultKey {
+                toolchain: "nightly-2015-01-01".parse().unwrap(),
+                crate_name: String::from(name),
+                crate_vers: String::from("1.0.0")
+            };
+            let db = connect();
+            assert!(db.get_diagnostics(&key("num")).unwrap().is_empty());
+
+            let diagnostics = vec![
+                Diagnostic::Error(String::from("unresolved import `foo`")),
+                Diagnostic::ErrorCode(String::from("E0277")),
+                Diagnostic::Ice(String::from("no type for node 12"))
+            ];
+            db.set_diagnostics(&key("num"), &[Diagnostic::Panic(String::from("oops"))]).unwrap();
+            db.set_diagnostics(&key("num"), &diagnostics).unwrap();
+            db.set_diagnostics(&key("toml"), &diagnostics[1..2]).unwrap();
+            assert_eq!(db.get_diagnostics(&key("num")).unwrap(), diagnostics);
+
+            let all = db.get_toolchain_diagnostics(&key("num").toolchain).unwrap();
+            assert_eq!(all.len(), 2);
+            assert_eq!(all[&(String::from("toml"), String::from("1.0.0"))], diagnostics[1..2].to_vec());
+        })
+    }
+
+    #[test]
+    fn add_custom_toolchain() {
+        dbtest(&|| {
+            let toolchain: Toolchain = "0123456789abcdef0123456789abcdef01234567".parse().unwrap();
+            let db = connect();
+            assert_eq!(db.get_custom_toolchain(&toolchain).unwrap(), None);
+
+            for &(status, task_id) in &[("failure", "task-1"), ("success", "task-2")] {
+                db.add_custom_toolchain(&CustomToolchain {
+                    toolchain: toolchain.clone(),
+                    status: status.parse().unwrap(),
+                    task_id: String::from(task_id)
+                }).unwrap();
+            }
+
+            let actual = db.get_custom_toolchain(&toolchain).unwrap().unwrap();
+            assert_eq!(actual.status, BuildStatus::Success);
+            assert_eq!(actual.task_id, "task-2");
+        })
+    }
+
+    #[test]
+    fn custom_toolchains_keyed_by_sha() {
+        dbtest(&|| {
+            let sha = "0123456789abcdef0123456789abcdef01234567";
+            let with_repo: Toolchain = format!("https://github.com/rust-lang/rust#{}", sha)
+                .parse().unwrap();
+            let db = connect();
+            db.add_build_result(&BuildResult {
+                toolchain: with_repo.clone(),
+                crate_name: String::from("num"),
+                crate_vers: String::from("1.0.0"),
+                status: BuildStatus::Success,
+                task_id: String::from("t1")
+            }).unwrap();
+
+            let key = BuildResultKey {
+                toolchain: sha.parse().unwrap(),
+                crate_name: String::from("num"),
+                crate_vers: String::from("1.0.0")
+            };
+            assert_eq!(db.get_build_result(&key).unwrap().task_id, "t1");
+
+            // As written by some old monitor.js
+            db.conn.execute("insert into build_results values ('nightly', 'num', '1.0.0', \
+                             'success', 't0')", &[]).unwrap();
+            assert_eq!(db.list_toolchains().unwrap(), vec![key.toolchain.clone()]);
+            assert_eq!(db.get_results_for_crate("num", Page::all()).unwrap().len(), 1);
+        })
+    }
+
+    #[test]
+    fn migrate_custom_toolchains_from_several_repos() {
+        dbtest(&|| {
+            // Before migration 10 the same sha could be built from
+            // more than one repo
+            let db = Database::connect_without_migrating(&config()).unwrap();
+            db.create_schema_version_table().unwrap();
+            for m in MIGRATIONS.iter().filter(|m| m.version < 10) {
+                for q in m.sql {
+                    db.conn.execute(q, &[]).unwrap();
+                }
+                db.conn.execute("insert into schema_version (version, name) values ($1, $2)",
+                                &[&m.version, &m.name]).unwrap();
+            }
+            let sha = "0123456789abcdef0123456789abcdef01234567";
+            let a = format!("https://github.com/a/rust#{}", sha);
+            let b = format!("https://github.com/b/rust#{}", sha);
+            let q = "insert into build_results values \
+                     ($1, 'num', '1.0.0', 'success', 't1'), ($2, 'num', '1.0.0', 'failure', 't2')";
+            db.conn.execute(q, &[&a, &b]).unwrap();
+            let q = "insert into custom_toolchains values \
+                     ($1, 'success', 'c1'), ($2, 'success', 'c2')";
+            db.conn.execute(q, &[&a, &b]).unwrap();
+
+            db.migrate().unwrap();
+
+            let toolchain: Toolchain = sha.parse().unwrap();
+            assert_eq!(db.get_build_result(&BuildResultKey {
+                toolchain: toolchain.clone(),
+                crate_name: String::from("num"),
+                crate_vers: String::from("1.0.0")
+            }).unwrap().task_id, "t1");
+            assert_eq!(db.get_results(&toolchain, Page::all()).unwrap().len(), 1);
+            assert_eq!(db.get_custom_toolchain(&toolchain).unwrap().unwrap().task_id, "c1");
+        })
+    }
+
+    fn new_experiment(name: &str) -> NewExperiment {
+        NewExperiment {
+            name: String::from(name),
+            owner: String::from("brson"),
+            toolchain_from: "nightly-2015-01-01".parse().unwrap(),
+            toolchain_to: "nightly-2015-01-02".parse().unwrap(),
+            crate_selection: CrateSelection {
+                top: Some(100),
+                most_recent_only: true,
+                name: None,
+                skip_existing: false
+            }
+        }
+    }
+
+    #[test]
+    fn experiments() {
+        dbtest(&|| {
+            let db = connect();
+            assert!(db.add_experiment(&new_experiment("a")).unwrap());
+            assert!(db.add_experiment(&new_experiment("b")).unwrap());
+            assert!(!db.add_experiment(&new_experiment("a")).unwrap());
+
+            let a = db.get_experiment("a").unwrap().unwrap();
+            assert_eq!(a.owner, "brson");
+            assert_eq!(a.toolchain_to, "nightly-2015-01-02".parse().unwrap());
+            assert_eq!(a.crate_selection.top, Some(100));
+            assert_eq!(a.state, ExperimentState::Created);
+            assert!(a.created_at > 0);
+
+            db.set_experiment_state("a", ExperimentState::Running).unwrap();
+            assert_eq!(db.get_experiment("a").unwrap().unwrap().state, ExperimentState::Running);
+            assert!(db.set_experiment_state("c", ExperimentState::Running).is_err());
+
+            let (created, running) = (ExperimentState::Created, ExperimentState::Running);
+            assert!(db.transition_experiment("b", created, running).unwrap());
+            assert!(!db.transition_experiment("b", created, running).unwrap());
+            assert!(!db.transition_experiment("c", created, running).unwrap());
+            assert_eq!(db.get_experiment("b").unwrap().unwrap().state, running);
+
+            assert_eq!(db.list_experiments().unwrap().len(), 2);
+            assert!(db.delete_experiment("a").unwrap());
+            assert!(!db.delete_experiment("a").unwrap());
+            assert_eq!(db.get_experiment("a").unwrap(), None);
+            assert_eq!(db.list_experiments().unwrap()[0].name, "b");
+        })
+    }
+
+    fn scheduled_job(crate_name: &str, task_id: &str) -> ScheduledJob {
+        ScheduledJob {
+            experiment: String::from("a"),
+            toolchain: "nightly-2015-01-01".parse().unwrap(),
+            crate_name: String::from(crate_name),
+            crate_vers: String::from("1.0.0"),
+            task_id: String::from(task_id)
+        }
+    }
+
+    fn result(crate_name: &str, task_id: &str, status: &str) -> BuildResult {
+        BuildResult {
+            toolchain: "nightly-2015-01-01".parse().unwrap(),
+            crate_name: String::from(crate_name),
+            crate_vers: String::from("1.0.0"),
+            status: status.parse().unwrap(),
+            task_id: String::from(task_id)
+        }
+    }
+
+    #[test]
+    fn experiment_progress() {
+        dbtest(&|| {
+            let db = connect();
+            assert!(db.add_experiment(&new_experiment("a")).unwrap());
+            db.add_scheduled_jobs(&[scheduled_job("num", "t1"),
+                                    scheduled_job("toml", "t2"),
+                                    scheduled_job("time", "t3"),
+                                    scheduled_job("log", "t4")]).unwrap();
+
+            assert!(db.set_job_status("t1", BuildStatus::Success).unwrap());
+            assert!(db.set_job_status("t2", BuildStatus::Failure).unwrap());
+            assert!(db.set_job_status("t3", BuildStatus::Exception).unwrap());
+            // From some earlier task
+            assert!(!db.set_job_status("t0", BuildStatus::Success).unwrap());
+
+            assert_eq!(db.get_experiment_progress("a").unwrap(), ExperimentProgress {
+                scheduled: 4,
+                finished: 2,
+                exception: 1,
+                outstanding: 1
+            });
+
+            // A later build of the same crate replaces the result, but
+            // not the job's status
+            db.add_build_result(&result("num", "t1", "success")).unwrap();
+            db.add_build_result(&result("num", "t5", "failure")).unwrap();
+            assert_eq!(db.get_experiment_progress("a").unwrap().finished, 2);
+
+            assert!(db.delete_experiment("a").unwrap());
+            assert_eq!(db.get_experiment_progress("a").unwrap().scheduled, 0);
+        })
+    }
+
+    #[test]
+    fn count_running_experiments() {
+        dbtest(&|| {
+            let db = connect();
+            assert!(db.add_experiment(&new_experiment("a")).unwrap());
+            assert!(db.add_experiment(&new_experiment("b")).unwrap());
+            db.add_scheduled_jobs(&[scheduled_job("num", "t1"),
+                                    scheduled_job("toml", "t2")]).unwrap();
+            // Still created, so not counted
+            assert_eq!(db.count_running_experiments("brson").unwrap(), 0);
+
+            db.set_experiment_state("a", ExperimentState::Running).unwrap();
+            db.set_experiment_state("b", ExperimentState::Running).unwrap();
+            // b has no outstanding jobs
+            assert_eq!(db.count_running_experiments("brson").unwrap(), 1);
+            assert_eq!(db.count_running_experiments("alexcrichton").unwrap(), 0);
+
+            db.set_job_status("t1", BuildStatus::Success).unwrap();
+            assert_eq!(db.count_running_experiments("brson").unwrap(), 1);
+            db.set_job_status("t2", BuildStatus::Exception).unwrap();
+            assert_eq!(db.count_running_experiments("brson").unwrap(), 0);
+        })
+    }
+
+    #[test]
+    fn add_dep_edges_twice() {
+        dbtest(&|| {
+            let edges = vec![
+                DepEdge { name: String::from("piston"), dep: String::from("pistoncore-input") },
+                DepEdge { name: String::from("num"), dep: String::from("rand") },
+                DepEdge { name: String::from("num"), dep: String::from("rustc-serialize") }
+            ];
+            let db = connect();
+            assert_eq!(db.add_dep_edges(&edges).unwrap(), 3);
+            assert_eq!(db.add_dep_edges(&edges[1..]).unwrap(), 0);
+
+            let actual = db.get_dep_edges().unwrap();
+            let expected = vec![
+                DepEdge { name: String::from("num"), dep: String::from("rand") },
+                DepEdge { name: String::from("num"), dep: String::from("rustc-serialize") },
+                DepEdge { name: String::from("piston"), dep: String::from("pistoncore-input") }
+            ];
+
+            assert_eq!(expected, actual);
+        })
+    }
+
+    fn crate_version(name: &str, vers: &str, deps: &[&str]) -> CrateVersion {
+        CrateVersion {
+            name: String::from(name),
+            vers: String::from(vers),
+            deps: deps.iter().map(|&dep| Dependency {
+                name: String::from(dep),
+                req: String::from("*"),
+                features: Vec::new(),
+                optional: false,
+                default_features: true,
+                target: None,
+                kind: None
+            }).collect(),
+            features: BTreeMap::new(),
+            yanked: false
+        }
+    }
+
+    #[test]
+    fn sync_index() {
+        dbtest(&|| {
+            let index = Index::from_versions(vec![
+                crate_version("num", "0.1.0", &["rand"]),
+                crate_version("num", "0.1.1", &["rand", "rustc-serialize"]),
+                crate_version("num", "0.1.1", &["rand", "rustc-serialize"]),
+                crate_version("rand", "0.3.0", &[]),
+                crate_version("rustc-serialize", "0.3.0", &[])
+            ]);
+            let db = connect();
+            db.sync_index(&index).unwrap();
+            let stats = db.sync_index(&index).unwrap();
+
+            assert_eq!(stats.crate_versions, 4);
+            assert_eq!(stats.dep_edges, 2);
+            assert_eq!(stats.ranked_crates, 3);
+
+            let expected = vec![
+                DepEdge { name: String::from("num"), dep: String::from("rand") },
+                DepEdge { name: String::from("num"), dep: String::from("rustc-serialize") }
+            ];
+            assert_eq!(expected, db.get_dep_edges().unwrap());
+        })
+    }
+
+    #[test]
+    fn rank_by_transitive_dependents() {
+        dbtest(&|| {
+            let index = Index::from_versions(vec![
+                crate_version("app", "0.1.0", &["num"]),
+                crate_version("num", "0.1.0", &["rand"]),
+                crate_version("rand", "0.3.0", &[]),
+                crate_version("log", "0.3.0", &[])
+            ]);
+            let db = connect();
+            db.sync_index(&index).unwrap();
+
+            assert_eq!(db.get_rank("rand").unwrap(), Some(1));
+            assert_eq!(db.get_rank("num").unwrap(), Some(2));
+            assert_eq!(db.get_rank("log").unwrap(), Some(4));
+            assert_eq!(db.get_rank("nope").unwrap(), None);
+            assert_eq!(db.get_ranked_crates().unwrap(),
+                       vec!["rand", "num", "app", "log"]);
+
+            let t1: Toolchain = "nightly-2015-01-01".parse().unwrap();
+            let t2: Toolchain = "nightly-2015-01-02".parse().unwrap();
+            for &(name, vers) in &[("app", "0.1.0"), ("log", "0.3.0"),
+                                   ("rand", "0.3.0"), ("zzz", "1.0.0")] {
+                for t in &[&t1, &t2] {
+                    db.add_build_result(&BuildResult {
+                        toolchain: (*t).clone(),
+                        crate_name: String::from(name),
+                        crate_vers: String::from(vers),
+                        status: BuildStatus::Success,
+                        task_id: String::from("id")
+                    }).unwrap();
+                }
+            }
+            let names: Vec<String> = db.get_result_pairs(&t1, &t2, Page::all()).unwrap()
+                .into_iter().map(|p| p.from.crate_name).collect();
+            assert_eq!(names, vec!["rand", "app", "log", "zzz"]);
+        })
+    }
+
+    #[test]
+    fn user_tokens() {
+        dbtest(&|| {
+            let db = connect();
+            let token = db.add_user("brson", Role::Admin).unwrap().unwrap();
+            assert_eq!(db.add_user("brson", Role::Reporter).unwrap(), None);
+
+            let brson = User { name: String::from("brson"), role: Role::Admin };
+            assert_eq!(db.authenticate("brson", &token).unwrap(), Some(brson));
+            assert_eq!(db.authenticate("brson", "bogus").unwrap(), None);
+            assert_eq!(db.authenticate("nobody", &token).unwrap(), None);
+
+            let rotated = db.rotate_token("brson").unwrap().unwrap();
+            assert_eq!(db.authenticate("brson", &token).unwrap(), None);
+            assert!(db.authenticate("brson", &rotated).unwrap().is_some());
+            assert_eq!(db.rotate_token("nobody").unwrap(), None);
+
+            assert!(db.revoke_user("brson").unwrap());
+            assert!(!db.revoke_user("brson").unwrap());
+            assert_eq!(db.authenticate("brson", &rotated).unwrap(), None);
+            assert_eq!(db.get_user("brson").unwrap(), None);
+            assert_eq!(db.rotate_token("brson").unwrap(), None);
+
+            // Revoked names can be reused
+            let token = db.add_user("brson", Role::Reporter).unwrap().unwrap();
+            assert_eq!(db.authenticate("brson", &token).unwrap().unwrap().role, Role::Reporter);
+        })
+    }
+
+    #[test]
+    fn page_audit_log() {
+        dbtest(&|| {
+            let db = connect();
+            for i in 0..5 {
+                db.add_audit_entry(&NewAuditEntry {
+                    user: String::from("brson"),
+                    endpoint: String::from("crate_build"),
+                    params: format!("{{\"top\":{}}}", i),
+                    result: format!("experiment e{}", i)
+                }).unwrap();
+            }
+
+            let results = |page: Page| -> Vec<String> {
+                db.get_audit_log(page).unwrap().into_iter().map(|e| e.result).collect()
+            };
+            assert_eq!(results(Page::new(0, 2)), vec!["experiment e4", "experiment e3"]);
+            assert_eq!(results(Page::new(4, 2)), vec!["experiment e0"]);
+            assert_eq!(results(Page::all()).len(), 5);
+            assert!(results(Page::new(u64::MAX, u64::MAX)).is_empty());
+
+            let entry = db.get_audit_log(Page::new(0, 1)).unwrap().pop().unwrap();
+            assert_eq!(entry.user, "brson");
+            assert_eq!(entry.params, "{\"top\":4}");
+            assert!(entry.created_at > 0);
+        })
+    }
+
+    #[test]
+    fn usage_counters() {
+        dbtest(&|| {
+            let db = connect();
+            assert_eq!(db.get_usage("brson").unwrap(), Usage::default());
+
+            db.add_usage("brson", &Usage { tasks: 100, custom_builds: 0 }).unwrap();
+            db.add_usage("brson", &Usage { tasks: 20, custom_builds: 1 }).unwrap();
+            db.add_usage("alexcrichton", &Usage { tasks: 5, custom_builds: 0 }).unwrap();
+
+            assert_eq!(db.get_usage("brson").unwrap(), Usage { tasks: 120, custom_builds: 1 });
+            assert_eq!(db.get_usage("alexcrichton").unwrap().tasks, 5);
+        })
+    }
+
+    #[test]
+    fn reserve_custom_builds() {
+        dbtest(&|| {
+            let db = connect();
+            assert!(!db.reserve_custom_build("brson", Some(0)).unwrap());
+            assert!(db.reserve_custom_build("brson", Some(2)).unwrap());
+            assert!(db.reserve_custom_build("brson", Some(2)).unwrap());
+            assert!(!db.reserve_custom_build("brson", Some(2)).unwrap());
+            assert_eq!(db.get_usage("brson").unwrap().custom_builds, 2);
+
+            db.refund_custom_build("brson").unwrap();
+            assert!(db.reserve_custom_build("brson", Some(2)).unwrap());
+            assert!(db.reserve_custom_build("brson", None).unwrap());
+            assert_eq!(db.get_usage("brson").unwrap().custom_builds, 3);
+
+            db.refund_custom_build("alexcrichton").unwrap();
+            assert_eq!(db.get_usage("alexcrichton").unwrap(), Usage::default());
+        })
+    }
 }
@@ -0,0 +1,251 @@
+//! The database schema, as an ordered list of migrations. Migrations
+//! are only ever appended; once one has been applied anywhere it must
+//! not change.
+//!
+//! The first few use `if not exists` because they predate
+//! `schema_version` and may already have been applied by hand.
+
+pub struct Migration {
+    /// Starts at 1 and counts up
+    pub version: i32,
+    pub name: &'static str,
+    /// Statements applied in order
+    pub sql: &'static [&'static str]
+}
+
+pub const MIGRATIONS: &'static [Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial schema",
+        sql: &[
+            "create table if not exists \
+             build_results ( \
+             toolchain text not null, \
+             crate_name text not null, crate_vers text not null, \
+             status text not null, \
+             task_id text not null, \
+             primary key ( \
+             toolchain, crate_name, crate_vers ) )",
+
+            "create table if not exists \
+             custom_toolchains ( \
+             toolchain text not null, \
+             status text not null, \
+             task_id text not null, \
+             primary key (toolchain) )",
+
+            "create table if not exists \
+             crate_versions ( \
+             name text not null, \
+             version text not null, \
+             primary key (name, version) )",
+
+            "create table if not exists \
+             crate_rank ( \
+             name text not null, \
+             rank integer not null, \
+             primary key (name) )",
+
+            "create table if not exists \
+             dep_edges ( \
+             name text not null, \
+             dep text not null, \
+             primary key (name, dep) )"
+        ]
+    },
+    Migration {
+        version: 2,
+        name: "add experiments",
+        sql: &[
+            "create table if not exists \
+             experiments ( \
+             name text not null, \
+             owner text not null, \
+             toolchain_from text not null, \
+             toolchain_to text not null, \
+             crate_selection text not null, \
+             created_at timestamp with time zone not null default now(), \
+             state text not null, \
+             primary key (name) )"
+        ]
+    },
+    Migration {
+        version: 3,
+        name: "add scheduled jobs",
+        sql: &[
+            "create table if not exists \
+             scheduled_jobs ( \
+             experiment text not null, \
+             toolchain text not null, \
+             crate_name text not null, crate_vers text not null, \
+             task_id text not null, \
+             scheduled_at timestamp with time zone not null default now(), \
+             primary key (task_id) )"
+        ]
+    },
+    Migration {
+        version: 4,
+        name: "restrict build statuses",
+        sql: &[
+            "update build_results set status = lower(trim(status))",
+            "update build_results set status = 'unknown' \
+             where status not in ('success', 'failure', 'exception', 'unknown')",
+            "alter table build_results add constraint build_results_status_check \
+             check (status in ('success', 'failure', 'exception', 'unknown'))",
+
+            "update custom_toolchains set status = lower(trim(status))",
+            "update custom_toolchains set status = 'unknown' \
+             where status not in ('success', 'failure', 'exception', 'unknown')",
+            "alter table custom_toolchains add constraint custom_toolchains_status_check \
+             check (status in ('success', 'failure', 'exception', 'unknown'))"
+        ]
+    },
+    Migration {
+        version: 5,
+        name: "add build logs",
+        sql: &[
+            "create table build_logs ( \
+             toolchain text not null, \
+             crate_name text not null, crate_vers text not null, \
+             task_id text not null, \
+             errors text not null, \
+             tail text not null, \
+             primary key ( \
+             toolchain, crate_name, crate_vers ) )"
+        ]
+    },
+    Migration {
+        version: 6,
+        name: "add build diagnostics",
+        sql: &[
+            "create table build_diagnostics ( \
+             toolchain text not null, \
+             crate_name text not null, crate_vers text not null, \
+             position integer not null, \
+             kind text not null \
+             check (kind in ('error_code', 'error', 'ice', 'panic')), \
+             message text not null, \
+             primary key ( \
+             toolchain, crate_name, crate_vers, position ) )"
+        ]
+    },
+    Migration {
+        version: 7,
+        name: "add users",
+        sql: &[
+            "create table users ( \
+             name text not null, \
+             role text not null \
+             check (role in ('reporter', 'scheduler', 'admin')), \
+             salt text not null, \
+             token_hash text not null, \
+             created_at timestamp with time zone not null default now(), \
+             revoked_at timestamp with time zone, \
+             primary key (name) )"
+        ]
+    },
+    Migration {
+        version: 8,
+        name: "add audit log",
+        sql: &[
+            "create table audit_log ( \
+             id bigserial not null, \
+             user_name text not null, \
+             endpoint text not null, \
+             params text not null, \
+             result text not null, \
+             created_at timestamp with time zone not null default now(), \
+             primary key (id) )"
+        ]
+    },
+    Migration {
+        version: 9,
+        name: "add usage counters",
+        sql: &[
+            "create table user_usage ( \
+             user_name text not null, \
+             day date not null, \
+             tasks bigint not null default 0, \
+             custom_builds bigint not null default 0, \
+             primary key (user_name, day) )"
+        ]
+    },
+    // Custom toolchains are keyed by sha alone, like monitor.js does.
+    // Where both forms exist the sha-only row wins, and of several
+    // repos with the same sha the first by name.
+    Migration {
+        version: 10,
+        name: "key custom toolchains by sha",
+        sql: &[
+            "delete from build_results a using build_results b \
+             where a.toolchain ~ '#[0-9a-f]{40}$' \
+             and b.toolchain = substring(a.toolchain from '#([0-9a-f]{40})$') \
+             and a.crate_name = b.crate_name and a.crate_vers = b.crate_vers",
+
+            "delete from build_results a using build_results b \
+             where a.toolchain ~ '#[0-9a-f]{40}$' and b.toolchain ~ '#[0-9a-f]{40}$' \
+             and substring(a.toolchain from '#([0-9a-f]{40})$') = \
+             substring(b.toolchain from '#([0-9a-f]{40})$') \
+             and a.toolchain > b.toolchain \
+             and a.crate_name = b.crate_name and a.crate_vers = b.crate_vers",
+
+            "update build_results set toolchain = substring(toolchain from '#([0-9a-f]{40})$') \
+             where toolchain ~ '#[0-9a-f]{40}$'",
+
+            "delete from build_logs a using build_logs b \
+             where a.toolchain ~ '#[0-9a-f]{40}$' \
+             and b.toolchain = substring(a.toolchain from '#([0-9a-f]{40})$') \
+             and a.crate_name = b.crate_name and a.crate_vers = b.crate_vers",
+
+            "delete from build_logs a using build_logs b \
+             where a.toolchain ~ '#[0-9a-f]{40}$' and b.toolchain ~ '#[0-9a-f]{40}$' \
+             and substring(a.toolchain from '#([0-9a-f]{40})$') = \
+             substring(b.toolchain from '#([0-9a-f]{40})$') \
+             and a.toolchain > b.toolchain \
+             and a.crate_name = b.crate_name and a.crate_vers = b.crate_vers",
+
+            "update build_logs set toolchain = substring(toolchain from '#([0-9a-f]{40})$') \
+             where toolchain ~ '#[0-9a-f]{40}$'",
+
+            "delete from build_diagnostics a using build_diagnostics b \
+             where a.toolchain ~ '#[0-9a-f]{40}$' \
+             and b.toolchain = substring(a.toolchain from '#([0-9a-f]{40})$') \
+             and a.crate_name = b.crate_name and a.crate_vers = b.crate_vers",
+
+            "delete from build_diagnostics a using build_diagnostics b \
+             where a.toolchain ~ '#[0-9a-f]{40}$' and b.toolchain ~ '#[0-9a-f]{40}$' \
+             and substring(a.toolchain from '#([0-9a-f]{40})$') = \
+             substring(b.toolchain from '#([0-9a-f]{40})$') \
+             and a.toolchain > b.toolchain \
+             and a.crate_name = b.crate_name and a.crate_vers = b.crate_vers",
+
+            "update build_diagnostics set toolchain = substring(toolchain from '#([0-9a-f]{40})$') \
+             where toolchain ~ '#[0-9a-f]{40}$'",
+
+            "delete from custom_toolchains a using custom_toolchains b \
+             where a.toolchain ~ '#[0-9a-f]{40}$' \
+             and b.toolchain = substring(a.toolchain from '#([0-9a-f]{40})$')",
+
+            "delete from custom_toolchains a using custom_toolchains b \
+             where a.toolchain ~ '#[0-9a-f]{40}$' and b.toolchain ~ '#[0-9a-f]{40}$' \
+             and substring(a.toolchain from '#([0-9a-f]{40})$') = \
+             substring(b.toolchain from '#([0-9a-f]{40})$') \
+             and a.toolchain > b.toolchain",
+
+            "update custom_toolchains set toolchain = substring(toolchain from '#([0-9a-f]{40})$') \
+             where toolchain ~ '#[0-9a-f]{40}$'"
+        ]
+    },
+    // Results are replaced by later builds of the same crate, so a job
+    // keeps its own status rather than being joined to them by task id
+    Migration {
+        version: 11,
+        name: "add scheduled job statuses",
+        sql: &[
+            "alter table scheduled_jobs add column status text \
+             check (status in ('success', 'failure', 'exception', 'unknown'))",
+            "update scheduled_jobs j set status = r.status \
+             from build_results r where r.task_id = j.task_id"
+        ]
+    }
+];
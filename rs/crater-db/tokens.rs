@@ -0,0 +1,80 @@
+//! API tokens. Only a salted hash of each token is stored, so a copy
+//! of the database doesn't let anyone in.
+
+use crypto::hmac::Hmac;
+use crypto::pbkdf2::pbkdf2;
+use crypto::sha2::Sha256;
+use crypto::util::fixed_time_eq;
+use rand::{self, Rng};
+use rustc_serialize::hex::{FromHex, ToHex};
+
+const TOKEN_BYTES: usize = 32;
+const SALT_BYTES: usize = 16;
+const HASH_BYTES: usize = 32;
+const HASH_ROUNDS: u32 = 10000;
+
+/// Checked against when there's no such user, so that takes as long
+/// as a wrong token for a real one. No token hashes to it.
+pub const DUMMY_SALT: &'static str = "00000000000000000000000000000000";
+pub const DUMMY_HASH: &'static str =
+    "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// A new random token, as hex
+pub fn new_token() -> String {
+    random_hex(TOKEN_BYTES)
+}
+
+/// A new random salt, as hex
+pub fn new_salt() -> String {
+    random_hex(SALT_BYTES)
+}
+
+/// PBKDF2-HMAC-SHA256 of the token, as hex
+pub fn hash_token(token: &str, salt: &str) -> String {
+    let mut mac = Hmac::new(Sha256::new(), token.as_bytes());
+    let mut hash = [0; HASH_BYTES];
+    pbkdf2(&mut mac, salt.as_bytes(), HASH_ROUNDS, &mut hash);
+
+    hash.to_hex()
+}
+
+/// Compares hashes in constant time, so response times don't give
+/// away how much of a guess was right
+pub fn token_matches(token: &str, salt: &str, hash: &str) -> bool {
+    let expected = match hash.from_hex() {
+        Ok(expected) => expected,
+        Err(_) => return false
+    };
+    let actual = hash_token(token, salt).from_hex().expect("hashes should be valid hex");
+
+    fixed_time_eq(&actual, &expected)
+}
+
+fn random_hex(len: usize) -> String {
+    let mut bytes = vec![0; len];
+    rand::thread_rng().fill_bytes(&mut bytes);
+
+    bytes.to_hex()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn match_hashed_token() {
+        let token = new_token();
+        let salt = new_salt();
+        let hash = hash_token(&token, &salt);
+        assert!(token_matches(&token, &salt, &hash));
+        assert!(!token_matches(&new_token(), &salt, &hash));
+        assert!(!token_matches(&token, &new_salt(), &hash));
+        assert!(!token_matches(&token, &salt, "not hex"));
+    }
+
+    #[test]
+    fn salts_differ() {
+        let token = new_token();
+        assert!(hash_token(&token, &new_salt()) != hash_token(&token, &new_salt()));
+    }
+}
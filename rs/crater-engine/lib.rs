@@ -1,27 +1,87 @@
+#![cfg_attr(test, feature(std_misc))]
+
 extern crate rustc_serialize;
+extern crate hyper;
+extern crate time;
+extern crate taskcluster;
 extern crate crater_bus as bus;
+extern crate crater_db as db;
+extern crate crater_api as api;
+extern crate crater_index as index;
+extern crate crater_logs as logs;
 #[macro_use]
 extern crate log;
 
+use api::{BuildStatus, Toolchain};
+use bus::Msg;
+use db::{BuildLog, BuildResult, BuildResultKey, CustomToolchain, Database};
+use rustc_serialize::json::Json;
 use std::error::Error as StdError;
 use std::fmt::{self, Display, Formatter};
+use std::thread;
+use std::time::Duration;
+use scheduler::Scheduler;
+use taskcluster::Queue;
+
+pub mod scheduler;
 
 #[derive(RustcEncodable, RustcDecodable)]
 #[derive(Clone)]
 pub struct Config {
-    bus_config: bus::Config
+    bus_config: bus::Config,
+    queue_config: taskcluster::Config,
+    scheduler_config: scheduler::Config
+}
+
+pub fn initialize(config: Config, db: Database) -> Result<Engine, Error> {
+    let tasks = QueueTaskLookup { queue: Queue::new(config.queue_config.clone()) };
+    initialize_with_task_lookup(config, db, Box::new(tasks))
 }
 
-pub fn initialize(config: Config) -> Result<Engine, Error> {
+pub fn initialize_with_task_lookup(config: Config, db: Database, tasks: Box<TaskLookup>
+                                   ) -> Result<Engine, Error> {
     let bus = try!(bus::connect(config.bus_config));
 
     Ok(Engine {
-        bus: bus
+        bus: bus,
+        db: db,
+        tasks: tasks
     })
 }
 
+/// Creates a scheduler for submitting crate builds. Unlike the
+/// engine, which runs on its own thread, it's used from request
+/// handlers.
+pub fn scheduler(config: &Config, db_config: &db::Config) -> Scheduler {
+    Scheduler::new(config.scheduler_config.clone(), config.queue_config.clone(),
+                   db_config.clone())
+}
+
 pub struct Engine {
-    bus: bus::Bus
+    bus: bus::Bus,
+    db: Database,
+    tasks: Box<TaskLookup>
+}
+
+/// What a crater task was scheduled to do, from the `extra.crater`
+/// section of its definition
+#[derive(Debug, Clone, PartialEq)]
+pub enum TaskInfo {
+    CrateBuild {
+        toolchain: Toolchain,
+        crate_name: String,
+        crate_vers: String
+    },
+    CustomBuild {
+        toolchain: Toolchain
+    }
+}
+
+/// Finds out what a task was for, and what it printed, given its id
+pub trait TaskLookup: Send {
+    fn lookup(&self, task_id: &str) -> Result<TaskInfo, Error>;
+    /// The terminal log of a finished crate build
+    fn build_log(&self, task_id: &str) -> Result<String, Error>;
 }
 
 impl Engine {
@@ -30,31 +90,311 @@ impl Engine {
         let listener = try!(self.bus.listen());
 
         loop {
-            match try!(listener.recv()) {
-                Some(_) => {
-                }
+            let mut batch = match try!(listener.recv()) {
+                Some(msg) => vec![msg],
                 None => {
                     return Ok(());
                 }
+            };
+            while batch.len() < MAX_BATCH {
+                match try!(listener.try_recv()) {
+                    Some(msg) => batch.push(msg),
+                    None => break
+                }
+            }
+
+            // Messages are acked only once their results are stored, so
+            // none are lost if the database or the engine goes away
+            let done = self.handle_batch(&batch);
+            for (msg, &done) in batch.iter().zip(&done) {
+                if done {
+                    try!(listener.ack(msg));
+                } else {
+                    try!(listener.requeue(msg));
+                }
+            }
+            if done.contains(&false) {
+                thread::sleep(Duration::from_secs(RETRY_DELAY_SECS));
             }
         }
     }
+
+    /// Records the crate build results of a batch of messages with a
+    /// single insert, or one at a time if that fails, then their job
+    /// statuses and logs. Returns whether
+    /// each message is done with, or should be handled again later.
+    fn handle_batch(&self, msgs: &[Msg]) -> Vec<bool> {
+        let mut done = vec![true; msgs.len()];
+        // The results, and the messages they came from
+        let mut results = Vec::new();
+        let mut sources = Vec::new();
+        for (i, msg) in msgs.iter().enumerate() {
+            // One bad task shouldn't stop the engine
+            match self.handle_msg(msg) {
+                Ok(Some(result)) => {
+                    results.push(result);
+                    sources.push(i);
+                }
+                Ok(None) => (),
+                Err(e) => {
+                    error!("failed to record result of task {}: {}", msg.event().task_id, e);
+                    if let Error::DbError(_) = e {
+                        done[i] = false;
+                    }
+                }
+            }
+        }
+        if results.is_empty() {
+            return done;
+        }
+
+        info!("adding {} build results", results.len());
+        let mut stored = vec![true; results.len()];
+        if let Err(e) = self.db.add_build_results(&results) {
+            // Probably one bad row, so the rest are stored one at a time
+            error!("failed to record {} build results, adding them singly: {}",
+                   results.len(), e);
+            for (result, stored) in results.iter().zip(&mut stored) {
+                if let Err(e) = self.db.add_build_result(result) {
+                    error!("failed to record result of task {}: {}", result.task_id, e);
+                    *stored = false;
+                }
+            }
+        }
+        for ((result, &i), &stored) in results.iter().zip(&sources).zip(&stored) {
+            if !stored {
+                done[i] = false;
+                continue;
+            }
+
+            if let Err(e) = self.db.set_job_status(&result.task_id, result.status) {
+                error!("failed to record status of task {}: {}", result.task_id, e);
+            }
+
+            // Tasks that hit exceptions often have no log, and the
+            // result is worth keeping without one
+            if let Err(e) = self.record_log(result) {
+                warn!("failed to record log of task {}: {}", result.task_id, e);
+            }
+        }
+
+        done
+    }
+
+    /// Records custom builds straight away, and returns the result of
+    /// a crate build for `handle_batch` to record
+    fn handle_msg(&self, msg: &Msg) -> Result<Option<BuildResult>, Error> {
+        let task_id = &msg.event().task_id;
+        let status = task_status(msg);
+
+        match try!(self.tasks.lookup(task_id)) {
+            TaskInfo::CrateBuild { toolchain, crate_name, crate_vers } => {
+                let result = BuildResult {
+                    toolchain: toolchain,
+                    crate_name: crate_name,
+                    crate_vers: crate_vers,
+                    status: status,
+                    task_id: task_id.clone()
+                };
+                debug!("build result: {:?}", result);
+                return Ok(Some(result));
+            }
+            // Like monitor.js, only completed builds are recorded, so a
+            // failed rebuild can't replace a good one
+            TaskInfo::CustomBuild { .. } if status != BuildStatus::Success => {
+                info!("ignoring unsuccessful custom build {}: {}", task_id, status);
+            }
+            TaskInfo::CustomBuild { toolchain } => {
+                let custom = CustomToolchain {
+                    toolchain: toolchain,
+                    status: status,
+                    task_id: task_id.clone()
+                };
+                info!("adding custom toolchain: {:?}", custom);
+                try!(self.db.add_custom_toolchain(&custom).map_err(db_error));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Stores the interesting parts of a build's log, and the
+    /// diagnostics found in it. Without a log, those from an earlier
+    /// build of the crate are deleted.
+    fn record_log(&self, result: &BuildResult) -> Result<(), Error> {
+        let key = BuildResultKey {
+            toolchain: result.toolchain.clone(),
+            crate_name: result.crate_name.clone(),
+            crate_vers: result.crate_vers.clone()
+        };
+        let log = match self.tasks.build_log(&result.task_id) {
+            Ok(log) => log,
+            Err(e) => {
+                try!(self.db.delete_build_log(&key).map_err(db_error));
+                try!(self.db.set_diagnostics(&key, &[]).map_err(db_error));
+                return Err(e);
+            }
+        };
+
+        try!(self.db.set_diagnostics(&key, &logs::diagnostics(&log)).map_err(db_error));
+
+        let excerpt = logs::excerpt(&log);
+        let log = BuildLog {
+            toolchain: result.toolchain.clone(),
+            crate_name: result.crate_name.clone(),
+            crate_vers: result.crate_vers.clone(),
+            task_id: result.task_id.clone(),
+            errors: excerpt.errors,
+            tail: excerpt.tail
+        };
+
+        self.db.add_build_log(&log).map_err(db_error)
+    }
+}
+
+/// The most messages handled together
+const MAX_BATCH: usize = 1000;
+
+/// How long to wait after failing to store results before handling
+/// more messages, so a database outage doesn't spin through them
+const RETRY_DELAY_SECS: u64 = 10;
+
+/// Maps the TaskCluster resolution of a task to a build status
+fn task_status(msg: &Msg) -> BuildStatus {
+    match *msg {
+        Msg::TaskCompleted(_) => BuildStatus::Success,
+        Msg::TaskFailed(_) => BuildStatus::Failure,
+        Msg::TaskException(_) => BuildStatus::Exception
+    }
+}
+
+/// The artifact crate builds write their output to
+const BUILD_LOG_ARTIFACT: &'static str = "public/logs/terminal.log";
+
+/// How much of a build log is downloaded, half from the start and
+/// half from the end. Errors come early and the outcome last, while
+/// some builds print hundreds of megabytes in between.
+const MAX_BUILD_LOG_BYTES: usize = 4 * 1024 * 1024;
+
+/// Looks up task definitions in the TaskCluster queue
+struct QueueTaskLookup {
+    queue: Queue
+}
+
+impl TaskLookup for QueueTaskLookup {
+    fn lookup(&self, task_id: &str) -> Result<TaskInfo, Error> {
+        debug!("requesting task {}", task_id);
+        let task = try!(self.queue.task(task_id));
+        task_info(&task.extra)
+    }
+
+    fn build_log(&self, task_id: &str) -> Result<String, Error> {
+        debug!("requesting log of task {}", task_id);
+        Ok(try!(self.queue.latest_public_artifact(task_id, BUILD_LOG_ARTIFACT,
+                                                  MAX_BUILD_LOG_BYTES)))
+    }
+}
+
+/// Reads the crater metadata from the `extra` section of a task
+/// definition
+pub fn task_info(extra: &Json) -> Result<TaskInfo, Error> {
+    let bad = |what: &str| Error::BadTask(String::from(what));
+    let extra = try!(extra.find("crater").ok_or(bad("no crater metadata")));
+    let field = |name: &str| extra.find(name).and_then(|f| f.as_string());
+
+    match field("taskType") {
+        Some("crate-build") => {
+            let toolchain = try!(extra.find("toolchain")
+                                 .and_then(parse_toolchain)
+                                 .ok_or(bad("bad toolchain")));
+            let crate_name = try!(field("crateName").ok_or(bad("no crate name")));
+            let crate_vers = try!(field("crateVers").ok_or(bad("no crate version")));
+
+            Ok(TaskInfo::CrateBuild {
+                toolchain: toolchain,
+                crate_name: String::from(crate_name),
+                crate_vers: String::from(crate_vers)
+            })
+        }
+        Some("custom-build") => {
+            let sha = try!(field("toolchainGitSha").ok_or(bad("no toolchain sha")));
+            let toolchain = try!(Toolchain::custom(sha, None).map_err(|e| {
+                Error::BadTask(e.to_string())
+            }));
+
+            Ok(TaskInfo::CustomBuild { toolchain: toolchain })
+        }
+        Some(t) => Err(Error::BadTask(format!("unknown task type {}", t))),
+        None => Err(bad("no task type"))
+    }
+}
+
+/// Toolchains are strings in tasks scheduled from Rust, but objects
+/// like `{ "channel": "nightly", "archiveDate": "2015-06-06" }` or
+/// `{ "customSha": "..." }` in those scheduled by scheduler.js
+fn parse_toolchain(json: &Json) -> Option<Toolchain> {
+    if let Some(s) = json.as_string() {
+        return s.parse().ok();
+    }
+
+    let field = |name: &str| json.find(name).and_then(|f| f.as_string());
+    match (field("customSha"), field("channel"), field("archiveDate")) {
+        (Some(sha), _, _) => Toolchain::custom(sha, None).ok(),
+        (None, Some(channel), Some(date)) => format!("{}-{}", channel, date).parse().ok(),
+        _ => None
+    }
+}
+
+fn db_error(e: Box<StdError>) -> Error {
+    Error::DbError(e.to_string())
 }
 
 #[derive(Debug)]
 pub enum Error {
-    BusError(bus::Error)
+    BusError(bus::Error),
+    DbError(String),
+    TaskLookupError(String),
+    BadTask(String),
+    /// A custom toolchain without a successful build
+    UnknownToolchain(String),
+    /// Failed to find the installer for a release
+    DistError(String),
+    /// `--top` was asked for before the index was synced
+    MissingRanking,
+    /// More jobs were selected than the caller allowed
+    TooManyJobs { jobs: usize, max_jobs: usize }
 }
 
 impl StdError for Error {
     fn description(&self) -> &str {
-        "message bus error"
+        match *self {
+            Error::BusError(_) => "message bus error",
+            Error::DbError(_) => "database error",
+            Error::TaskLookupError(_) => "failed to look up task",
+            Error::BadTask(_) => "unexpected task definition",
+            Error::UnknownToolchain(_) => "no build of toolchain",
+            Error::DistError(_) => "failed to find release installer",
+            Error::MissingRanking => "no crate ranking; sync the index first",
+            Error::TooManyJobs { .. } => "too many crate builds"
+        }
     }
 }
 
 impl Display for Error {
     fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
-        f.write_str(self.description())
+        match *self {
+            Error::BusError(ref e) => write!(f, "{}: {}", self.description(), e),
+            Error::DbError(ref s) |
+            Error::TaskLookupError(ref s) |
+            Error::BadTask(ref s) |
+            Error::UnknownToolchain(ref s) |
+            Error::DistError(ref s) => write!(f, "{}: {}", self.description(), s),
+            Error::MissingRanking => write!(f, "{}", self.description()),
+            Error::TooManyJobs { jobs, max_jobs } => {
+                write!(f, "{}: {} selected, at most {} allowed",
+                       self.description(), jobs, max_jobs)
+            }
+        }
     }
 }
 
@@ -63,3 +403,219 @@ impl From<bus::Error> for Error {
         Error::BusError(e)
     }
 }
+
+impl From<taskcluster::Error> for Error {
+    fn from(e: taskcluster::Error) -> Error {
+        Error::TaskLookupError(e.to_string())
+    }
+}
+
+// Tests expect username/password/db's 'crater-test' to exist
+#[cfg(test)]
+mod test {
+    use super::*;
+    use api::{BuildStatus, Diagnostic, Toolchain};
+    use bus::{self, Backend, MemoryConfig, Msg, TaskEvent};
+    use db::{self, BuildLog, BuildResultKey, Database, ExperimentProgress, ScheduledJob};
+    use rustc_serialize::json::Json;
+    use std::collections::HashMap;
+    use std::sync::{StaticMutex, MUTEX_INIT};
+
+    static LOCK: StaticMutex = MUTEX_INIT;
+
+    const CUSTOM_SHA: &'static str = "0123456789abcdef0123456789abcdef01234567";
+
+    struct FakeTaskLookup {
+        tasks: HashMap<String, TaskInfo>,
+        logs: HashMap<String, String>
+    }
+
+    impl TaskLookup for FakeTaskLookup {
+        fn lookup(&self, task_id: &str) -> Result<TaskInfo, Error> {
+            self.tasks.get(task_id).cloned().ok_or(Error::TaskLookupError(task_id.to_string()))
+        }
+
+        fn build_log(&self, task_id: &str) -> Result<String, Error> {
+            self.logs.get(task_id).cloned().ok_or(Error::TaskLookupError(task_id.to_string()))
+        }
+    }
+
+    fn connect() -> Database {
+        Database::connect(&db::Config {
+            dbname: "crater-test".to_string(),
+            username: "crater-test".to_string(),
+            password: "crater-test".to_string(),
+            host: "localhost".to_string(),
+            port: 5432
+        }).unwrap()
+    }
+
+    fn event(task_id: &str) -> TaskEvent {
+        TaskEvent {
+            task_id: task_id.to_string(),
+            run_id: 0,
+            routing_key: "route.crater.#".to_string(),
+            delivery_tag: None
+        }
+    }
+
+    fn crate_build(toolchain: &str, name: &str) -> TaskInfo {
+        TaskInfo::CrateBuild {
+            toolchain: toolchain.parse().unwrap(),
+            crate_name: name.to_string(),
+            crate_vers: "1.0.0".to_string()
+        }
+    }
+
+    /// Runs an engine over `msgs` until the bus runs dry
+    fn run_engine(tasks: Vec<(&str, TaskInfo)>, logs: Vec<(&str, &str)>, msgs: Vec<Msg>) {
+        let config = Config {
+            bus_config: bus::Config {
+                backend: Backend::Memory,
+                amqp: None,
+                memory: Some(MemoryConfig { blocking: false, timeout_ms: None })
+            },
+            queue_config: taskcluster::Config {
+                queue_url: String::from(taskcluster::DEFAULT_QUEUE_URL),
+                credentials: None
+            },
+            scheduler_config: scheduler::Config {
+                dl_root_url: String::from("https://crates.io/api/v1/crates"),
+                rust_dist_url: String::from("https://static.rust-lang.org/dist"),
+                max_concurrency: 1
+            }
+        };
+        let tasks = FakeTaskLookup {
+            tasks: tasks.into_iter().map(|(id, info)| (id.to_string(), info)).collect(),
+            logs: logs.into_iter().map(|(id, log)| (id.to_string(), log.to_string())).collect()
+        };
+        let engine = initialize_with_task_lookup(config, connect(), Box::new(tasks)).unwrap();
+
+        let publisher = engine.bus.publisher().unwrap();
+        for msg in msgs {
+            publisher.publish(msg).unwrap();
+        }
+        engine.run().unwrap();
+    }
+
+    fn status(db: &Database, toolchain: &str, name: &str) -> BuildStatus {
+        db.get_build_result(&BuildResultKey {
+            toolchain: toolchain.parse().unwrap(),
+            crate_name: name.to_string(),
+            crate_vers: "1.0.0".to_string()
+        }).unwrap().status
+    }
+
+    #[test]
+    fn records_results_from_messages() {
+        let _g = LOCK.lock().unwrap_or_else(|p| p.into_inner());
+        { connect().delete_tables_and_close().unwrap(); }
+
+        let tasks = vec![
+            ("t1", crate_build("nightly-2015-01-01", "num")),
+            ("t2", crate_build("nightly-2015-01-01", "toml")),
+            ("t3", crate_build("nightly-2015-01-01", "time")),
+            ("t4", TaskInfo::CustomBuild { toolchain: Toolchain::custom(CUSTOM_SHA, None).unwrap() }),
+            ("t5", TaskInfo::CustomBuild { toolchain: Toolchain::custom(CUSTOM_SHA, None).unwrap() })
+        ];
+        let msgs = vec![
+            Msg::TaskCompleted(event("t1")),
+            Msg::TaskFailed(event("t2")),
+            Msg::TaskException(event("t3")),
+            // Not ours, and mustn't stop the engine
+            Msg::TaskCompleted(event("not-a-crater-task")),
+            Msg::TaskCompleted(event("t4")),
+            // A failed rebuild
+            Msg::TaskFailed(event("t5"))
+        ];
+        // As if scheduled as crate build e
+        let job = |name: &str, task_id: &str| ScheduledJob {
+            experiment: "e".to_string(),
+            toolchain: "nightly-2015-01-01".parse().unwrap(),
+            crate_name: name.to_string(),
+            crate_vers: "1.0.0".to_string(),
+            task_id: task_id.to_string()
+        };
+        let jobs = vec![job("num", "t1"), job("toml", "t2"), job("time", "t3")];
+        {
+            let db = connect();
+            db.add_scheduled_jobs(&jobs).unwrap();
+            // From an earlier build of time, and stale once t3 is in
+            db.add_build_log(&BuildLog {
+                toolchain: "nightly-2015-01-01".parse().unwrap(),
+                crate_name: "time".to_string(),
+                crate_vers: "1.0.0".to_string(),
+                task_id: "t0".to_string(),
+                errors: String::new(),
+                tail: "   Compiling time v1.0.0".to_string()
+            }).unwrap();
+            db.set_diagnostics(&BuildResultKey {
+                toolchain: "nightly-2015-01-01".parse().unwrap(),
+                crate_name: "time".to_string(),
+                crate_vers: "1.0.0".to_string()
+            }, &[Diagnostic::ErrorCode("E0425".to_string())]).unwrap();
+        }
+        // t3 has no log, which mustn't lose its result
+        let logs = vec![
+            ("t1", "   Compiling num v1.0.0"),
+            ("t2", "   Compiling toml v1.0.0\nerror: unresolved import\nCould not compile `toml`.")
+        ];
+        run_engine(tasks, logs, msgs);
+
+        let db = connect();
+        assert_eq!(db.get_experiment_progress("e").unwrap(), ExperimentProgress {
+            scheduled: 3,
+            finished: 2,
+            exception: 1,
+            outstanding: 0
+        });
+        assert_eq!(status(&db, "nightly-2015-01-01", "num"), BuildStatus::Success);
+        assert_eq!(status(&db, "nightly-2015-01-01", "toml"), BuildStatus::Failure);
+        assert_eq!(status(&db, "nightly-2015-01-01", "time"), BuildStatus::Exception);
+
+        let log = |name: &str| db.get_build_log(&BuildResultKey {
+            toolchain: "nightly-2015-01-01".parse().unwrap(),
+            crate_name: name.to_string(),
+            crate_vers: "1.0.0".to_string()
+        }).unwrap();
+        let toml = log("toml").unwrap();
+        assert_eq!(toml.task_id, "t2");
+        assert_eq!(toml.errors, "error: unresolved import");
+        assert!(toml.tail.ends_with("Could not compile `toml`."));
+        assert_eq!(db.get_diagnostics(&BuildResultKey {
+            toolchain: "nightly-2015-01-01".parse().unwrap(),
+            crate_name: "toml".to_string(),
+            crate_vers: "1.0.0".to_string()
+        }).unwrap(), vec![Diagnostic::Error("unresolved import".to_string())]);
+        assert_eq!(log("time"), None);
+        assert_eq!(db.get_diagnostics(&BuildResultKey {
+            toolchain: "nightly-2015-01-01".parse().unwrap(),
+            crate_name: "time".to_string(),
+            crate_vers: "1.0.0".to_string()
+        }).unwrap(), vec![]);
+
+        let custom = db.get_custom_toolchain(&CUSTOM_SHA.parse().unwrap()).unwrap().unwrap();
+        assert_eq!(custom.status, BuildStatus::Success);
+        assert_eq!(custom.task_id, "t4");
+    }
+
+    #[test]
+    fn task_info_from_definitions() {
+        let js_task = Json::from_str(r#"{ "crater": {
+            "toolchain": { "channel": "nightly", "archiveDate": "2015-01-01" },
+            "crateName": "num", "crateVers": "1.0.0", "taskType": "crate-build"
+        } }"#).unwrap();
+        assert_eq!(task_info(&js_task).unwrap(), crate_build("nightly-2015-01-01", "num"));
+
+        let custom_task = Json::from_str(&format!(r#"{{ "crater": {{
+            "toolchainGitRepo": "https://github.com/rust-lang/rust.git",
+            "toolchainGitSha": "{}", "taskType": "custom-build"
+        }} }}"#, CUSTOM_SHA)).unwrap();
+        assert_eq!(task_info(&custom_task).unwrap(), TaskInfo::CustomBuild {
+            toolchain: CUSTOM_SHA.parse().unwrap()
+        });
+
+        let other_task = Json::from_str(r#"{}"#).unwrap();
+        assert!(task_info(&other_task).is_err());
+    }
+}
@@ -0,0 +1,503 @@
+//! Schedules crate builds on TaskCluster. This follows
+//! `createSchedule` and `scheduleBuilds` in scheduler.js.
+
+use api::{BuildStatus, Channel, Toolchain};
+use api::v1::CrateSelection;
+use db::{self, BuildResultKey, Database, Experiment, ScheduledJob};
+use hyper::Client;
+use index::Index;
+use rustc_serialize::json::Json;
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error as StdError;
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use taskcluster::{self, Metadata, Payload, Queue, TaskDefinition};
+use time::{self, Duration};
+use Error;
+
+const TRIPLE: &'static str = "x86_64-unknown-linux-gnu";
+const CRATE_BUILD_MAX_RUN_TIME_SECS: u64 = 10 * 60;
+const CRATE_BUILD_DEADLINE_MINS: i64 = 960;
+
+#[derive(RustcEncodable, RustcDecodable)]
+#[derive(Clone)]
+pub struct Config {
+    /// Where crates are downloaded from, e.g.
+    /// `https://crates.io/api/v1/crates`
+    pub dl_root_url: String,
+    /// The Rust release archives, e.g.
+    /// `https://static.rust-lang.org/dist`
+    pub rust_dist_url: String,
+    /// The most tasks to submit at once
+    pub max_concurrency: usize
+}
+
+/// A single crate build
+#[derive(Debug, Clone, PartialEq)]
+pub struct Job {
+    pub toolchain: Toolchain,
+    pub crate_name: String,
+    pub crate_vers: String
+}
+
+/// Where a task downloads its toolchain from. Custom builds come
+/// with separate std and cargo installers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Installers {
+    pub rust: String,
+    pub std: Option<String>,
+    pub cargo: Option<String>
+}
+
+#[derive(Debug)]
+pub struct ScheduledBuilds {
+    pub experiment_id: String,
+    pub jobs: usize
+}
+
+pub struct Scheduler {
+    config: Config,
+    queue: Arc<Queue>,
+    /// Tasks are submitted in the background, which records failures
+    /// over its own connection
+    db_config: db::Config
+}
+
+impl Scheduler {
+    pub fn new(config: Config, queue_config: taskcluster::Config, db_config: db::Config
+               ) -> Scheduler {
+        Scheduler {
+            config: config,
+            queue: Arc::new(Queue::new(queue_config)),
+            db_config: db_config
+        }
+    }
+
+    /// Works out which crates to build and starts submitting the
+    /// tasks in the background, returning immediately. Nothing is
+    /// scheduled if there would be more than `max_jobs`.
+    pub fn schedule_crate_builds(&self, db: &Database, index: &Index, toolchain: &Toolchain,
+                                 selection: &CrateSelection, max_jobs: Option<usize>
+                                 ) -> Result<ScheduledBuilds, Error> {
+        let experiment_id = taskcluster::slugid();
+        let jobs = try!(self.schedule(db, index, &experiment_id, &[toolchain], selection,
+                                      max_jobs));
+
+        Ok(ScheduledBuilds {
+            experiment_id: experiment_id,
+            jobs: jobs
+        })
+    }
+
+    /// Schedules the crate builds for both of an experiment's
+    /// toolchains, in the background like `schedule_crate_builds`
+    pub fn schedule_experiment(&self, db: &Database, index: &Index, experiment: &Experiment,
+                               max_jobs: Option<usize>) -> Result<ScheduledBuilds, Error> {
+        let toolchains = [&experiment.toolchain_from, &experiment.toolchain_to];
+        let jobs = try!(self.schedule(db, index, &experiment.name, &toolchains,
+                                      &experiment.crate_selection, max_jobs));
+
+        Ok(ScheduledBuilds {
+            experiment_id: experiment.name.clone(),
+            jobs: jobs
+        })
+    }
+
+    /// Finds the installers for every toolchain before submitting
+    /// anything, so a bad toolchain fails the whole request. The top
+    /// crates come from the ranking stored by the last index sync. Jobs are
+    /// recorded with their task ids before the tasks are created, so
+    /// progress can be tracked from the start.
+    fn schedule(&self, db: &Database, index: &Index, experiment_id: &str,
+                toolchains: &[&Toolchain], selection: &CrateSelection,
+                max_jobs: Option<usize>) -> Result<usize, Error> {
+        let ranking = match selection.top {
+            Some(_) => try!(db.get_ranked_crates().map_err(::db_error)),
+            None => Vec::new()
+        };
+        if selection.top.is_some() && ranking.is_empty() {
+            return Err(Error::MissingRanking);
+        }
+
+        let mut batches = Vec::new();
+        for &toolchain in toolchains {
+            let mut jobs = select_jobs(index, &ranking, toolchain, selection);
+            if selection.skip_existing {
+                jobs = remove_jobs_with_results(db, jobs);
+            }
+            let installers = try!(self.installers(db, toolchain));
+            info!("scheduling {} crate builds for {} as {}", jobs.len(), toolchain, experiment_id);
+
+            let jobs: Vec<(String, Job)> = jobs.into_iter()
+                .map(|job| (taskcluster::slugid(), job))
+                .collect();
+            batches.push((jobs, installers));
+        }
+
+        let total = batches.iter().fold(0, |n, &(ref jobs, _)| n + jobs.len());
+        if let Some(max_jobs) = max_jobs {
+            if total > max_jobs {
+                return Err(Error::TooManyJobs { jobs: total, max_jobs: max_jobs });
+            }
+        }
+
+        let mut scheduled = Vec::new();
+        for &(ref jobs, _) in &batches {
+            for &(ref task_id, ref job) in jobs {
+                scheduled.push(ScheduledJob {
+                    experiment: experiment_id.to_string(),
+                    toolchain: job.toolchain.clone(),
+                    crate_name: job.crate_name.clone(),
+                    crate_vers: job.crate_vers.clone(),
+                    task_id: task_id.clone()
+                });
+            }
+        }
+        try!(db.add_scheduled_jobs(&scheduled).map_err(::db_error));
+
+        for (jobs, installers) in batches {
+            self.submit(jobs, installers);
+        }
+
+        Ok(scheduled.len())
+    }
+
+    /// Creates the tasks from a pool of `max_concurrency` threads.
+    /// Jobs whose task couldn't be created are marked as exceptions
+    /// once the pool is done, so they don't stay outstanding.
+    fn submit(&self, jobs: Vec<(String, Job)>, installers: Installers) {
+        let total = jobs.len();
+        let jobs = Arc::new(Mutex::new(jobs.into_iter().enumerate()));
+        let installers = Arc::new(installers);
+        let failed = Arc::new(Mutex::new(Vec::new()));
+
+        let mut workers = Vec::new();
+        for _ in 0..cmp::min(self.config.max_concurrency, total) {
+            let jobs = jobs.clone();
+            let installers = installers.clone();
+            let queue = self.queue.clone();
+            let dl_root_url = self.config.dl_root_url.clone();
+            let failed = failed.clone();
+
+            workers.push(thread::spawn(move || {
+                loop {
+                    let next = jobs.lock().unwrap_or_else(|p| p.into_inner()).next();
+                    let (i, (task_id, job)) = match next {
+                        Some(next) => next,
+                        None => break
+                    };
+
+                    let task = crate_build_task(&job, &installers, &dl_root_url);
+                    match queue.create_task(&task_id, &task) {
+                        Ok(_) => {
+                            info!("created task {} of {} for {}-{}: {}",
+                                  i + 1, total, job.crate_name, job.crate_vers, task_id);
+                        }
+                        Err(e) => {
+                            error!("failed to create task for {}-{}: {}",
+                                   job.crate_name, job.crate_vers, e);
+                            failed.lock().unwrap_or_else(|p| p.into_inner()).push(task_id);
+                        }
+                    }
+                }
+            }));
+        }
+
+        let db_config = self.db_config.clone();
+        thread::spawn(move || {
+            for worker in workers {
+                let _ = worker.join();
+            }
+            let failed = failed.lock().unwrap_or_else(|p| p.into_inner());
+            if !failed.is_empty() {
+                if let Err(e) = mark_unsubmitted(&db_config, &failed) {
+                    error!("failed to mark {} unsubmitted jobs: {}", failed.len(), e);
+                }
+            }
+        });
+    }
+
+    fn installers(&self, db: &Database, toolchain: &Toolchain) -> Result<Installers, Error> {
+        match *toolchain {
+            Toolchain::Dist { channel, ref date } => {
+                let rust = try!(dist_installer_url(&self.config.rust_dist_url, channel, date));
+                Ok(Installers {
+                    rust: rust,
+                    std: None,
+                    cargo: None
+                })
+            }
+            Toolchain::Custom { ref sha, .. } => {
+                // Custom toolchains are recorded by sha alone
+                let key = try!(Toolchain::custom(sha, None)
+                               .map_err(|e| Error::UnknownToolchain(e.to_string())));
+                let custom = try!(db.get_custom_toolchain(&key).map_err(::db_error));
+                let task_id = match custom {
+                    Some(ref c) if c.status == BuildStatus::Success => &c.task_id,
+                    _ => return Err(Error::UnknownToolchain(toolchain.to_string()))
+                };
+
+                let url = |name: &str| {
+                    let artifact = format!("public/{}-dev-{}.tar.gz", name, TRIPLE);
+                    self.queue.latest_artifact_url(task_id, &artifact)
+                };
+                Ok(Installers {
+                    rust: url("rustc"),
+                    std: Some(url("rust-std")),
+                    cargo: Some(url("cargo"))
+                })
+            }
+        }
+    }
+}
+
+/// Lists the crate builds for `selection`, applying the name, top
+/// and most-recent filters in that order. `ranking` lists crate names
+/// most popular first and is only used for the top filter. Every crate
+/// version is built once, in index order.
+pub fn select_jobs(index: &Index, ranking: &[String], toolchain: &Toolchain,
+                   selection: &CrateSelection) -> Vec<Job> {
+    let name_matches = |name: &str| selection.name.as_ref().map(|n| n == name).unwrap_or(true);
+
+    let top: Option<BTreeSet<&str>> = selection.top.map(|top| {
+        ranking.iter()
+            .map(|name| &**name)
+            .filter(|name| name_matches(*name))
+            .take(top as usize)
+            .collect()
+    });
+    let recent = index.most_recent_versions();
+
+    let mut seen = BTreeSet::new();
+    index.versions().iter()
+        .filter(|v| name_matches(&*v.name))
+        .filter(|v| top.as_ref().map(|t| t.contains(&*v.name)).unwrap_or(true))
+        .filter(|v| {
+            !selection.most_recent_only ||
+                recent.get(&*v.name).map(|r| r.vers == v.vers).unwrap_or(false)
+        })
+        .filter(|v| seen.insert((&*v.name, &*v.vers)))
+        .map(|v| Job {
+            toolchain: toolchain.clone(),
+            crate_name: v.name.clone(),
+            crate_vers: v.vers.clone()
+        })
+        .collect()
+}
+
+/// Drops jobs that already have a usable result. Exceptions and
+/// unknown results are rebuilt.
+fn remove_jobs_with_results(db: &Database, jobs: Vec<Job>) -> Vec<Job> {
+    jobs.into_iter().filter(|job| {
+        let key = BuildResultKey {
+            toolchain: job.toolchain.clone(),
+            crate_name: job.crate_name.clone(),
+            crate_vers: job.crate_vers.clone()
+        };
+        // A missing result is also an error here
+        match db.get_build_result(&key) {
+            Ok(ref r) if r.status == BuildStatus::Success || r.status == BuildStatus::Failure => {
+                debug!("existing result for {}-{}", job.crate_name, job.crate_vers);
+                false
+            }
+            _ => true
+        }
+    }).collect()
+}
+
+/// Marks jobs whose task was never created as exceptions
+fn mark_unsubmitted(db_config: &db::Config, task_ids: &[String]) -> Result<(), Box<StdError>> {
+    let db = try!(Database::connect_without_migrating(db_config));
+    for task_id in task_ids {
+        try!(db.set_job_status(task_id, BuildStatus::Exception));
+    }
+    Ok(())
+}
+
+/// Finds the installer for `TRIPLE` in a release's channel manifest
+fn dist_installer_url(rust_dist_url: &str, channel: Channel, date: &str) -> Result<String, Error> {
+    let manifest_url = format!("{}/{}/channel-rust-{}", rust_dist_url, date, channel);
+    debug!("manifest: {}", manifest_url);
+
+    let dist_error = |e: &ToString| Error::DistError(format!("{}: {}", manifest_url, e.to_string()));
+    let client = Client::new();
+    let mut res = try!(client.get(&manifest_url).send().map_err(|e| dist_error(&e)));
+    let mut manifest = String::new();
+    try!(res.read_to_string(&mut manifest).map_err(|e| dist_error(&e)));
+    if !res.status.is_success() {
+        return Err(dist_error(&res.status));
+    }
+
+    let installer = manifest.lines()
+        .map(|l| l.trim())
+        .filter(|l| l.contains(TRIPLE) && l.ends_with(".tar.gz"))
+        .last();
+    match installer {
+        Some(installer) => Ok(format!("{}/{}/{}", rust_dist_url, date, installer)),
+        None => Err(dist_error(&format!("no installer for {}", TRIPLE)))
+    }
+}
+
+/// Describes a crate build the way `createTaskDescriptor` does
+pub fn crate_build_task(job: &Job, installers: &Installers, dl_root_url: &str) -> TaskDefinition {
+    let task_type = "crate-build";
+
+    let mut env = BTreeMap::new();
+    env.insert(String::from("CRATER_RUST_INSTALLER"), installers.rust.clone());
+    env.insert(String::from("CRATER_CRATE_FILE"),
+               format!("{}/{}/{}/download", dl_root_url, job.crate_name, job.crate_vers));
+    if let Some(ref std) = installers.std {
+        env.insert(String::from("CRATER_STD_INSTALLER"), std.clone());
+    }
+    if let Some(ref cargo) = installers.cargo {
+        env.insert(String::from("CRATER_CARGO_INSTALLER"), cargo.clone());
+    }
+    env.insert(String::from("CRATER_TASK_TYPE"), String::from(task_type));
+
+    let mut crater = BTreeMap::new();
+    crater.insert(String::from("toolchain"), Json::String(job.toolchain.to_string()));
+    crater.insert(String::from("crateName"), Json::String(job.crate_name.clone()));
+    crater.insert(String::from("crateVers"), Json::String(job.crate_vers.clone()));
+    crater.insert(String::from("taskType"), Json::String(String::from(task_type)));
+    let mut extra = BTreeMap::new();
+    extra.insert(String::from("crater"), Json::Object(crater));
+
+    let cmd = "cd /home && curl -sfL https://raw.githubusercontent.com/brson/taskcluster-crater/\
+               master/run-crater-task.sh -o ./run.sh && sh ./run.sh";
+    let created = time::now_utc();
+    let deadline = created + Duration::minutes(CRATE_BUILD_DEADLINE_MINS);
+    let name = format!("{}-vs-{}-{}", job.toolchain, job.crate_name, job.crate_vers);
+
+    TaskDefinition {
+        provisionerId: String::from("aws-provisioner-v1"),
+        workerType: String::from("cratertest"),
+        created: taskcluster::timestamp(&created),
+        deadline: taskcluster::timestamp(&deadline),
+        retries: 5,
+        routes: vec![String::from("crater.#")],
+        payload: Payload {
+            image: String::from("brson/crater:3"),
+            command: vec![String::from("/bin/bash"), String::from("-c"), String::from(cmd)],
+            env: env,
+            maxRunTime: CRATE_BUILD_MAX_RUN_TIME_SECS,
+            artifacts: BTreeMap::new()
+        },
+        metadata: Metadata {
+            name: format!("Crater task {}", name),
+            description: String::from("Testing Rust crates for Rust language regressions"),
+            owner: String::from("banderson@mozilla.com"),
+            source: String::from("http://github.com/brson/taskcluster-crater")
+        },
+        extra: Json::Object(extra)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use api::Toolchain;
+    use api::v1::CrateSelection;
+    use index::{CrateVersion, Dependency, Index};
+    use std::collections::BTreeMap;
+
+    fn krate(name: &str, vers: &str, deps: &[&str]) -> CrateVersion {
+        CrateVersion {
+            name: name.to_string(),
+            vers: vers.to_string(),
+            deps: deps.iter().map(|d| Dependency {
+                name: d.to_string(),
+                req: "*".to_string(),
+                features: vec![],
+                optional: false,
+                default_features: true,
+                target: None,
+                kind: None
+            }).collect(),
+            features: BTreeMap::new(),
+            yanked: false
+        }
+    }
+
+    fn index() -> Index {
+        Index::from_versions(vec![
+            krate("a", "0.1.0", &[]),
+            krate("a", "0.2.0", &["c"]),
+            krate("b", "1.0.0", &["c"]),
+            krate("b", "1.0.0", &["c"]),
+            krate("c", "0.1.0", &[]),
+            krate("d", "0.1.0", &["c", "b"])
+        ])
+    }
+
+    fn names(jobs: &[Job]) -> Vec<String> {
+        jobs.iter().map(|j| format!("{}-{}", j.crate_name, j.crate_vers)).collect()
+    }
+
+    fn select(selection: CrateSelection) -> Vec<String> {
+        let toolchain: Toolchain = "nightly-2015-01-01".parse().unwrap();
+        let index = index();
+        let ranking: Vec<String> = index.popularity_ranking().into_iter()
+            .map(String::from)
+            .collect();
+        names(&select_jobs(&index, &ranking, &toolchain, &selection))
+    }
+
+    #[test]
+    fn select_everything() {
+        assert_eq!(select(CrateSelection { most_recent_only: false, ..Default::default() }),
+                   vec!["a-0.1.0", "a-0.2.0", "b-1.0.0", "c-0.1.0", "d-0.1.0"]);
+    }
+
+    #[test]
+    fn select_filtered() {
+        assert_eq!(select(CrateSelection {
+            name: Some("a".to_string()),
+            most_recent_only: false,
+            ..Default::default()
+        }), vec!["a-0.1.0", "a-0.2.0"]);
+        // Most recent only by default
+        assert_eq!(select(CrateSelection::default()),
+                   vec!["a-0.2.0", "b-1.0.0", "c-0.1.0", "d-0.1.0"]);
+        // c has three dependents, then b has one
+        assert_eq!(select(CrateSelection { top: Some(2), ..Default::default() }),
+                   vec!["b-1.0.0", "c-0.1.0"]);
+        assert_eq!(select(CrateSelection {
+            top: Some(1),
+            most_recent_only: true,
+            name: Some("a".to_string()),
+            skip_existing: false
+        }), vec!["a-0.2.0"]);
+    }
+
+    #[test]
+    fn crate_build_task_for_custom_toolchain() {
+        let job = Job {
+            toolchain: "0123456789abcdef0123456789abcdef01234567".parse().unwrap(),
+            crate_name: "num".to_string(),
+            crate_vers: "1.0.0".to_string()
+        };
+        let installers = Installers {
+            rust: "https://example.com/rustc.tar.gz".to_string(),
+            std: Some("https://example.com/rust-std.tar.gz".to_string()),
+            cargo: None
+        };
+        let task = crate_build_task(&job, &installers, "https://crates.io/api/v1/crates");
+
+        assert_eq!(task.workerType, "cratertest");
+        assert_eq!(task.payload.maxRunTime, 600);
+        let env = &task.payload.env;
+        assert_eq!(env["CRATER_CRATE_FILE"], "https://crates.io/api/v1/crates/num/1.0.0/download");
+        assert_eq!(env["CRATER_STD_INSTALLER"], "https://example.com/rust-std.tar.gz");
+        assert!(!env.contains_key("CRATER_CARGO_INSTALLER"));
+        assert_eq!(env["CRATER_TASK_TYPE"], "crate-build");
+
+        // The engine has to be able to read back what was scheduled
+        assert_eq!(::task_info(&task.extra).unwrap(), ::TaskInfo::CrateBuild {
+            toolchain: job.toolchain.clone(),
+            crate_name: job.crate_name.clone(),
+            crate_vers: job.crate_vers.clone()
+        });
+    }
+}
@@ -0,0 +1,323 @@
+extern crate rustc_serialize;
+extern crate semver;
+
+use rustc_serialize::json;
+use semver::Version;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error as StdError;
+use std::fmt::{self, Display, Formatter};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+#[derive(RustcEncodable, RustcDecodable)]
+#[derive(Clone)]
+pub struct Config {
+    /// A local checkout of the crates.io index
+    pub index_dir: String
+}
+
+/// The checkout that crate-index.js keeps up to date
+impl Default for Config {
+    fn default() -> Config {
+        Config { index_dir: String::from("./cache/crate-index") }
+    }
+}
+
+/// A published revision of a crate. Each line of an index file is one
+/// of these.
+#[derive(RustcEncodable, RustcDecodable)]
+#[derive(Debug, Clone)]
+pub struct CrateVersion {
+    pub name: String,
+    pub vers: String,
+    pub deps: Vec<Dependency>,
+    pub features: BTreeMap<String, Vec<String>>,
+    pub yanked: bool
+}
+
+#[derive(RustcEncodable, RustcDecodable)]
+#[derive(Debug, Clone)]
+pub struct Dependency {
+    pub name: String,
+    pub req: String,
+    pub features: Vec<String>,
+    pub optional: bool,
+    pub default_features: bool,
+    pub target: Option<String>,
+    /// "normal", "build" or "dev". Missing from older entries.
+    pub kind: Option<String>
+}
+
+pub struct Index {
+    crates: Vec<CrateVersion>
+}
+
+impl Index {
+    /// Loads every crate revision from the index checkout in
+    /// `config.index_dir`
+    pub fn load(config: &Config) -> Result<Index, Error> {
+        let mut files = Vec::new();
+        try!(find_files(Path::new(&config.index_dir), true, &mut files));
+        files.sort();
+
+        let mut crates = Vec::new();
+        for path in files {
+            let mut file = try!(File::open(&path));
+            let mut s = String::new();
+            try!(file.read_to_string(&mut s));
+
+            for line in s.lines().filter(|l| !l.trim().is_empty()) {
+                let krate = try!(json::decode(line).map_err(|e| Error::Json(path.clone(), e)));
+                crates.push(krate);
+            }
+        }
+
+        Ok(Index { crates: crates })
+    }
+
+    pub fn from_versions(crates: Vec<CrateVersion>) -> Index {
+        Index { crates: crates }
+    }
+
+    /// Every revision of every crate
+    pub fn versions(&self) -> &[CrateVersion] {
+        &self.crates
+    }
+
+    /// The highest version of each crate, by semver ordering
+    pub fn most_recent_versions(&self) -> BTreeMap<&str, &CrateVersion> {
+        let mut map: BTreeMap<&str, &CrateVersion> = BTreeMap::new();
+        for krate in &self.crates {
+            let newer = match map.get(&*krate.name) {
+                Some(prev) => compare_versions(&prev.vers, &krate.vers) == Ordering::Less,
+                None => true
+            };
+            if newer {
+                map.insert(&krate.name, krate);
+            }
+        }
+
+        map
+    }
+
+    /// Maps each crate name to the names of its dependencies, of any
+    /// kind, in its most recent version. Older revisions may have had
+    /// different dependencies so this is only approximate.
+    pub fn dag(&self) -> BTreeMap<&str, Vec<&str>> {
+        self.most_recent_versions().into_iter().map(|(name, krate)| {
+            (name, krate.deps.iter().map(|d| &*d.name).collect())
+        }).collect()
+    }
+
+    /// Maps each crate name to the number of dependencies on it from
+    /// the most recent versions of other crates
+    pub fn reverse_dependency_counts(&self) -> BTreeMap<&str, u64> {
+        let mut counts: BTreeMap<&str, u64> = BTreeMap::new();
+        for krate in &self.crates {
+            counts.insert(&krate.name, 0);
+        }
+
+        for (_, deps) in self.dag() {
+            for dep in deps {
+                *counts.entry(dep).or_insert(0) += 1;
+            }
+        }
+
+        counts
+    }
+
+    /// Maps each crate name to the number of other crates that depend
+    /// on it directly or through other dependencies, going by the
+    /// most recent versions as in `dag`
+    pub fn transitive_reverse_dependency_counts(&self) -> BTreeMap<&str, u64> {
+        let mut rdeps: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+        for krate in &self.crates {
+            rdeps.entry(&krate.name).or_insert_with(Vec::new);
+        }
+        for (name, deps) in self.dag() {
+            for dep in deps {
+                rdeps.entry(dep).or_insert_with(Vec::new).push(name);
+            }
+        }
+
+        rdeps.keys().map(|&name| {
+            // Dependency cycles are possible through dev-dependencies
+            let mut seen = BTreeSet::new();
+            let mut stack = vec![name];
+            while let Some(n) = stack.pop() {
+                for &r in rdeps.get(n).map(|r| &r[..]).unwrap_or(&[]) {
+                    if r != name && seen.insert(r) {
+                        stack.push(r);
+                    }
+                }
+            }
+
+            (name, seen.len() as u64)
+        }).collect()
+    }
+
+    /// Every crate name, most depended-on first counting transitive
+    /// dependencies, with ties broken by name
+    pub fn popularity_ranking(&self) -> Vec<&str> {
+        // The counts come out ordered by name, and the sort is stable
+        let counts = self.transitive_reverse_dependency_counts();
+        let mut ranking: Vec<(&str, u64)> = counts.into_iter().collect();
+        ranking.sort_by(|a, b| b.1.cmp(&a.1));
+
+        ranking.into_iter().map(|(name, _)| name).collect()
+    }
+}
+
+/// Orders by semver, falling back to string comparison for the odd
+/// version that doesn't parse
+fn compare_versions(a: &str, b: &str) -> Ordering {
+    match (Version::parse(a), Version::parse(b)) {
+        (Ok(a), Ok(b)) => a.cmp(&b),
+        _ => a.cmp(b)
+    }
+}
+
+/// Finds the crate files in the index, skipping git metadata and
+/// the top-level files like config.json
+fn find_files(dir: &Path, top: bool, files: &mut Vec<PathBuf>) -> Result<(), Error> {
+    for entry in try!(fs::read_dir(dir)) {
+        let entry = try!(entry);
+        let path = entry.path();
+        if try!(entry.file_type()).is_dir() {
+            if entry.file_name().to_str().map(|n| n.starts_with(".")).unwrap_or(false) {
+                continue;
+            }
+            try!(find_files(&path, false, files));
+        } else if !top {
+            files.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Json(PathBuf, json::DecoderError)
+}
+
+impl StdError for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Io(_) => "I/O error reading the crate index",
+            Error::Json(..) => "malformed crate index entry"
+        }
+    }
+
+    fn cause(&self) -> Option<&StdError> {
+        match *self {
+            Error::Io(ref e) => Some(e as &StdError),
+            Error::Json(_, ref e) => Some(e as &StdError)
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            Error::Json(ref path, ref e) => {
+                write!(f, "{} in {}: {}", self.description(), path.display(), e)
+            }
+            _ => f.write_str(self.description())
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+// Tests expect the crates.io-index submodule to be checked out
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn load() -> Index {
+        let config = Config {
+            index_dir: format!("{}/../../test/crates.io-index", env!("CARGO_MANIFEST_DIR"))
+        };
+        Index::load(&config).unwrap()
+    }
+
+    #[test]
+    fn load_crates() {
+        assert_eq!(load().versions().len(), 8827);
+    }
+
+    #[test]
+    fn most_recent_versions() {
+        let index = load();
+        let recent = index.most_recent_versions();
+        assert_eq!(recent["toml"].vers, "0.1.20");
+        assert_eq!(recent["obj-rs"].vers, "0.4.7");
+    }
+
+    #[test]
+    fn dag() {
+        let index = load();
+        let dag = index.dag();
+        assert_eq!(dag["piston"][0], "pistoncore-input");
+    }
+
+    #[test]
+    fn reverse_dependency_counts() {
+        let index = load();
+        let counts = index.reverse_dependency_counts();
+        assert_eq!(counts["time"], 92);
+        assert_eq!(counts["num"], 66);
+        assert_eq!(counts["piston"], 1);
+    }
+
+    fn krate(name: &str, deps: &[&str]) -> CrateVersion {
+        CrateVersion {
+            name: String::from(name),
+            vers: String::from("1.0.0"),
+            deps: deps.iter().map(|&dep| Dependency {
+                name: String::from(dep),
+                req: String::from("*"),
+                features: Vec::new(),
+                optional: false,
+                default_features: true,
+                target: None,
+                kind: None
+            }).collect(),
+            features: BTreeMap::new(),
+            yanked: false
+        }
+    }
+
+    #[test]
+    fn transitive_popularity() {
+        // libc has one direct user but three in all; the crates with
+        // one user between them are ordered by name
+        let index = Index::from_versions(vec![
+            krate("libc", &[]),
+            krate("rand", &["libc"]),
+            krate("log", &[]),
+            krate("num", &["rand"]),
+            krate("app", &["num", "log"]),
+            // A dev-dependency cycle
+            krate("a", &["b"]),
+            krate("b", &["a"]),
+        ]);
+        let counts = index.transitive_reverse_dependency_counts();
+        assert_eq!(counts["libc"], 3);
+        assert_eq!(counts["rand"], 2);
+        assert_eq!(counts["log"], 1);
+        assert_eq!(counts["a"], 1);
+        assert_eq!(counts["app"], 0);
+        assert_eq!(index.popularity_ranking(),
+                   vec!["libc", "rand", "a", "b", "log", "num", "app"]);
+    }
+}
@@ -0,0 +1,278 @@
+//! Picks out the parts of build logs worth keeping, and what went
+//! wrong
+
+extern crate crater_api as api;
+
+use api::Diagnostic;
+
+/// How many error lines to keep from the start of a log
+pub const ERROR_LINES: usize = 50;
+
+/// How many lines to keep from the end of a log
+pub const TAIL_LINES: usize = 100;
+
+/// An internal compiler error
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ice {
+    pub message: String,
+    /// Where rustc panicked, e.g. `../src/librustc/middle/ty.rs:1234`.
+    /// Only known for ICEs that are panics.
+    pub location: Option<String>
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Excerpt {
+    /// The first `ERROR_LINES` lines that look like errors or panics,
+    /// then the first line of each later ICE so that ICE reports,
+    /// which only see the excerpt, don't miss any
+    pub errors: String,
+    /// The last `TAIL_LINES` lines
+    pub tail: String
+}
+
+pub fn excerpt(log: &str) -> Excerpt {
+    let lines: Vec<&str> = log.lines().collect();
+    let mut errors: Vec<&str> = Vec::new();
+    let mut seen_ices = Vec::new();
+    for line in lines.iter().cloned().filter(|l| is_error_line(l)) {
+        let ice = ices(line).pop();
+        let new_ice = match ice {
+            Some(ref ice) => !seen_ices.contains(ice),
+            None => false
+        };
+        if errors.len() < ERROR_LINES || new_ice {
+            errors.push(line);
+            if let Some(ice) = ice {
+                seen_ices.push(ice);
+            }
+        }
+    }
+    let tail = &lines[lines.len().saturating_sub(TAIL_LINES)..];
+
+    Excerpt {
+        errors: errors.join("\n"),
+        tail: tail.join("\n")
+    }
+}
+
+/// The errors and panics in a log, in the order they first appear.
+/// rustc's closing "aborting due to" and cargo's "Could not compile"
+/// are left out since every failed build has them.
+pub fn diagnostics(log: &str) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    for line in log.lines() {
+        if let Some(d) = diagnostic(line) {
+            if !diagnostics.contains(&d) {
+                diagnostics.push(d);
+            }
+        }
+    }
+
+    diagnostics
+}
+
+/// The internal compiler errors in a log, in the order they first
+/// appear. Both rustc panics and `error: internal compiler error: ...`
+/// lines count; the `Box<Any>` panic that follows the latter says
+/// nothing more and is skipped.
+pub fn ices(log: &str) -> Vec<Ice> {
+    let mut ices = Vec::new();
+    for line in log.lines() {
+        let line = line.trim();
+        let ice = match parse_panic(line) {
+            Some(ref p) if p.thread == "rustc" && p.message != "Box<Any>" => Ice {
+                message: String::from(p.message),
+                location: p.location.map(String::from)
+            },
+            Some(_) => continue,
+            None => match diagnostic(line) {
+                Some(Diagnostic::Ice(msg)) => Ice { message: msg, location: None },
+                _ => continue
+            }
+        };
+        if !ices.contains(&ice) {
+            ices.push(ice);
+        }
+    }
+
+    ices
+}
+
+fn diagnostic(line: &str) -> Option<Diagnostic> {
+    let line = line.trim();
+
+    if let Some(panic) = parse_panic(line) {
+        if panic.thread == "rustc" {
+            // Follows an `internal compiler error` line, which has
+            // the message
+            if panic.message == "Box<Any>" {
+                return None;
+            }
+            return Some(Diagnostic::Ice(String::from(panic.message)));
+        }
+        return Some(Diagnostic::Panic(String::from(panic.message)));
+    }
+
+    let error = match error_message(line) {
+        Some(error) => error,
+        None => return None
+    };
+
+    if error.starts_with("[") {
+        return error.find(']').map(|end| Diagnostic::ErrorCode(String::from(&error[1..end])));
+    }
+
+    let msg = error[1..].trim();
+    if msg.starts_with("internal compiler error: ") {
+        let msg = &msg["internal compiler error: ".len()..];
+        // The panic that follows says more
+        if msg == "unexpected panic" {
+            return None;
+        }
+        return Some(Diagnostic::Ice(String::from(msg)));
+    }
+    if msg.starts_with("aborting due to") || msg.starts_with("Could not compile") {
+        return None;
+    }
+
+    Some(Diagnostic::Error(String::from(msg)))
+}
+
+/// What follows `error` in an `error: ...` or `error[E0000]: ...` line,
+/// possibly prefixed by a source location
+fn error_message(line: &str) -> Option<&str> {
+    if line.starts_with("error:") || line.starts_with("error[") {
+        return Some(&line["error".len()..]);
+    }
+
+    [" error: ", " error["].iter()
+        .filter_map(|&pat| line.find(pat))
+        .min()
+        .map(|i| &line[i + " error".len()..])
+}
+
+struct Panic<'a> {
+    thread: &'a str,
+    message: &'a str,
+    location: Option<&'a str>
+}
+
+/// Parses `thread 'name' panicked at 'message', file:line`
+fn parse_panic(line: &str) -> Option<Panic> {
+    let (thread, rest) = match (line.find("thread '"), line.find("' panicked at '")) {
+        (Some(i), Some(j)) if i < j => {
+            (&line[i + "thread '".len()..j], &line[j + "' panicked at '".len()..])
+        }
+        _ => return None
+    };
+    let (message, location) = match rest.rfind("', ") {
+        Some(i) => (&rest[..i], Some(rest[i + "', ".len()..].trim())),
+        None => (rest.trim_right_matches('\''), None)
+    };
+
+    Some(Panic {
+        thread: thread,
+        message: message,
+        location: location
+    })
+}
+
+/// Matches rustc's `error: ...` and `error[E0000]: ...` lines, with or
+/// without the older `file:line:col: ` prefix, and panics from rustc
+/// or build scripts
+fn is_error_line(line: &str) -> bool {
+    let line = line.trim_left();
+    line.starts_with("error:") || line.starts_with("error[") ||
+        line.contains(" error: ") || line.contains(" error[") ||
+        line.contains("panicked at")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use api::Diagnostic;
+
+    #[test]
+    fn excerpt_errors() {
+        let log = "   Compiling num v0.1.0\n\
+                   src/lib.rs:1:5: 1:8 error: unresolved import `foo`\n\
+                   error[E0277]: the trait bound `T: Copy` is not satisfied\n\
+                   thread 'rustc' panicked at 'oops', src/librustc/lib.rs:1\n\
+                   error: aborting due to 2 previous errors\n\
+                   Could not compile `num`.";
+        let e = excerpt(log);
+        assert_eq!(e.errors, "src/lib.rs:1:5: 1:8 error: unresolved import `foo`\n\
+                              error[E0277]: the trait bound `T: Copy` is not satisfied\n\
+                              thread 'rustc' panicked at 'oops', src/librustc/lib.rs:1\n\
+                              error: aborting due to 2 previous errors");
+        assert_eq!(e.tail, log);
+    }
+
+    #[test]
+    fn excerpt_long_log() {
+        let lines: Vec<String> = (0..1000).map(|i| format!("error: {}", i)).collect();
+        let e = excerpt(&lines.join("\n"));
+        assert_eq!(e.errors, lines[..ERROR_LINES].join("\n"));
+        assert_eq!(e.tail, lines[1000 - TAIL_LINES..].join("\n"));
+    }
+
+    #[test]
+    fn excerpt_keeps_late_ices() {
+        let mut lines: Vec<String> = (0..1000).map(|i| format!("error: {}", i)).collect();
+        let ice = "thread 'rustc' panicked at 'oops', src/librustc/lib.rs:1";
+        lines[500] = ice.to_string();
+        lines[600] = ice.to_string();
+        let e = excerpt(&lines.join("\n"));
+        assert_eq!(e.errors, format!("{}\n{}", lines[..ERROR_LINES].join("\n"), ice));
+    }
+
+    #[test]
+    fn diagnostics_in_order() {
+        let log = "   Compiling num v0.1.0\n\
+                   src/lib.rs:1:5: 1:8 error: unresolved import `foo`\n\
+                   src/lib.rs:2:5: 2:8 error[E0277]: the trait bound `T: Copy` is not satisfied\n\
+                   error[E0277]: the trait bound `U: Copy` is not satisfied\n\
+                   error: aborting due to 3 previous errors\n\
+                   Could not compile `num`.";
+        assert_eq!(diagnostics(log), vec![
+            Diagnostic::Error(String::from("unresolved import `foo`")),
+            Diagnostic::ErrorCode(String::from("E0277"))
+        ]);
+    }
+
+    #[test]
+    fn ices_by_location() {
+        let log = "error: internal compiler error: unexpected panic\n\
+                   thread 'rustc' panicked at 'assertion failed: a == b', \
+                   ../src/librustc/middle/ty.rs:1234\n\
+                   thread '<main>' panicked at 'no OUT_DIR', build.rs:3\n\
+                   src/lib.rs:3:1: 3:5 error: internal compiler error: no type for node 12\n\
+                   thread 'rustc' panicked at 'Box<Any>', ../src/libsyntax/diagnostic.rs:176";
+        assert_eq!(ices(log), vec![
+            Ice {
+                message: String::from("assertion failed: a == b"),
+                location: Some(String::from("../src/librustc/middle/ty.rs:1234"))
+            },
+            Ice {
+                message: String::from("no type for node 12"),
+                location: None
+            }
+        ]);
+    }
+
+    #[test]
+    fn diagnostics_of_panics() {
+        let log = "error: internal compiler error: unexpected panic\n\
+                   note: the compiler unexpectedly panicked. this is a bug.\n\
+                   thread 'rustc' panicked at 'assertion failed: `(left == right)`', \
+                   ../src/librustc/middle/ty.rs:1234\n\
+                   thread '<main>' panicked at 'no OUT_DIR', build.rs:3\n\
+                   error: internal compiler error: no type for node 12\n\
+                   thread 'rustc' panicked at 'Box<Any>', ../src/libsyntax/errors/mod.rs:523";
+        assert_eq!(diagnostics(log), vec![
+            Diagnostic::Ice(String::from("assertion failed: `(left == right)`")),
+            Diagnostic::Panic(String::from("no OUT_DIR")),
+            Diagnostic::Ice(String::from("no type for node 12"))
+        ]);
+    }
+}
@@ -0,0 +1,446 @@
+extern crate rustc_serialize;
+extern crate crater_api as api;
+extern crate crater_db as db;
+extern crate crater_logs as logs;
+
+use api::{BuildStatus, Diagnostic, Toolchain};
+use api::v1::{ComparisonReport, ComparisonStatus, CrateComparison, DependentRegression,
+              Ice, IceCrate, IceReport, RegressedCrate, RegressionGroup, StatusSummary,
+              TaskResult};
+use db::{BuildLog, BuildResultPair, Database, DepEdge, Page};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error::Error as StdError;
+
+/// Compares the results of every crate revision built with both
+/// toolchains
+pub fn comparison_report(db: &Database, toolchain_from: &Toolchain, toolchain_to: &Toolchain
+                         ) -> Result<ComparisonReport, Box<StdError>> {
+    let pairs = try!(db.get_result_pairs(toolchain_from, toolchain_to, Page::all()));
+    let crates: Vec<CrateComparison> = pairs.into_iter().map(compare).collect();
+    let summary = summarize(&crates);
+
+    let graph = DepGraph::from_edges(try!(db.get_dep_edges()));
+    let RootRegressions { roots, dependent } = {
+        let regressed: Vec<&str> = crates.iter()
+            .filter(|c| c.status == ComparisonStatus::Regressed)
+            .map(|c| &*c.crate_name)
+            .collect();
+        graph.root_regressions(&regressed)
+    };
+
+    let groups = {
+        let regressed: Vec<&CrateComparison> = crates.iter()
+            .filter(|c| c.status == ComparisonStatus::Regressed)
+            .collect();
+        group_regressions(&regressed,
+                          &try!(db.get_toolchain_diagnostics(toolchain_from)),
+                          &try!(db.get_toolchain_diagnostics(toolchain_to)))
+    };
+
+    Ok(ComparisonReport {
+        toolchain_from: toolchain_from.clone(),
+        toolchain_to: toolchain_to.clone(),
+        summary: summary,
+        crates: crates,
+        root_regressions: roots,
+        dependent_regressions: dependent,
+        regression_groups: groups
+    })
+}
+
+/// Diagnostics by crate name and version
+pub type CrateDiagnostics = BTreeMap<(String, String), Vec<Diagnostic>>;
+
+/// Groups regressed crates by the first diagnostic of their 'to' build
+/// that their 'from' build didn't have, preferring ICEs. Crates
+/// without one, e.g. because their logs are missing, aren't in any
+/// group. Bigger groups come first.
+pub fn group_regressions(regressed: &[&CrateComparison],
+                         from: &CrateDiagnostics, to: &CrateDiagnostics) -> Vec<RegressionGroup> {
+    let none = Vec::new();
+    let mut groups = BTreeMap::new();
+    for c in regressed {
+        let key = (c.crate_name.clone(), c.crate_vers.clone());
+        let old = from.get(&key).unwrap_or(&none);
+        let new: Vec<&Diagnostic> = to.get(&key).unwrap_or(&none).iter()
+            .filter(|d| !old.contains(*d))
+            .collect();
+        let ice = new.iter().cloned().find(|d| match **d {
+            Diagnostic::Ice(_) => true,
+            _ => false
+        });
+
+        if let Some(d) = ice.or(new.first().cloned()) {
+            groups.entry(d.clone()).or_insert_with(Vec::new).push(RegressedCrate {
+                crate_name: c.crate_name.clone(),
+                crate_vers: c.crate_vers.clone()
+            });
+        }
+    }
+
+    let mut groups: Vec<RegressionGroup> = groups.into_iter().map(|(d, crates)| {
+        RegressionGroup { diagnostic: d, crates: crates }
+    }).collect();
+    groups.sort_by(|a, b| b.crates.len().cmp(&a.crates.len()));
+
+    groups
+}
+
+/// Matches the log lines `logs::ices` looks for
+const ICE_PATTERN: &'static str = "internal compiler error|thread 'rustc' panicked";
+
+/// Finds the internal compiler errors in the stored logs of one
+/// toolchain's builds
+pub fn ice_report(db: &Database, toolchain: &Toolchain) -> Result<IceReport, Box<StdError>> {
+    let build_logs = try!(db.search_build_logs(toolchain, ICE_PATTERN));
+
+    Ok(IceReport {
+        toolchain: toolchain.clone(),
+        ices: group_ices(&build_logs)
+    })
+}
+
+/// Collects the crates that hit each ICE, keeping the order of `build_logs`
+/// within each. ICEs are the same if rustc panicked in the same place
+/// or, when that isn't known, have the same message. Only the stored
+/// excerpts are searched, but they keep every distinct ICE of the log.
+pub fn group_ices(build_logs: &[BuildLog]) -> Vec<Ice> {
+    let mut ices: Vec<Ice> = Vec::new();
+    for log in build_logs {
+        // The panic can be in either part of the excerpt
+        let mut found = logs::ices(&log.errors);
+        for ice in logs::ices(&log.tail) {
+            if !found.contains(&ice) {
+                found.push(ice);
+            }
+        }
+
+        for ice in found {
+            let krate = IceCrate {
+                crate_name: log.crate_name.clone(),
+                crate_vers: log.crate_vers.clone(),
+                task_id: log.task_id.clone()
+            };
+            match ices.iter().position(|i| same_ice(i, &ice)) {
+                Some(i) => {
+                    if !ices[i].crates.contains(&krate) {
+                        ices[i].crates.push(krate);
+                    }
+                }
+                None => ices.push(Ice {
+                    message: ice.message,
+                    location: ice.location,
+                    crates: vec![krate]
+                })
+            }
+        }
+    }
+    ices.sort_by(|a, b| b.crates.len().cmp(&a.crates.len()));
+
+    ices
+}
+
+fn same_ice(a: &Ice, b: &logs::Ice) -> bool {
+    match (&a.location, &b.location) {
+        (&Some(ref a), &Some(ref b)) => a == b,
+        (&None, &None) => a.message == b.message,
+        _ => false
+    }
+}
+
+/// Classifies a crate by its build status under the 'from' and 'to'
+/// toolchains. An exception on either side takes precedence over an
+/// unknown status, since it's worth retrying.
+pub fn classify(status_from: BuildStatus, status_to: BuildStatus) -> ComparisonStatus {
+    use api::BuildStatus::*;
+
+    match (status_from, status_to) {
+        (Success, Success) => ComparisonStatus::Working,
+        (Failure, Failure) => ComparisonStatus::Broken,
+        (Success, Failure) => ComparisonStatus::Regressed,
+        (Failure, Success) => ComparisonStatus::Fixed,
+        (Exception, _) | (_, Exception) => ComparisonStatus::Exception,
+        (Unknown, _) | (_, Unknown) => ComparisonStatus::Unknown
+    }
+}
+
+/// Maps crate names to the names of their direct dependencies
+pub struct DepGraph {
+    deps: HashMap<String, Vec<String>>
+}
+
+pub struct RootRegressions {
+    pub roots: Vec<String>,
+    pub dependent: Vec<DependentRegression>
+}
+
+impl DepGraph {
+    pub fn new() -> DepGraph {
+        DepGraph { deps: HashMap::new() }
+    }
+
+    pub fn from_edges(edges: Vec<DepEdge>) -> DepGraph {
+        let mut graph = DepGraph::new();
+        for edge in edges {
+            graph.add_edge(edge.name, edge.dep);
+        }
+
+        graph
+    }
+
+    pub fn add_edge(&mut self, name: String, dep: String) {
+        self.deps.entry(name).or_insert_with(Vec::new).push(dep);
+    }
+
+    /// Splits regressed crates into those that are regressions in
+    /// their own right and those with a regressed crate somewhere
+    /// in their transitive dependencies. Results keep the order of
+    /// `regressed`.
+    pub fn root_regressions(&self, regressed: &[&str]) -> RootRegressions {
+        let regressed_set: HashSet<&str> = regressed.iter().cloned().collect();
+
+        let mut roots = Vec::new();
+        let mut dependent = Vec::new();
+        for &name in regressed {
+            match self.find_regressed_dep(name, &regressed_set) {
+                Some(dep) => {
+                    dependent.push(DependentRegression {
+                        crate_name: name.to_string(),
+                        regressed_dep: dep.to_string()
+                    });
+                }
+                None => roots.push(name.to_string())
+            }
+        }
+
+        RootRegressions {
+            roots: roots,
+            dependent: dependent
+        }
+    }
+
+    /// Does a depth-first search of the dependencies of `name`,
+    /// returning the first one found in `regressed`
+    fn find_regressed_dep<'a>(&'a self, name: &str, regressed: &HashSet<&str>
+                              ) -> Option<&'a str> {
+        let mut visited = HashSet::new();
+        let mut stack: Vec<&str> = self.direct_deps(name).iter().rev().map(|d| &**d).collect();
+
+        visited.insert(name);
+        while let Some(dep) = stack.pop() {
+            if !visited.insert(dep) {
+                continue;
+            }
+            if regressed.contains(dep) {
+                return Some(dep);
+            }
+            stack.extend(self.direct_deps(dep).iter().rev().map(|d| &**d));
+        }
+
+        None
+    }
+
+    fn direct_deps(&self, name: &str) -> &[String] {
+        self.deps.get(name).map(|d| &d[..]).unwrap_or(&[])
+    }
+}
+
+fn compare(pair: BuildResultPair) -> CrateComparison {
+    let BuildResultPair { from, to } = pair;
+    CrateComparison {
+        status: classify(from.status, to.status),
+        crate_name: from.crate_name,
+        crate_vers: from.crate_vers,
+        from: TaskResult {
+            status: from.status,
+            task_id: from.task_id
+        },
+        to: TaskResult {
+            status: to.status,
+            task_id: to.task_id
+        }
+    }
+}
+
+fn summarize(crates: &[CrateComparison]) -> StatusSummary {
+    let mut summary = StatusSummary::default();
+    for c in crates {
+        match c.status {
+            ComparisonStatus::Working => summary.working += 1,
+            ComparisonStatus::Broken => summary.broken += 1,
+            ComparisonStatus::Regressed => summary.regressed += 1,
+            ComparisonStatus::Fixed => summary.fixed += 1,
+            ComparisonStatus::Exception => summary.exception += 1,
+            ComparisonStatus::Unknown => summary.unknown += 1
+        }
+    }
+
+    summary
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use api::BuildStatus::*;
+    use api::Diagnostic;
+    use api::v1::{ComparisonStatus, CrateComparison, Ice, RegressedCrate, RegressionGroup,
+                  TaskResult};
+    use db::BuildLog;
+
+    #[test]
+    fn classify_known_statuses() {
+        assert_eq!(classify(Success, Success), ComparisonStatus::Working);
+        assert_eq!(classify(Failure, Failure), ComparisonStatus::Broken);
+        assert_eq!(classify(Success, Failure), ComparisonStatus::Regressed);
+        assert_eq!(classify(Failure, Success), ComparisonStatus::Fixed);
+    }
+
+    #[test]
+    fn classify_exceptions() {
+        assert_eq!(classify(Exception, Success), ComparisonStatus::Exception);
+        assert_eq!(classify(Failure, Exception), ComparisonStatus::Exception);
+        assert_eq!(classify(Exception, Unknown), ComparisonStatus::Exception);
+    }
+
+    #[test]
+    fn classify_unknowns() {
+        assert_eq!(classify(Success, Unknown), ComparisonStatus::Unknown);
+        assert_eq!(classify(Unknown, Failure), ComparisonStatus::Unknown);
+        assert_eq!(classify(Unknown, Unknown), ComparisonStatus::Unknown);
+    }
+
+    fn graph(edges: &[(&str, &str)]) -> DepGraph {
+        let mut graph = DepGraph::new();
+        for &(name, dep) in edges {
+            graph.add_edge(name.to_string(), dep.to_string());
+        }
+        graph
+    }
+
+    fn causes(r: &RootRegressions) -> Vec<(&str, &str)> {
+        r.dependent.iter().map(|d| (&*d.crate_name, &*d.regressed_dep)).collect()
+    }
+
+    #[test]
+    fn independent_regressions_are_roots() {
+        let g = graph(&[("a", "b"), ("c", "d")]);
+        let r = g.root_regressions(&["a", "c"]);
+        assert_eq!(r.roots, vec!["a", "c"]);
+        assert!(r.dependent.is_empty());
+    }
+
+    #[test]
+    fn direct_dep_regression() {
+        let g = graph(&[("a", "b"), ("b", "c")]);
+        let r = g.root_regressions(&["a", "b"]);
+        assert_eq!(r.roots, vec!["b"]);
+        assert_eq!(causes(&r), vec![("a", "b")]);
+    }
+
+    #[test]
+    fn transitive_dep_regression() {
+        // Only the bottom of the chain is regressed along with the top
+        let g = graph(&[("a", "b"), ("b", "c"), ("c", "d")]);
+        let r = g.root_regressions(&["a", "d"]);
+        assert_eq!(r.roots, vec!["d"]);
+        assert_eq!(causes(&r), vec![("a", "d")]);
+    }
+
+    #[test]
+    fn diamond_and_cycle() {
+        let g = graph(&[("a", "b"), ("a", "c"), ("b", "d"), ("c", "d"),
+                        ("d", "a"), ("e", "e")]);
+        let r = g.root_regressions(&["a", "d", "e"]);
+        assert_eq!(r.roots, vec!["e"]);
+        assert_eq!(causes(&r), vec![("a", "d"), ("d", "a")]);
+    }
+
+    #[test]
+    fn unknown_crates_are_roots() {
+        let g = graph(&[]);
+        let r = g.root_regressions(&["a"]);
+        assert_eq!(r.roots, vec!["a"]);
+    }
+
+    fn regression(name: &str) -> CrateComparison {
+        CrateComparison {
+            crate_name: name.to_string(),
+            crate_vers: "1.0.0".to_string(),
+            status: ComparisonStatus::Regressed,
+            from: TaskResult { status: Success, task_id: "from".to_string() },
+            to: TaskResult { status: Failure, task_id: "to".to_string() }
+        }
+    }
+
+    fn diagnostics(crates: &[(&str, Vec<Diagnostic>)]) -> CrateDiagnostics {
+        crates.iter().map(|&(name, ref d)| {
+            ((name.to_string(), "1.0.0".to_string()), d.clone())
+        }).collect()
+    }
+
+    #[test]
+    fn group_regressions_by_new_diagnostic() {
+        let e0277 = Diagnostic::ErrorCode("E0277".to_string());
+        let e0425 = Diagnostic::ErrorCode("E0425".to_string());
+        let ice = Diagnostic::Ice("no type for node 12".to_string());
+        let crates: Vec<CrateComparison> = ["a", "b", "c", "d", "e"].iter()
+            .map(|n| regression(n))
+            .collect();
+        let regressed: Vec<&CrateComparison> = crates.iter().collect();
+
+        let from = diagnostics(&[("c", vec![e0425.clone()])]);
+        let to = diagnostics(&[("a", vec![e0277.clone()]),
+                               ("b", vec![e0425.clone(), e0277.clone()]),
+                               ("c", vec![e0425.clone(), e0277.clone()]),
+                               ("d", vec![e0277.clone(), ice.clone()])]);
+        let groups = group_regressions(&regressed, &from, &to);
+
+        let krate = |name: &str| RegressedCrate {
+            crate_name: name.to_string(),
+            crate_vers: "1.0.0".to_string()
+        };
+        assert_eq!(groups, vec![
+            RegressionGroup { diagnostic: e0277, crates: vec![krate("a"), krate("c")] },
+            RegressionGroup { diagnostic: e0425, crates: vec![krate("b")] },
+            RegressionGroup { diagnostic: ice, crates: vec![krate("d")] },
+        ]);
+    }
+
+    fn build_log(name: &str, errors: &str, tail: &str) -> BuildLog {
+        BuildLog {
+            toolchain: "nightly-2015-01-01".parse().unwrap(),
+            crate_name: name.to_string(),
+            crate_vers: "1.0.0".to_string(),
+            task_id: format!("task-{}", name),
+            errors: errors.to_string(),
+            tail: tail.to_string()
+        }
+    }
+
+    fn ice_crates(ice: &Ice) -> Vec<&str> {
+        ice.crates.iter().map(|c| &*c.crate_name).collect()
+    }
+
+    #[test]
+    fn group_ices_by_location() {
+        let assertion = "thread 'rustc' panicked at 'assertion failed', ../src/librustc/ty.rs:12";
+        let logs = vec![
+            build_log("a", "error: internal compiler error: no type for node 12", ""),
+            build_log("b", "", assertion),
+            build_log("c", assertion,
+                      &format!("{}\nthread 'rustc' panicked at 'other message', \
+                                ../src/librustc/ty.rs:12", assertion)),
+            build_log("d", "error: internal compiler error: no type for node 12", ""),
+            build_log("e", "thread 'rustc' panicked at 'oops', ../src/librustc/lint.rs:3", ""),
+        ];
+        let ices = group_ices(&logs);
+
+        assert_eq!(ices.len(), 3);
+        assert_eq!(ices[0].location, None);
+        assert_eq!(ices[0].message, "no type for node 12");
+        assert_eq!(ice_crates(&ices[0]), vec!["a", "d"]);
+        assert_eq!(ices[1].location, Some("../src/librustc/ty.rs:12".to_string()));
+        assert_eq!(ice_crates(&ices[1]), vec!["b", "c"]);
+        assert_eq!(ice_crates(&ices[2]), vec!["e"]);
+    }
+}
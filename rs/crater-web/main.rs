@@ -9,28 +9,44 @@ extern crate crater_db as db;
 extern crate rustc_serialize;
 extern crate crater_engine as engine;
 extern crate crater_api as api;
-
+extern crate crater_index as index;
+extern crate crater_reports as reports;
+
+use api::{v1, v2};
+use ctxt::{Ctxt, Quotas};
+use db::User;
+use iron::BeforeMiddleware;
+use iron::headers::Authorization;
 use iron::mime::Mime;
 use iron::prelude::*;
 use iron::status;
+use iron::typemap::Key;
 use mount::Mount;
 use router::Router;
+use rustc_serialize::Decodable;
 use rustc_serialize::json;
 use std::convert::From;
 use std::error::Error as StdError;
 use std::fmt::{self, Display, Formatter};
 use std::fs::File;
 use std::io::{self, Read};
+use std::process;
 use std::sync::Arc;
 use std::thread;
 
+/// Audit log entries per page when the request doesn't say
+const DEFAULT_AUDIT_LIMIT: u64 = 50;
+
 #[derive(RustcEncodable, RustcDecodable)]
 struct Config {
     host: String,
     port: u16,
     db: db::Config,
+    /// Defaults to the checkout crate-index.js keeps
+    index: Option<index::Config>,
     engine: engine::Config,
-    users: Vec<(String, String)>
+    /// Defaults to no limits
+    quotas: Option<Quotas>
 }
 
 fn main() {
@@ -44,7 +60,7 @@ fn run() -> Result<(), Error> {
 
     // Start the job engine that listens to the pulse server, creates
     // taskcluster tasks, and updates the database with results.
-    try!(start_engine(config.engine.clone()));
+    try!(start_engine(config.engine.clone(), &config.db));
 
     // Blocks until the process is killed
     run_web_server(&config)
@@ -62,24 +78,35 @@ fn load_config() -> Result<Config, Error> {
     return Ok(try!(json::decode(&s)));
 }
 
-fn start_engine(engine_config: engine::Config) -> Result<(), Error> {
-
-    let engine = try!(engine::initialize(engine_config));
+fn start_engine(engine_config: engine::Config, db_config: &db::Config) -> Result<(), Error> {
+    // The engine gets its own connection
+    let db = try!(db::Database::connect(db_config));
+    let engine = try!(engine::initialize(engine_config, db));
 
+    // Without the engine results stop being recorded, so rather than
+    // carry on serving, exit and leave restarting to the supervisor
     thread::spawn(|| {
-        // FIXME: error handling
-        engine.run().unwrap();
+        match engine.run() {
+            Ok(()) => error!("engine stopped: message bus disconnected"),
+            Err(e) => error!("engine failed: {}", e)
+        }
+        process::exit(1);
     });
 
     Ok(())
 }
 
 fn run_web_server(config: &Config) -> Result<(), Error> {
+    let db = try!(db::Database::connect(&config.db));
+
     let static_router = static_router();
-    let api_router_v1 = api_router_v1(config.users.clone());
+    let scheduler = engine::scheduler(&config.engine, &config.db);
+    let api_ctxt = Arc::new(Ctxt::new(db, config.index.clone().unwrap_or_default(), scheduler,
+                                      config.quotas.clone().unwrap_or_default()));
 
     let mut mount = Mount::new();
-    mount.mount("/api/v1/", api_router_v1);
+    mount.mount("/api/v1/", api_router_v1(api_ctxt.clone()));
+    mount.mount("/api/v2/", api_chain_v2(api_ctxt));
     mount.mount("/", static_router);
 
     let addr = format!("{}:{}", config.host, config.port);
@@ -88,42 +115,340 @@ fn run_web_server(config: &Config) -> Result<(), Error> {
     return Ok(());
 }
 
-fn api_router_v1(users: Vec<(String, String)>) -> Router {
-    let api_ctxt_master = Arc::new(api_v1::Ctxt::new(users));
+/// The v1 API takes credentials in the body of every request
+fn api_router_v1(api_ctxt_master: Arc<Ctxt>) -> Router {
     let mut router = Router::new();
 
     let api_ctxt = api_ctxt_master.clone();
     router.post("/custom_build", move |r: &mut Request| {
-        let mut body = String::new();
-        try!(r.body.read_to_string(&mut body).map_err(|e| Error::from(e)));
-        let payload = try!(api_ctxt.custom_build(&body));
-        Ok(Response::with((status::Ok, payload)).set(known_mime_type("application/json")))
+        let req: v1::CustomBuildRequest = try!(read_json(r));
+        let ref user = try!(api_ctxt.authenticate(&req.auth.name, &req.auth.token));
+        let payload = try!(api_ctxt.custom_build(user, &v2::CustomBuildRequest {
+            repo_url: req.repo_url,
+            commit_sha: req.commit_sha
+        }));
+        Ok(json_response(payload))
     });
     let api_ctxt = api_ctxt_master.clone();
     router.post("/crate_build", move |r: &mut Request| {
-        let mut body = String::new();
-        try!(r.body.read_to_string(&mut body).map_err(|e| Error::from(e)));
-        let payload = try!(api_ctxt.crate_build(&body));
-        Ok(Response::with((status::Ok, payload)).set(known_mime_type("application/json")))
+        let req: v1::CrateBuildRequest = try!(read_json(r));
+        let ref user = try!(api_ctxt.authenticate(&req.auth.name, &req.auth.token));
+        let payload = try!(api_ctxt.crate_build(user, &v2::CrateBuildRequest {
+            toolchain: req.toolchain,
+            selection: req.selection.unwrap_or_default()
+        }));
+        Ok(json_response(payload))
     });
     let api_ctxt = api_ctxt_master.clone();
     router.post("/report", move |r: &mut Request| {
-        let mut body = String::new();
-        try!(r.body.read_to_string(&mut body).map_err(|e| Error::from(e)));
-        let payload = try!(api_ctxt.report(&body));
-        Ok(Response::with((status::Ok, payload)).set(known_mime_type("application/json")))
+        let req: v1::ReportRequest = try!(read_json(r));
+        let ref user = try!(api_ctxt.authenticate(&req.auth.name, &req.auth.token));
+        let payload = try!(api_ctxt.report(user, &req.kind));
+        Ok(json_response(payload))
+    });
+    let api_ctxt = api_ctxt_master.clone();
+    router.post("/sync_index", move |r: &mut Request| {
+        let req: v1::SyncIndexRequest = try!(read_json(r));
+        let ref user = try!(api_ctxt.authenticate(&req.auth.name, &req.auth.token));
+        let payload = try!(api_ctxt.sync_index(user));
+        Ok(json_response(payload))
+    });
+    let api_ctxt = api_ctxt_master.clone();
+    router.post("/experiments/create", move |r: &mut Request| {
+        let req: v1::CreateExperimentRequest = try!(read_json(r));
+        let ref user = try!(api_ctxt.authenticate(&req.auth.name, &req.auth.token));
+        let payload = try!(api_ctxt.create_experiment(user, &v2::CreateExperimentRequest {
+            name: req.name,
+            toolchain_from: req.toolchain_from,
+            toolchain_to: req.toolchain_to,
+            selection: req.selection
+        }));
+        Ok(json_response(payload))
+    });
+    let api_ctxt = api_ctxt_master.clone();
+    router.post("/experiments/list", move |r: &mut Request| {
+        let req: v1::ListExperimentsRequest = try!(read_json(r));
+        let ref user = try!(api_ctxt.authenticate(&req.auth.name, &req.auth.token));
+        let payload = try!(api_ctxt.list_experiments(user));
+        Ok(json_response(payload))
+    });
+    let api_ctxt = api_ctxt_master.clone();
+    router.post("/experiments/show", move |r: &mut Request| {
+        let req: v1::ExperimentRequest = try!(read_json(r));
+        let ref user = try!(api_ctxt.authenticate(&req.auth.name, &req.auth.token));
+        let payload = try!(api_ctxt.show_experiment(user, &req.name));
+        Ok(json_response(payload))
+    });
+    let api_ctxt = api_ctxt_master.clone();
+    router.post("/experiments/delete", move |r: &mut Request| {
+        let req: v1::ExperimentRequest = try!(read_json(r));
+        let ref user = try!(api_ctxt.authenticate(&req.auth.name, &req.auth.token));
+        let payload = try!(api_ctxt.delete_experiment(user, &req.name));
+        Ok(json_response(payload))
+    });
+    let api_ctxt = api_ctxt_master.clone();
+    router.post("/experiments/run", move |r: &mut Request| {
+        let req: v1::ExperimentRequest = try!(read_json(r));
+        let ref user = try!(api_ctxt.authenticate(&req.auth.name, &req.auth.token));
+        let payload = try!(api_ctxt.run_experiment(user, &req.name));
+        Ok(json_response(payload))
+    });
+    let api_ctxt = api_ctxt_master.clone();
+    router.post("/experiments/status", move |r: &mut Request| {
+        let req: v1::ExperimentRequest = try!(read_json(r));
+        let ref user = try!(api_ctxt.authenticate(&req.auth.name, &req.auth.token));
+        let payload = try!(api_ctxt.experiment_status(user, &req.name));
+        Ok(json_response(payload))
+    });
+    let api_ctxt = api_ctxt_master.clone();
+    router.post("/results/:toolchain/:crate/:version/log", move |r: &mut Request| {
+        let req: v1::BuildLogRequest = try!(read_json(r));
+        let ref user = try!(api_ctxt.authenticate(&req.auth.name, &req.auth.token));
+        let payload = try!(api_ctxt.build_log(user, &route_param(r, "toolchain"),
+                                              &route_param(r, "crate"),
+                                              &route_param(r, "version")));
+        Ok(Response::with((status::Ok, payload)).set(known_mime_type("text/plain")))
+    });
+    let api_ctxt = api_ctxt_master.clone();
+    router.post("/users/create", move |r: &mut Request| {
+        let req: v1::CreateUserRequest = try!(read_json(r));
+        let ref user = try!(api_ctxt.authenticate(&req.auth.name, &req.auth.token));
+        let payload = try!(api_ctxt.create_user(user, &v2::CreateUserRequest {
+            name: req.name,
+            role: req.role
+        }));
+        Ok(json_response(payload))
+    });
+    let api_ctxt = api_ctxt_master.clone();
+    router.post("/users/revoke", move |r: &mut Request| {
+        let req: v1::UserRequest = try!(read_json(r));
+        let ref user = try!(api_ctxt.authenticate(&req.auth.name, &req.auth.token));
+        let payload = try!(api_ctxt.revoke_user(user, &req.name));
+        Ok(json_response(payload))
+    });
+    let api_ctxt = api_ctxt_master.clone();
+    router.post("/users/rotate", move |r: &mut Request| {
+        let req: v1::UserRequest = try!(read_json(r));
+        let ref user = try!(api_ctxt.authenticate(&req.auth.name, &req.auth.token));
+        let payload = try!(api_ctxt.rotate_token(user, &req.name));
+        Ok(json_response(payload))
+    });
+    let api_ctxt = api_ctxt_master.clone();
+    router.post("/audit", move |r: &mut Request| {
+        let req: v1::AuditRequest = try!(read_json(r));
+        let ref user = try!(api_ctxt.authenticate(&req.auth.name, &req.auth.token));
+        let payload = try!(api_ctxt.audit_log(user, req.offset, req.limit));
+        Ok(json_response(payload))
+    });
+    let api_ctxt = api_ctxt_master.clone();
+    router.post("/quota", move |r: &mut Request| {
+        let req: v1::QuotaRequest = try!(read_json(r));
+        let ref user = try!(api_ctxt.authenticate(&req.auth.name, &req.auth.token));
+        let payload = try!(api_ctxt.quota(user));
+        Ok(json_response(payload))
     });
     let api_ctxt = api_ctxt_master.clone();
     router.post("/self-test", move |r: &mut Request| {
-        let mut body = String::new();
-        try!(r.body.read_to_string(&mut body).map_err(|e| Error::from(e)));
-        let payload = try!(api_ctxt.self_test(&body));
-        Ok(Response::with((status::Ok, payload)).set(known_mime_type("application/json")))
+        let req: v1::SelfTestRequest = try!(read_json(r));
+        let ref user = try!(api_ctxt.authenticate(&req.auth.name, &req.auth.token));
+        let payload = try!(api_ctxt.self_test(user));
+        Ok(json_response(payload))
     });
 
     return router;
 }
 
+/// The v2 API takes credentials from the `Authorization` header
+fn api_chain_v2(api_ctxt_master: Arc<Ctxt>) -> Chain {
+    let mut router = Router::new();
+
+    let api_ctxt = api_ctxt_master.clone();
+    router.post("/custom_build", move |r: &mut Request| {
+        let ref user = try!(current_user(r));
+        let ref req: v2::CustomBuildRequest = try!(read_json(r));
+        let payload = try!(api_ctxt.custom_build(user, req));
+        Ok(json_response(payload))
+    });
+    let api_ctxt = api_ctxt_master.clone();
+    router.post("/crate_build", move |r: &mut Request| {
+        let ref user = try!(current_user(r));
+        let ref req: v2::CrateBuildRequest = try!(read_json(r));
+        let payload = try!(api_ctxt.crate_build(user, req));
+        Ok(json_response(payload))
+    });
+    let api_ctxt = api_ctxt_master.clone();
+    router.post("/report", move |r: &mut Request| {
+        let ref user = try!(current_user(r));
+        let req: v2::ReportRequest = try!(read_json(r));
+        let payload = try!(api_ctxt.report(user, &req.kind));
+        Ok(json_response(payload))
+    });
+    let api_ctxt = api_ctxt_master.clone();
+    router.post("/sync_index", move |r: &mut Request| {
+        let ref user = try!(current_user(r));
+        let payload = try!(api_ctxt.sync_index(user));
+        Ok(json_response(payload))
+    });
+    let api_ctxt = api_ctxt_master.clone();
+    router.get("/experiments", move |r: &mut Request| {
+        let ref user = try!(current_user(r));
+        let payload = try!(api_ctxt.list_experiments(user));
+        Ok(json_response(payload))
+    });
+    let api_ctxt = api_ctxt_master.clone();
+    router.post("/experiments", move |r: &mut Request| {
+        let ref user = try!(current_user(r));
+        let ref req: v2::CreateExperimentRequest = try!(read_json(r));
+        let payload = try!(api_ctxt.create_experiment(user, req));
+        Ok(json_response(payload))
+    });
+    let api_ctxt = api_ctxt_master.clone();
+    router.get("/experiments/:name", move |r: &mut Request| {
+        let ref user = try!(current_user(r));
+        let payload = try!(api_ctxt.show_experiment(user, &route_param(r, "name")));
+        Ok(json_response(payload))
+    });
+    let api_ctxt = api_ctxt_master.clone();
+    router.delete("/experiments/:name", move |r: &mut Request| {
+        let ref user = try!(current_user(r));
+        let payload = try!(api_ctxt.delete_experiment(user, &route_param(r, "name")));
+        Ok(json_response(payload))
+    });
+    let api_ctxt = api_ctxt_master.clone();
+    router.post("/experiments/:name/run", move |r: &mut Request| {
+        let ref user = try!(current_user(r));
+        let payload = try!(api_ctxt.run_experiment(user, &route_param(r, "name")));
+        Ok(json_response(payload))
+    });
+    let api_ctxt = api_ctxt_master.clone();
+    router.get("/experiments/:name/status", move |r: &mut Request| {
+        let ref user = try!(current_user(r));
+        let payload = try!(api_ctxt.experiment_status(user, &route_param(r, "name")));
+        Ok(json_response(payload))
+    });
+    let api_ctxt = api_ctxt_master.clone();
+    router.get("/results/:toolchain/:crate/:version/log", move |r: &mut Request| {
+        let ref user = try!(current_user(r));
+        let payload = try!(api_ctxt.build_log(user, &route_param(r, "toolchain"),
+                                              &route_param(r, "crate"),
+                                              &route_param(r, "version")));
+        Ok(Response::with((status::Ok, payload)).set(known_mime_type("text/plain")))
+    });
+    let api_ctxt = api_ctxt_master.clone();
+    router.post("/users", move |r: &mut Request| {
+        let ref user = try!(current_user(r));
+        let ref req: v2::CreateUserRequest = try!(read_json(r));
+        let payload = try!(api_ctxt.create_user(user, req));
+        Ok(json_response(payload))
+    });
+    let api_ctxt = api_ctxt_master.clone();
+    router.delete("/users/:name", move |r: &mut Request| {
+        let ref user = try!(current_user(r));
+        let payload = try!(api_ctxt.revoke_user(user, &route_param(r, "name")));
+        Ok(json_response(payload))
+    });
+    let api_ctxt = api_ctxt_master.clone();
+    router.post("/users/:name/rotate", move |r: &mut Request| {
+        let ref user = try!(current_user(r));
+        let payload = try!(api_ctxt.rotate_token(user, &route_param(r, "name")));
+        Ok(json_response(payload))
+    });
+    let api_ctxt = api_ctxt_master.clone();
+    router.get("/audit", move |r: &mut Request| {
+        let ref user = try!(current_user(r));
+        let offset = try!(query_param_u64(r, "offset", 0));
+        let limit = try!(query_param_u64(r, "limit", DEFAULT_AUDIT_LIMIT));
+        let payload = try!(api_ctxt.audit_log(user, offset, limit));
+        Ok(json_response(payload))
+    });
+    let api_ctxt = api_ctxt_master.clone();
+    router.get("/quota", move |r: &mut Request| {
+        let ref user = try!(current_user(r));
+        let payload = try!(api_ctxt.quota(user));
+        Ok(json_response(payload))
+    });
+    let api_ctxt = api_ctxt_master.clone();
+    router.get("/self-test", move |r: &mut Request| {
+        let ref user = try!(current_user(r));
+        let payload = try!(api_ctxt.self_test(user));
+        Ok(json_response(payload))
+    });
+
+    let mut chain = Chain::new(router);
+    chain.link_before(BearerAuth { ctxt: api_ctxt_master });
+
+    return chain;
+}
+
+/// Authenticates v2 requests that have an `Authorization: Bearer`
+/// header, recording the user as the request's `CurrentUser`. Requests
+/// without one carry on, for the endpoints that don't need a user.
+struct BearerAuth {
+    ctxt: Arc<Ctxt>
+}
+
+struct CurrentUser;
+
+impl Key for CurrentUser {
+    type Value = User;
+}
+
+impl BeforeMiddleware for BearerAuth {
+    fn before(&self, r: &mut Request) -> IronResult<()> {
+        let credentials = match r.headers.get::<Authorization<String>>() {
+            Some(&Authorization(ref value)) => v2::Credentials::from_header(value),
+            None => return Ok(())
+        };
+        let credentials = match credentials {
+            Some(credentials) => credentials,
+            None => return Err(IronError::from(Error::AuthError))
+        };
+        let user = try!(self.ctxt.authenticate(&credentials.name, &credentials.token));
+        r.extensions.insert::<CurrentUser>(user);
+
+        Ok(())
+    }
+}
+
+fn current_user(r: &Request) -> Result<User, Error> {
+    r.extensions.get::<CurrentUser>().cloned().ok_or(Error::AuthError)
+}
+
+fn read_json<T: Decodable>(r: &mut Request) -> Result<T, Error> {
+    let mut body = String::new();
+    try!(r.body.read_to_string(&mut body));
+
+    Ok(try!(json::decode(&body)))
+}
+
+fn json_response(payload: String) -> Response {
+    Response::with((status::Ok, payload)).set(known_mime_type("application/json"))
+}
+
+fn route_param(r: &Request, name: &str) -> String {
+    let params = r.extensions.get::<Router>().expect("request should have been routed");
+    String::from(params.find(name).expect("route should have named parameter"))
+}
+
+/// A numeric `?name=value` parameter, or `default` if it's missing
+fn query_param_u64(r: &Request, name: &str, default: u64) -> Result<u64, Error> {
+    let query = match r.url.query {
+        Some(ref query) => query,
+        None => return Ok(default)
+    };
+    for pair in query.split('&') {
+        let mut parts = pair.splitn(2, '=');
+        if parts.next() == Some(name) {
+            let value = parts.next().unwrap_or("");
+            return value.parse().map_err(|_| {
+                Error::BadRequest(format!("bad {} in query: {}", name, value))
+            });
+        }
+    }
+
+    Ok(default)
+}
+
 fn static_router() -> Router {
     let mut router = Router::new();
     router.get("/", move |_: &mut Request| {
@@ -166,7 +491,13 @@ fn get_static_file(name: &str) -> Result<String, Error> {
     path.push(asset_dir);
     path.push(name);
 
-    let mut file = try!(File::open(path));
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(Error::NotFound(format!("no such file: {}", name)));
+        }
+        Err(e) => return Err(Error::from(e))
+    };
 
     let mut s = String::new();
     try!(file.read_to_string(&mut s));
@@ -190,7 +521,42 @@ fn get_mime_type(name: &str) -> Result<&'static str, Error> {
 pub enum Error {
     BadMimeType,
     StdError(Box<StdError + Send>),
-    AuthError
+    DbError(String),
+    AuthError,
+    /// The request was understood but its contents are invalid,
+    /// e.g. a malformed toolchain
+    BadRequest(String),
+    /// The user is known but may not do this
+    Forbidden(String),
+    NotFound(String),
+    /// One of the user's limits would be exceeded
+    QuotaExceeded(String)
+}
+
+impl Error {
+    /// What the client is told
+    fn api_error(&self) -> (status::Status, v1::ApiError) {
+        use api::v1::ErrorCode;
+
+        let (status, code) = match *self {
+            Error::BadRequest(_) => (status::BadRequest, ErrorCode::BadRequest),
+            Error::AuthError => (status::Unauthorized, ErrorCode::AuthenticationFailed),
+            Error::Forbidden(_) => (status::Forbidden, ErrorCode::Forbidden),
+            Error::QuotaExceeded(_) => (status::TooManyRequests, ErrorCode::QuotaExceeded),
+            Error::NotFound(_) |
+            Error::BadMimeType => (status::NotFound, ErrorCode::NotFound),
+            Error::StdError(_) |
+            Error::DbError(_) => {
+                let e = v1::ApiError {
+                    details: Some(self.to_string()),
+                    ..v1::ApiError::new(ErrorCode::Internal, "internal server error")
+                };
+                return (status::InternalServerError, e);
+            }
+        };
+
+        (status, v1::ApiError::new(code, self.description()))
+    }
 }
 
 impl StdError for Error {
@@ -198,7 +564,12 @@ impl StdError for Error {
         match *self {
             Error::BadMimeType => "bad mime type",
             Error::StdError(ref e) => e.description(),
-            Error::AuthError => "authentication failure"
+            Error::DbError(ref s) => &**s,
+            Error::AuthError => "authentication failed",
+            Error::BadRequest(ref s) => &**s,
+            Error::Forbidden(ref s) => &**s,
+            Error::NotFound(ref s) => &**s,
+            Error::QuotaExceeded(ref s) => &**s
         }
     }
 
@@ -216,10 +587,12 @@ impl Display for Error {
     }
 }
 
+/// Every error goes back to the client as a `v1::ApiError`
 impl From<Error> for IronError {
     fn from(e: Error) -> IronError {
-        // FIXME
-        IronError::new(e, status::InternalServerError)
+        let (status, ref body) = e.api_error();
+        let body = json::encode(body).expect("api errors should always encode");
+        IronError::new(e, (status, body, known_mime_type("application/json")))
     }
 }
 
@@ -237,7 +610,18 @@ impl From<json::EncoderError> for Error {
 
 impl From<json::DecoderError> for Error {
     fn from(e: json::DecoderError) -> Error {
-        Error::StdError(Box::new(e))
+        match e {
+            // Raised by our own `Decodable` impls, e.g. for `Toolchain`,
+            // when a well-formed request contains bogus values
+            json::DecoderError::ApplicationError(msg) => Error::BadRequest(msg),
+            e => Error::BadRequest(format!("bad JSON: {}", e))
+        }
+    }
+}
+
+impl From<api::ParseToolchainError> for Error {
+    fn from(e: api::ParseToolchainError) -> Error {
+        Error::BadRequest(e.to_string())
     }
 }
 
@@ -255,6 +639,29 @@ impl From<log::SetLoggerError> for Error {
 
 impl From<engine::Error> for Error {
     fn from(e: engine::Error) -> Error {
+        match e {
+            engine::Error::UnknownToolchain(_) |
+            engine::Error::MissingRanking => Error::BadRequest(e.to_string()),
+            // The only limit on jobs is the daily task quota
+            engine::Error::TooManyJobs { jobs, max_jobs } => {
+                Error::QuotaExceeded(format!("{} crate builds would exceed the daily task \
+                                              quota; {} left today", jobs, max_jobs))
+            }
+            e => Error::StdError(Box::new(e))
+        }
+    }
+}
+
+/// Errors from the database and reports aren't `Send`, so only their
+/// messages are kept
+impl From<Box<StdError>> for Error {
+    fn from(e: Box<StdError>) -> Error {
+        Error::DbError(e.to_string())
+    }
+}
+
+impl From<index::Error> for Error {
+    fn from(e: index::Error) -> Error {
         Error::StdError(Box::new(e))
     }
 }
@@ -265,75 +672,194 @@ impl From<std::string::FromUtf8Error> for Error {
     }
 }
 
-mod api_v1 {
+/// What the API does, for any version. Every operation but
+/// `authenticate` is done on behalf of a user, and
+/// checks their role first. Operations that change anything are
+/// recorded in the audit log.
+mod ctxt {
     use super::Error;
-    use rustc_serialize::json;
-    use api::v1;
+    use rustc_serialize::json::{self, Json};
+    use std::collections::BTreeMap;
+    use std::i64;
+    use std::sync::Mutex;
+    use api::{self, v1, v2, Role, Toolchain};
+    use db::{BuildResultKey, Database, Experiment, NewAuditEntry, NewExperiment, Page, Usage,
+             User};
+    use engine::scheduler::Scheduler;
+    use index::{self, Index};
+    use reports;
 
     pub struct Ctxt {
-        users: Vec<(String, String)>
+        db: Mutex<Database>,
+        index_config: index::Config,
+        scheduler: Scheduler,
+        quotas: Quotas
+    }
+
+    /// Limits on what each user can schedule, so no one can flood the
+    /// workers. A missing limit is unlimited.
+    #[derive(RustcEncodable, RustcDecodable)]
+    #[derive(Clone, Debug, Default)]
+    pub struct Quotas {
+        /// Running named experiments with outstanding jobs. Ad-hoc
+        /// crate builds have no experiment, so are only held back by
+        /// `tasks_per_day`.
+        pub concurrent_experiments: Option<u64>,
+        /// Crate build tasks per day, UTC
+        pub tasks_per_day: Option<u64>,
+        pub custom_builds_per_day: Option<u64>
     }
 
     impl Ctxt {
-        pub fn new(users: Vec<(String, String)>) -> Ctxt {
-            Ctxt { users: users }
+        pub fn new(db: Database, index_config: index::Config, scheduler: Scheduler,
+                   quotas: Quotas) -> Ctxt {
+            Ctxt {
+                db: Mutex::new(db),
+                index_config: index_config,
+                scheduler: scheduler,
+                quotas: quotas
+            }
         }
 
-        pub fn custom_build(&self, req: &str) -> Result<String, Error> {
-            let ref req: v1::CustomBuildRequest = try!(json::decode(req));
-
-            info!("custom_build: {:?}", req);
-
-            try!(self.authorize(&req.auth));
-
-            let script = "schedule-tasks.js";
-            let ref args = ["custom-build", &*req.repo_url, &*req.commit_sha];
-            let res = try!(node_exec(script, args));
-            Ok(res)
+        pub fn authenticate(&self, name: &str, token: &str) -> Result<User, Error> {
+            let db = self.db.lock().unwrap_or_else(|p| p.into_inner());
+            match try!(db.authenticate(name, token)) {
+                Some(user) => Ok(user),
+                None => Err(Error::AuthError)
+            }
         }
 
-        pub fn crate_build(&self, req: &str) -> Result<String, Error> {
-            let ref req: v1::CrateBuildRequest = try!(json::decode(req));
-
-            info!("crate_build: {:?}", req);
+        pub fn custom_build(&self, user: &User, req: &v2::CustomBuildRequest
+                            ) -> Result<String, Error> {
+            info!("custom_build: {} {:?}", user.name, req);
+
+            self.audited(user, "custom_build", try!(json::encode(req)), || {
+                try!(require(user, Role::Scheduler));
+
+                try!(Toolchain::custom(&req.commit_sha, Some(&req.repo_url[..])));
+
+                // Taken before the lock is released for the build, and
+                // given back if it fails
+                {
+                    let db = self.db.lock().unwrap_or_else(|p| p.into_inner());
+                    let limit = self.quotas.custom_builds_per_day;
+                    if !try!(db.reserve_custom_build(&user.name, limit)) {
+                        return Err(Error::QuotaExceeded(format!(
+                            "{} has used all {} custom builds allowed today",
+                            user.name, limit.unwrap_or(0))));
+                    }
+                }
 
-            try!(self.authorize(&req.auth));
+                let script = "schedule-tasks.js";
+                let ref args = ["custom-build", &*req.repo_url, &*req.commit_sha];
+                let res = node_exec(script, args);
+
+                let db = self.db.lock().unwrap_or_else(|p| p.into_inner());
+                let ref res = match res {
+                    Ok(res) => res,
+                    Err(e) => {
+                        self.refund_custom_build(&db, user);
+                        return Err(e);
+                    }
+                };
+                self.audit(&db, user, "custom_build", try!(json::encode(req)),
+                           String::from(res.stdout.trim()));
+                if !res.success {
+                    self.refund_custom_build(&db, user);
+                }
 
-            let script = "schedule-tasks.js";
-            let ref args = ["crate-build", &*req.toolchain, "--most-recent-only"];
-            let res = try!(node_exec(script, args));
-            Ok(res)
+                Ok(try!(json::encode(res)))
+            })
         }
 
-        pub fn report(&self, req: &str) -> Result<String, Error> {
-            let ref req: v1::ReportRequest = try!(json::decode(req));
+        pub fn crate_build(&self, user: &User, req: &v2::CrateBuildRequest
+                           ) -> Result<String, Error> {
+            info!("crate_build: {} {:?}", user.name, req);
+
+            self.audited(user, "crate_build", try!(json::encode(req)), || {
+                try!(require(user, Role::Scheduler));
+
+                let index = try!(Index::load(&self.index_config));
+                let db = self.db.lock().unwrap_or_else(|p| p.into_inner());
+                let max_jobs = try!(self.remaining_tasks(&db, user));
+                let scheduled = try!(self.scheduler.schedule_crate_builds(&db, &index,
+                                                                          &req.toolchain,
+                                                                          &req.selection,
+                                                                          max_jobs));
+                // Audited first, since the tasks exist whatever fails next
+                self.audit(&db, user, "crate_build", try!(json::encode(req)),
+                           format!("experiment {} with {} jobs",
+                                   scheduled.experiment_id, scheduled.jobs));
+                self.record_usage(&db, user, scheduled.jobs);
+
+                let ref res = v1::CrateBuildResponse {
+                    experiment_id: scheduled.experiment_id,
+                    jobs: scheduled.jobs as u64
+                };
+
+                Ok(try!(json::encode(res)))
+            })
+        }
 
-            info!("report: {:?}", req);
+        pub fn report(&self, user: &User, kind: &v1::ReportKind) -> Result<String, Error> {
+            info!("report: {} {:?}", user.name, kind);
 
-            try!(self.authorize(&req.auth));
+            try!(require(user, Role::Reporter));
 
             let script = "print-report.js";
-            let res = match req.kind {
+            let res = match *kind {
                 v1::ReportKind::Comparison {
                     ref toolchain_from, ref toolchain_to
                 } => {
-                    let ref args = ["comparison", &**toolchain_from, &**toolchain_to];
-                    try!(node_exec(script, args))
+                    let db = self.db.lock().unwrap_or_else(|p| p.into_inner());
+                    let ref report = try!(reports::comparison_report(&db,
+                                                                     toolchain_from,
+                                                                     toolchain_to));
+                    try!(json::encode(report))
                 }
                 v1::ReportKind::Toolchain(ref t) => {
+                    let ref t = t.to_string();
                     let ref args = ["toolchain", &**t];
-                    try!(node_exec(script, args))
+                    try!(json::encode(&try!(node_exec(script, args))))
+                }
+                v1::ReportKind::Ices { ref toolchain } => {
+                    let db = self.db.lock().unwrap_or_else(|p| p.into_inner());
+                    let ref report = try!(reports::ice_report(&db, toolchain));
+                    try!(json::encode(report))
                 }
             };
             Ok(res)
         }
 
-        pub fn self_test(&self, req: &str) -> Result<String, Error> {
-            let ref req: v1::SelfTestRequest = try!(json::decode(req));
+        pub fn sync_index(&self, user: &User) -> Result<String, Error> {
+            info!("sync_index: {}", user.name);
 
-            info!("self-test: {:?}", req);
+            self.audited(user, "sync_index", String::from("{}"), || {
+                try!(require(user, Role::Admin));
 
-            try!(self.authorize(&req.auth));
+                let index = try!(Index::load(&self.index_config));
+                let db = self.db.lock().unwrap_or_else(|p| p.into_inner());
+                let stats = try!(db.sync_index(&index));
+
+                info!("synced index: {:?}", stats);
+                self.audit(&db, user, "sync_index", String::from("{}"),
+                           format!("{} crate versions, {} dependency edges, {} ranked crates",
+                                   stats.crate_versions, stats.dep_edges, stats.ranked_crates));
+
+                let ref res = v1::SyncIndexResponse {
+                    crate_versions: stats.crate_versions,
+                    dep_edges: stats.dep_edges,
+                    ranked_crates: stats.ranked_crates
+                };
+
+                Ok(try!(json::encode(res)))
+            })
+        }
+
+        pub fn self_test(&self, user: &User) -> Result<String, Error> {
+            info!("self-test: {}", user.name);
+
+            try!(require(user, Role::Reporter));
 
             let ref res = v1::StdIoResponse {
                 stdout: String::from("self-test succeeded"),
@@ -344,19 +870,402 @@ mod api_v1 {
             Ok(try!(json::encode(res)))
         }
 
-        fn authorize(&self, auth: &v1::Auth) -> Result<(), Error> {
-            for &(ref name, ref token) in &self.users {
-                if name == &auth.name && token == &auth.token {
-                    return Ok(());
+        pub fn create_experiment(&self, user: &User, req: &v2::CreateExperimentRequest
+                                 ) -> Result<String, Error> {
+            info!("create_experiment: {} {:?}", user.name, req);
+
+            self.audited(user, "create_experiment", try!(json::encode(req)), || {
+                try!(require(user, Role::Scheduler));
+                try!(check_name(&req.name));
+
+                let db = self.db.lock().unwrap_or_else(|p| p.into_inner());
+                let added = try!(db.add_experiment(&NewExperiment {
+                    name: req.name.clone(),
+                    owner: user.name.clone(),
+                    toolchain_from: req.toolchain_from.clone(),
+                    toolchain_to: req.toolchain_to.clone(),
+                    crate_selection: req.selection.clone()
+                }));
+                if !added {
+                    return Err(Error::BadRequest(format!("experiment {} already exists", req.name)));
+                }
+                self.audit(&db, user, "create_experiment", try!(json::encode(req)),
+                           format!("experiment {}", req.name));
+
+                let ref res = v1_experiment(try!(get_experiment(&db, &req.name)));
+                Ok(try!(json::encode(res)))
+            })
+        }
+
+        pub fn list_experiments(&self, user: &User) -> Result<String, Error> {
+            info!("list_experiments: {}", user.name);
+
+            try!(require(user, Role::Reporter));
+
+            let db = self.db.lock().unwrap_or_else(|p| p.into_inner());
+            let experiments = try!(db.list_experiments());
+            let ref res: Vec<v1::Experiment> = experiments.into_iter().map(v1_experiment).collect();
+
+            Ok(try!(json::encode(res)))
+        }
+
+        pub fn show_experiment(&self, user: &User, name: &str) -> Result<String, Error> {
+            info!("show_experiment: {} {}", user.name, name);
+
+            try!(require(user, Role::Reporter));
+
+            let db = self.db.lock().unwrap_or_else(|p| p.into_inner());
+            let ref res = v1_experiment(try!(get_experiment(&db, name)));
+
+            Ok(try!(json::encode(res)))
+        }
+
+        /// The stored excerpt of a crate build's log, as plain text
+        pub fn build_log(&self, user: &User, toolchain: &str, crate_name: &str,
+                         crate_vers: &str) -> Result<String, Error> {
+            info!("build_log: {} {} {}-{}", user.name, toolchain, crate_name, crate_vers);
+
+            try!(require(user, Role::Reporter));
+
+            let key = BuildResultKey {
+                toolchain: try!(toolchain.parse()),
+                crate_name: String::from(crate_name),
+                crate_vers: String::from(crate_vers)
+            };
+            let db = self.db.lock().unwrap_or_else(|p| p.into_inner());
+            let log = match try!(db.get_build_log(&key)) {
+                Some(log) => log,
+                None => return Err(Error::NotFound(format!("no log for {}-{} on {}",
+                                                           crate_name, crate_vers, toolchain)))
+            };
+
+            Ok(format!("task {}\n\nerrors:\n{}\n\nlast lines:\n{}\n",
+                       log.task_id, log.errors, log.tail))
+        }
+
+        /// Only the owner or an admin can delete an experiment
+        pub fn delete_experiment(&self, user: &User, name: &str) -> Result<String, Error> {
+            info!("delete_experiment: {} {}", user.name, name);
+
+            self.audited(user, "delete_experiment", name_params(name), || {
+                try!(require(user, Role::Scheduler));
+
+                let db = self.db.lock().unwrap_or_else(|p| p.into_inner());
+                let experiment = try!(get_experiment(&db, name));
+                if experiment.owner != user.name && user.role != Role::Admin {
+                    return Err(Error::Forbidden(format!("{} is owned by {}",
+                                                        name, experiment.owner)));
+                }
+                try!(db.delete_experiment(name));
+                self.audit(&db, user, "delete_experiment", name_params(name),
+                           format!("experiment {}", name));
+
+                let ref res = v1_experiment(experiment);
+                Ok(try!(json::encode(res)))
+            })
+        }
+
+        pub fn run_experiment(&self, user: &User, name: &str) -> Result<String, Error> {
+            info!("run_experiment: {} {}", user.name, name);
+
+            self.audited(user, "run_experiment", name_params(name), || {
+                try!(require(user, Role::Scheduler));
+
+                let index = try!(Index::load(&self.index_config));
+                let db = self.db.lock().unwrap_or_else(|p| p.into_inner());
+                let experiment = try!(get_experiment(&db, name));
+                let quota = try!(self.get_quota(&db, user));
+                if quota.concurrent_experiments.remaining() == Some(0) {
+                    return Err(Error::QuotaExceeded(format!(
+                        "{} already has {} experiments running; wait for one to finish",
+                        user.name, quota.concurrent_experiments.used)));
+                }
+                let max_jobs = try!(self.remaining_tasks(&db, user));
+
+                // Running twice would submit every task again
+                let (created, running) = (v1::ExperimentState::Created,
+                                          v1::ExperimentState::Running);
+                if !try!(db.transition_experiment(name, created, running)) {
+                    return Err(Error::BadRequest(format!("experiment {} is already {}",
+                                                         name, experiment.state)));
+                }
+                let scheduled = match self.scheduler.schedule_experiment(&db, &index, &experiment,
+                                                                         max_jobs) {
+                    Ok(scheduled) => scheduled,
+                    Err(e) => {
+                        // Nothing was submitted, so it can be run again
+                        if let Err(e) = db.transition_experiment(name, running, created) {
+                            error!("failed to reset experiment {}: {}", name, e);
+                        }
+                        return Err(Error::from(e));
+                    }
+                };
+                // Audited first, since the tasks exist whatever fails next
+                self.audit(&db, user, "run_experiment", name_params(name),
+                           format!("experiment {} with {} jobs",
+                                   scheduled.experiment_id, scheduled.jobs));
+                self.record_usage(&db, user, scheduled.jobs);
+
+                let ref res = v1::CrateBuildResponse {
+                    experiment_id: scheduled.experiment_id,
+                    jobs: scheduled.jobs as u64
+                };
+
+                Ok(try!(json::encode(res)))
+            })
+        }
+
+        pub fn experiment_status(&self, user: &User, name: &str) -> Result<String, Error> {
+            info!("experiment_status: {} {}", user.name, name);
+
+            try!(require(user, Role::Reporter));
+
+            let db = self.db.lock().unwrap_or_else(|p| p.into_inner());
+            // Ad-hoc crate builds have jobs but no experiment
+            let progress = try!(db.get_experiment_progress(name));
+            if progress.scheduled == 0 && try!(db.get_experiment(name)).is_none() {
+                return Err(Error::NotFound(format!("no experiment or crate build {}", name)));
+            }
+
+            let ref res = v1::ExperimentProgress {
+                experiment: String::from(name),
+                scheduled: progress.scheduled,
+                finished: progress.finished,
+                exception: progress.exception,
+                outstanding: progress.outstanding
+            };
+
+            Ok(try!(json::encode(res)))
+        }
+
+        pub fn create_user(&self, user: &User, req: &v2::CreateUserRequest
+                           ) -> Result<String, Error> {
+            info!("create_user: {} {:?}", user.name, req);
+
+            self.audited(user, "create_user", try!(json::encode(req)), || {
+                try!(require(user, Role::Admin));
+                try!(check_name(&req.name));
+
+                let db = self.db.lock().unwrap_or_else(|p| p.into_inner());
+                let token = match try!(db.add_user(&req.name, req.role)) {
+                    Some(token) => token,
+                    None => return Err(Error::BadRequest(format!("user {} already exists", req.name)))
+                };
+                // Never the token
+                self.audit(&db, user, "create_user", try!(json::encode(req)),
+                           format!("user {}", req.name));
+
+                let ref res = v1::UserTokenResponse {
+                    name: req.name.clone(),
+                    role: req.role,
+                    token: token
+                };
+                Ok(try!(json::encode(res)))
+            })
+        }
+
+        pub fn revoke_user(&self, user: &User, name: &str) -> Result<String, Error> {
+            info!("revoke_user: {} {}", user.name, name);
+
+            self.audited(user, "revoke_user", name_params(name), || {
+                try!(require(user, Role::Admin));
+
+                let db = self.db.lock().unwrap_or_else(|p| p.into_inner());
+                let ref res = v1::RevokeUserResponse {
+                    name: String::from(name),
+                    revoked: try!(db.revoke_user(name))
+                };
+                self.audit(&db, user, "revoke_user", name_params(name),
+                           format!("revoked: {}", res.revoked));
+
+                Ok(try!(json::encode(res)))
+            })
+        }
+
+        pub fn rotate_token(&self, user: &User, name: &str) -> Result<String, Error> {
+            info!("rotate_token: {} {}", user.name, name);
+
+            self.audited(user, "rotate_token", name_params(name), || {
+                try!(require(user, Role::Admin));
+
+                let db = self.db.lock().unwrap_or_else(|p| p.into_inner());
+                let no_user = || Error::NotFound(format!("no user named {}", name));
+                let target = try!(try!(db.get_user(name)).ok_or_else(&no_user));
+                let token = try!(try!(db.rotate_token(name)).ok_or_else(&no_user));
+                self.audit(&db, user, "rotate_token", name_params(name),
+                           format!("user {}", name));
+
+                let ref res = v1::UserTokenResponse {
+                    name: target.name,
+                    role: target.role,
+                    token: token
+                };
+                Ok(try!(json::encode(res)))
+            })
+        }
+
+        /// The user's limits and what's left of them
+        pub fn quota(&self, user: &User) -> Result<String, Error> {
+            info!("quota: {}", user.name);
+
+            try!(require(user, Role::Reporter));
+
+            let db = self.db.lock().unwrap_or_else(|p| p.into_inner());
+            let ref res = try!(self.get_quota(&db, user));
+
+            Ok(try!(json::encode(res)))
+        }
+
+        fn get_quota(&self, db: &Database, user: &User) -> Result<v1::QuotaResponse, Error> {
+            let usage = try!(db.get_usage(&user.name));
+            let running = try!(db.count_running_experiments(&user.name));
+
+            Ok(v1::QuotaResponse {
+                name: user.name.clone(),
+                concurrent_experiments: v1::QuotaUsage {
+                    used: running,
+                    limit: self.quotas.concurrent_experiments
+                },
+                tasks_today: v1::QuotaUsage {
+                    used: usage.tasks,
+                    limit: self.quotas.tasks_per_day
+                },
+                custom_builds_today: v1::QuotaUsage {
+                    used: usage.custom_builds,
+                    limit: self.quotas.custom_builds_per_day
                 }
+            })
+        }
+
+        /// Counts scheduled crate builds against the user's quota. The
+        /// tasks have been submitted by now, so a failure is only
+        /// logged; reporting it would have the user schedule them again.
+        fn record_usage(&self, db: &Database, user: &User, jobs: usize) {
+            let usage = Usage { tasks: jobs as u64, custom_builds: 0 };
+            if let Err(e) = db.add_usage(&user.name, &usage) {
+                error!("failed to record {} tasks for {}: {}", jobs, user.name, e);
             }
+        }
+
+        fn refund_custom_build(&self, db: &Database, user: &User) {
+            if let Err(e) = db.refund_custom_build(&user.name) {
+                error!("failed to refund custom build for {}: {}", user.name, e);
+            }
+        }
 
-            Err(Error::AuthError)
+        /// How many more crate builds the user may schedule today, for
+        /// the scheduler to enforce
+        fn remaining_tasks(&self, db: &Database, user: &User) -> Result<Option<usize>, Error> {
+            let quota = try!(self.get_quota(db, user));
+            Ok(quota.tasks_today.remaining().map(|n| n as usize))
         }
 
+        /// Pages through the audit log, newest first
+        pub fn audit_log(&self, user: &User, offset: u64, limit: u64) -> Result<String, Error> {
+            info!("audit_log: {} {} {}", user.name, offset, limit);
+
+            try!(require(user, Role::Admin));
+            if offset > i64::MAX as u64 {
+                return Err(Error::BadRequest(format!("offset {} is too large", offset)));
+            }
+
+            let db = self.db.lock().unwrap_or_else(|p| p.into_inner());
+            let entries = try!(db.get_audit_log(Page::new(offset, limit)));
+            let ref res: Vec<v1::AuditEntry> = entries.into_iter().map(|e| v1::AuditEntry {
+                id: e.id,
+                user: e.user,
+                endpoint: e.endpoint,
+                params: e.params,
+                result: e.result,
+                created_at: e.created_at
+            }).collect();
+
+            Ok(try!(json::encode(res)))
+        }
+
+        /// Runs a call that changes something, also recording it in
+        /// the audit log if it was refused because of the user's role,
+        /// ownership or quota. Successful calls record themselves.
+        fn audited<F>(&self, user: &User, endpoint: &str, params: String, f: F
+                      ) -> Result<String, Error>
+            where F: FnOnce() -> Result<String, Error>
+        {
+            let res = f();
+            if let Err(ref e) = res {
+                match *e {
+                    Error::Forbidden(_) | Error::QuotaExceeded(_) => {
+                        let db = self.db.lock().unwrap_or_else(|p| p.into_inner());
+                        self.audit(&db, user, endpoint, params, format!("denied: {}", e));
+                    }
+                    _ => ()
+                }
+            }
+
+            res
+        }
+
+        /// By now the call has happened, so failing to record it is
+        /// only logged
+        fn audit(&self, db: &Database, user: &User, endpoint: &str, params: String,
+                 result: String) {
+            let entry = NewAuditEntry {
+                user: user.name.clone(),
+                endpoint: String::from(endpoint),
+                params: params,
+                result: result
+            };
+            if let Err(e) = db.add_audit_entry(&entry) {
+                error!("failed to audit {} by {}: {}", endpoint, user.name, e);
+            }
+        }
     }
 
-    fn node_exec(script: &str, args: &[&str]) -> Result<String, Error> {
+    /// Checks that the user's role includes `role`
+    fn require(user: &User, role: Role) -> Result<(), Error> {
+        if user.role < role {
+            return Err(Error::Forbidden(format!("{} is a {}; this needs a {}",
+                                                user.name, user.role, role)));
+        }
+
+        Ok(())
+    }
+
+    /// New names must be safe to use in URLs
+    fn check_name(name: &str) -> Result<(), Error> {
+        if !api::is_valid_name(name) {
+            return Err(Error::BadRequest(format!("{:?} isn't a valid name: use up to 64 letters, \
+                                                  digits, '-', '_' and '.'", name)));
+        }
+        Ok(())
+    }
+
+    fn get_experiment(db: &Database, name: &str) -> Result<Experiment, Error> {
+        match try!(db.get_experiment(name)) {
+            Some(experiment) => Ok(experiment),
+            None => Err(Error::NotFound(format!("no experiment named {}", name)))
+        }
+    }
+
+    fn v1_experiment(e: Experiment) -> v1::Experiment {
+        v1::Experiment {
+            name: e.name,
+            owner: e.owner,
+            toolchain_from: e.toolchain_from,
+            toolchain_to: e.toolchain_to,
+            selection: e.crate_selection,
+            created_at: e.created_at,
+            state: e.state
+        }
+    }
+
+    /// `{"name": name}`, for the audit log
+    fn name_params(name: &str) -> String {
+        let mut params = BTreeMap::new();
+        params.insert(String::from("name"), Json::String(String::from(name)));
+        Json::Object(params).to_string()
+    }
+
+    fn node_exec(script: &str, args: &[&str]) -> Result<v1::StdIoResponse, Error> {
         use std::process::Command;
 
         info!("running node: {} {:?}", script, args);
@@ -373,13 +1282,11 @@ mod api_v1 {
                           .current_dir(dir)
                           .output());
 
-        let ref res = v1::StdIoResponse {
+        Ok(v1::StdIoResponse {
             stdout: try!(String::from_utf8(output.stdout)),
             stderr: try!(String::from_utf8(output.stderr)),
             success: output.status.success()
-        };
-
-        Ok(try!(json::encode(res)))
+        })
     }
 }
 
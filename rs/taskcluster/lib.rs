@@ -0,0 +1,726 @@
+extern crate rustc_serialize;
+extern crate hyper;
+extern crate crypto;
+extern crate rand;
+extern crate time;
+#[macro_use]
+extern crate log;
+
+use hyper::Client;
+use hyper::header::{ContentType, Headers};
+use hyper::method::Method;
+use rand::Rng;
+use rustc_serialize::base64::{ToBase64, URL_SAFE};
+use rustc_serialize::json::{self, Json};
+use rustc_serialize::{Decodable, Decoder};
+use std::collections::{BTreeMap, VecDeque};
+use std::error::Error as StdError;
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, Read};
+use time::Tm;
+
+pub const DEFAULT_QUEUE_URL: &'static str = "https://queue.taskcluster.net/v1";
+
+#[derive(RustcEncodable, RustcDecodable)]
+#[derive(Clone)]
+pub struct Config {
+    /// The queue API, normally `DEFAULT_QUEUE_URL`
+    pub queue_url: String,
+    /// Only needed for creating tasks
+    pub credentials: Option<Credentials>
+}
+
+#[derive(RustcEncodable, RustcDecodable)]
+#[derive(Clone)]
+pub struct Credentials {
+    pub client_id: String,
+    pub access_token: String,
+    /// The JSON certificate that comes with temporary credentials
+    pub certificate: Option<String>
+}
+
+/// A task, as given to `Queue::create_task`. Mirrors the tasks built
+/// by `createTaskDescriptor` in scheduler.js.
+#[allow(non_snake_case)]
+#[derive(RustcEncodable)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct TaskDefinition {
+    pub provisionerId: String,
+    pub workerType: String,
+    /// Timestamps as formatted by `timestamp`
+    pub created: String,
+    pub deadline: String,
+    pub retries: u32,
+    pub routes: Vec<String>,
+    pub payload: Payload,
+    pub metadata: Metadata,
+    /// Free-form data for whoever handles the task's results
+    pub extra: Json
+}
+
+/// The payload understood by the docker-worker
+#[allow(non_snake_case)]
+#[derive(RustcEncodable, RustcDecodable)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Payload {
+    pub image: String,
+    pub command: Vec<String>,
+    pub env: BTreeMap<String, String>,
+    /// In seconds
+    pub maxRunTime: u64,
+    /// Files to upload when the task finishes, by artifact name
+    pub artifacts: BTreeMap<String, Artifact>
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Artifact {
+    /// `file` or `directory`. Serialized as `type`.
+    pub kind: String,
+    pub path: String,
+    pub expires: String
+}
+
+#[derive(RustcEncodable, RustcDecodable)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Metadata {
+    pub name: String,
+    pub description: String,
+    pub owner: String,
+    pub source: String
+}
+
+#[allow(non_snake_case)]
+#[derive(RustcEncodable, RustcDecodable)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct TaskStatus {
+    pub taskId: String,
+    pub provisionerId: String,
+    pub workerType: String,
+    pub deadline: String,
+    /// `unscheduled`, `pending`, `running`, `completed`, `failed`
+    /// or `exception`
+    pub state: String,
+    pub retriesLeft: u32,
+    pub runs: Vec<RunInfo>
+}
+
+#[allow(non_snake_case)]
+#[derive(RustcEncodable, RustcDecodable)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunInfo {
+    pub runId: u64,
+    pub state: String
+}
+
+/// The queue wraps task statuses in an object
+#[derive(RustcDecodable)]
+struct TaskStatusResponse {
+    status: TaskStatus
+}
+
+pub struct Queue {
+    config: Config,
+    client: Client
+}
+
+impl Queue {
+    pub fn new(config: Config) -> Queue {
+        Queue {
+            config: config,
+            client: Client::new()
+        }
+    }
+
+    /// Submits a task, returning its initial status
+    pub fn create_task(&self, task_id: &str, task: &TaskDefinition) -> Result<TaskStatus, Error> {
+        let url = format!("{}/task/{}", self.config.queue_url, task_id);
+        let body = try!(json::encode(task));
+        let res = try!(self.request(Method::Put, &url, Some(&body)));
+        let res: TaskStatusResponse = try!(decode(&res));
+
+        Ok(res.status)
+    }
+
+    /// Gets the definition of a task
+    pub fn task(&self, task_id: &str) -> Result<TaskDefinition, Error> {
+        let url = format!("{}/task/{}", self.config.queue_url, task_id);
+        let res = try!(self.request(Method::Get, &url, None));
+        let json = try!(Json::from_str(&res).map_err(|e| Error::BadResponse(e.to_string())));
+
+        // `extra` can't be decoded generically, so is taken straight
+        // from the JSON
+        let extra = json.find("extra").cloned().unwrap_or(Json::Null);
+        let mut task: TaskDefinition = try!(Decodable::decode(&mut json::Decoder::new(json))
+                                            .map_err(|e| Error::BadResponse(e.to_string())));
+        task.extra = extra;
+
+        Ok(task)
+    }
+
+    pub fn status(&self, task_id: &str) -> Result<TaskStatus, Error> {
+        let url = format!("{}/task/{}/status", self.config.queue_url, task_id);
+        let res = try!(self.request(Method::Get, &url, None));
+        let res: TaskStatusResponse = try!(decode(&res));
+
+        Ok(res.status)
+    }
+
+    /// The URL of an artifact from a particular run of a task. Public
+    /// artifacts can be downloaded from here without credentials.
+    pub fn artifact_url(&self, task_id: &str, run_id: u64, name: &str) -> String {
+        format!("{}/task/{}/runs/{}/artifacts/{}", self.config.queue_url, task_id, run_id, name)
+    }
+
+    /// The URL of an artifact from the most recent run of a task
+    pub fn latest_artifact_url(&self, task_id: &str, name: &str) -> String {
+        format!("{}/task/{}/artifacts/{}", self.config.queue_url, task_id, name)
+    }
+
+    /// Downloads a public artifact from the most recent run of a task.
+    /// Never signed, since the queue redirects to storage that would
+    /// reject the queue's credentials. Invalid UTF-8 is replaced, which
+    /// is fine for the logs this is meant for. At most `max_bytes` are
+    /// kept, from the start and end of the artifact, with a line
+    /// marking what was skipped in between.
+    pub fn latest_public_artifact(&self, task_id: &str, name: &str, max_bytes: usize
+                                  ) -> Result<String, Error> {
+        let url = self.latest_artifact_url(task_id, name);
+        debug!("GET {}", url);
+
+        let mut res = try!(self.client.get(&url).send());
+        let (mut bytes, skipped) = try!(read_head_and_tail(&mut res, max_bytes));
+        if skipped > 0 {
+            let head = max_bytes / 2;
+            let marker = format!("\n[crater: skipped {} bytes]\n", skipped);
+            let tail = bytes.split_off(head);
+            bytes.extend(marker.bytes());
+            bytes.extend(tail);
+        }
+        let s = String::from_utf8_lossy(&bytes).into_owned();
+        if !res.status.is_success() {
+            return Err(Error::Status(res.status.to_u16(), s));
+        }
+
+        Ok(s)
+    }
+
+    /// Makes a request, signed if there are credentials, returning the
+    /// body of a successful response
+    fn request(&self, method: Method, url: &str, body: Option<&str>) -> Result<String, Error> {
+        debug!("{} {}", method, url);
+
+        let mut headers = Headers::new();
+        if let Some(ref credentials) = self.config.credentials {
+            let (host, port, resource) = try!(split_url(url).ok_or(Error::BadUrl(url.to_string())));
+            let auth = hawk::header(credentials, &method.to_string(), &resource, &host, port,
+                                    time::get_time().sec, &hawk::nonce());
+            headers.set_raw("Authorization", vec![auth.into_bytes()]);
+        }
+
+        let req = self.client.request(method, url);
+        let mut res = match body {
+            Some(body) => {
+                headers.set(ContentType::json());
+                try!(req.headers(headers).body(body).send())
+            }
+            None => try!(req.headers(headers).send())
+        };
+
+        let mut s = String::new();
+        try!(res.read_to_string(&mut s));
+        if !res.status.is_success() {
+            return Err(Error::Status(res.status.to_u16(), s));
+        }
+
+        Ok(s)
+    }
+}
+
+/// Reads `r` to the end keeping only the first and last halves of
+/// `max_bytes`, and returns them along with how many bytes were
+/// dropped from the middle
+fn read_head_and_tail<R: Read>(r: &mut R, max_bytes: usize) -> io::Result<(Vec<u8>, u64)> {
+    let head_len = max_bytes / 2;
+    let tail_len = max_bytes - head_len;
+
+    let mut head = Vec::new();
+    try!(r.by_ref().take(head_len as u64).read_to_end(&mut head));
+
+    let mut tail = VecDeque::new();
+    let mut skipped = 0;
+    let mut buf = [0; 8192];
+    loop {
+        let n = match r.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e)
+        };
+        for &b in &buf[..n] {
+            tail.push_back(b);
+            if tail.len() > tail_len {
+                tail.pop_front();
+                skipped += 1;
+            }
+        }
+    }
+
+    head.extend(tail);
+    Ok((head, skipped))
+}
+
+/// Generates a random task id, a v4 UUID in url-safe base64
+pub fn slugid() -> String {
+    let mut bytes = [0; 16];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes[6] = bytes[6] & 0x0f | 0x40;
+    bytes[8] = bytes[8] & 0x3f | 0x80;
+    // Ids starting with '-' confuse command line tools
+    bytes[0] &= 0x7f;
+
+    bytes.to_base64(URL_SAFE)
+}
+
+/// Formats a time the way the queue expects, e.g.
+/// `2015-06-06T12:00:00Z`
+pub fn timestamp(tm: &Tm) -> String {
+    time::at_utc(tm.to_timespec()).rfc3339().to_string()
+}
+
+fn decode<T: Decodable>(s: &str) -> Result<T, Error> {
+    json::decode(s).map_err(|e| Error::BadResponse(e.to_string()))
+}
+
+/// Splits an http(s) URL into host, port and the path and query
+fn split_url(url: &str) -> Option<(String, u16, String)> {
+    let (rest, default_port) = if url.starts_with("https://") {
+        (&url["https://".len()..], 443)
+    } else if url.starts_with("http://") {
+        (&url["http://".len()..], 80)
+    } else {
+        return None;
+    };
+
+    let (authority, resource) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/")
+    };
+    let (host, port) = match authority.rfind(':') {
+        Some(i) => (&authority[..i], match authority[i + 1..].parse() {
+            Ok(port) => port,
+            Err(_) => return None
+        }),
+        None => (authority, default_port)
+    };
+
+    Some((host.to_lowercase(), port, resource.to_string()))
+}
+
+/// Hawk request signing, as required by TaskCluster's authenticated
+/// endpoints. Payloads aren't signed.
+mod hawk {
+    use crypto::hmac::Hmac;
+    use crypto::mac::Mac;
+    use crypto::sha2::Sha256;
+    use rand::{self, Rng};
+    use rustc_serialize::base64::{ToBase64, STANDARD};
+    use rustc_serialize::json::Json;
+    use std::collections::BTreeMap;
+    use super::Credentials;
+
+    pub fn nonce() -> String {
+        rand::thread_rng().gen_ascii_chars().take(6).collect()
+    }
+
+    /// The value of the `Authorization` header
+    pub fn header(credentials: &Credentials, method: &str, resource: &str,
+                  host: &str, port: u16, ts: i64, nonce: &str) -> String {
+        let ext = credentials.certificate.as_ref().map(|c| ext(c));
+        let mac = mac(&credentials.access_token, method, resource, host, port, ts, nonce,
+                      ext.as_ref().map(|e| &e[..]).unwrap_or(""));
+
+        let mut header = format!("Hawk id=\"{}\", ts=\"{}\", nonce=\"{}\", mac=\"{}\"",
+                                 credentials.client_id, ts, nonce, mac);
+        if let Some(ext) = ext {
+            header.push_str(&format!(", ext=\"{}\"", ext));
+        }
+
+        header
+    }
+
+    /// Temporary credentials pass their certificate in `ext`, as
+    /// base64-encoded JSON
+    fn ext(certificate: &str) -> String {
+        let mut ext = BTreeMap::new();
+        let certificate = Json::from_str(certificate)
+            .unwrap_or_else(|_| Json::String(certificate.to_string()));
+        ext.insert(String::from("certificate"), certificate);
+
+        Json::Object(ext).to_string().as_bytes().to_base64(STANDARD)
+    }
+
+    pub fn mac(key: &str, method: &str, resource: &str, host: &str, port: u16,
+               ts: i64, nonce: &str, ext: &str) -> String {
+        let normalized = format!("hawk.1.header\n{}\n{}\n{}\n{}\n{}\n{}\n\n{}\n",
+                                 ts, nonce, method.to_uppercase(), resource,
+                                 host.to_lowercase(), port, ext);
+
+        let mut hmac = Hmac::new(Sha256::new(), key.as_bytes());
+        hmac.input(normalized.as_bytes());
+        hmac.result().code().to_base64(STANDARD)
+    }
+}
+
+impl rustc_serialize::Encodable for Artifact {
+    fn encode<S: rustc_serialize::Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        s.emit_struct("Artifact", 3, |s| {
+            try!(s.emit_struct_field("type", 0, |s| s.emit_str(&self.kind)));
+            try!(s.emit_struct_field("path", 1, |s| s.emit_str(&self.path)));
+            s.emit_struct_field("expires", 2, |s| s.emit_str(&self.expires))
+        })
+    }
+}
+
+impl Decodable for Artifact {
+    fn decode<D: Decoder>(d: &mut D) -> Result<Artifact, D::Error> {
+        d.read_struct("Artifact", 3, |d| {
+            Ok(Artifact {
+                kind: try!(d.read_struct_field("type", 0, |d| d.read_str())),
+                path: try!(d.read_struct_field("path", 1, |d| d.read_str())),
+                expires: try!(d.read_struct_field("expires", 2, |d| d.read_str()))
+            })
+        })
+    }
+}
+
+/// Decodes everything but `extra`, which is left null
+impl Decodable for TaskDefinition {
+    fn decode<D: Decoder>(d: &mut D) -> Result<TaskDefinition, D::Error> {
+        d.read_struct("TaskDefinition", 9, |d| {
+            Ok(TaskDefinition {
+                provisionerId: try!(d.read_struct_field("provisionerId", 0, Decodable::decode)),
+                workerType: try!(d.read_struct_field("workerType", 1, Decodable::decode)),
+                created: try!(d.read_struct_field("created", 2, Decodable::decode)),
+                deadline: try!(d.read_struct_field("deadline", 3, Decodable::decode)),
+                retries: try!(d.read_struct_field("retries", 4, Decodable::decode)),
+                routes: try!(d.read_struct_field("routes", 5, Decodable::decode)),
+                payload: try!(d.read_struct_field("payload", 6, Decodable::decode)),
+                metadata: try!(d.read_struct_field("metadata", 7, Decodable::decode)),
+                extra: Json::Null
+            })
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Http(hyper::Error),
+    Io(io::Error),
+    Encoding(json::EncoderError),
+    BadUrl(String),
+    /// An unsuccessful HTTP status, with the response body
+    Status(u16, String),
+    BadResponse(String)
+}
+
+impl StdError for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Http(_) => "HTTP error",
+            Error::Io(_) => "I/O error",
+            Error::Encoding(_) => "failed to encode request",
+            Error::BadUrl(_) => "bad queue URL",
+            Error::Status(..) => "queue request failed",
+            Error::BadResponse(_) => "unexpected response from queue"
+        }
+    }
+
+    fn cause(&self) -> Option<&StdError> {
+        match *self {
+            Error::Http(ref e) => Some(e as &StdError),
+            Error::Io(ref e) => Some(e as &StdError),
+            Error::Encoding(ref e) => Some(e as &StdError),
+            _ => None
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            Error::Http(ref e) => write!(f, "{}: {}", self.description(), e),
+            Error::Io(ref e) => write!(f, "{}: {}", self.description(), e),
+            Error::Encoding(ref e) => write!(f, "{}: {}", self.description(), e),
+            Error::BadUrl(ref s) |
+            Error::BadResponse(ref s) => write!(f, "{}: {}", self.description(), s),
+            Error::Status(code, ref body) => {
+                write!(f, "{} with status {}: {}", self.description(), code, body)
+            }
+        }
+    }
+}
+
+impl From<hyper::Error> for Error {
+    fn from(e: hyper::Error) -> Error {
+        Error::Http(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<json::EncoderError> for Error {
+    fn from(e: json::EncoderError) -> Error {
+        Error::Encoding(e)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::{hawk, read_head_and_tail, split_url};
+    use rustc_serialize::json::{self, Json};
+    use std::collections::BTreeMap;
+    use std::io::{self, BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc::{self, Receiver};
+    use std::thread;
+
+    /// What the mock server saw
+    struct Request {
+        request_line: String,
+        authorization: Option<String>,
+        body: String
+    }
+
+    /// Serves a single request with `status` and `body`, returning the
+    /// queue URL to use and the request it receives
+    fn mock_server(status: &'static str, body: &'static str) -> (String, Receiver<Request>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = mpsc::channel();
+
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut authorization = None;
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let line = line.trim_right().to_string();
+                if line.is_empty() {
+                    break;
+                }
+                let (name, value) = line.split_at(line.find(':').unwrap());
+                let value = value[1..].trim().to_string();
+                match &*name.to_lowercase() {
+                    "authorization" => authorization = Some(value),
+                    "content-length" => content_length = value.parse().unwrap(),
+                    _ => {}
+                }
+            }
+            let mut req_body = vec![0; content_length];
+            reader.read_exact(&mut req_body).unwrap();
+
+            let mut stream = stream;
+            write!(stream, "HTTP/1.1 {}\r\nContent-Type: application/json\r\n\
+                            Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                   status, body.len(), body).unwrap();
+            tx.send(Request {
+                request_line: request_line.trim_right().to_string(),
+                authorization: authorization,
+                body: String::from_utf8(req_body).unwrap()
+            }).unwrap();
+        });
+
+        (format!("http://{}/v1", addr), rx)
+    }
+
+    fn queue(queue_url: String, credentials: Option<Credentials>) -> Queue {
+        Queue::new(Config {
+            queue_url: queue_url,
+            credentials: credentials
+        })
+    }
+
+    fn credentials() -> Credentials {
+        Credentials {
+            client_id: String::from("crater"),
+            access_token: String::from("secret"),
+            certificate: None
+        }
+    }
+
+    fn task_definition() -> TaskDefinition {
+        let mut env = BTreeMap::new();
+        env.insert(String::from("CRATER_TASK_TYPE"), String::from("crate-build"));
+        let mut extra = BTreeMap::new();
+        extra.insert(String::from("taskType"), Json::String(String::from("crate-build")));
+        let mut crater = BTreeMap::new();
+        crater.insert(String::from("crater"), Json::Object(extra));
+
+        TaskDefinition {
+            provisionerId: String::from("aws-provisioner-v1"),
+            workerType: String::from("cratertest"),
+            created: String::from("2015-06-06T12:00:00Z"),
+            deadline: String::from("2015-06-07T04:00:00Z"),
+            retries: 5,
+            routes: vec![String::from("crater.#")],
+            payload: Payload {
+                image: String::from("brson/crater:3"),
+                command: vec![String::from("/bin/bash")],
+                env: env,
+                maxRunTime: 600,
+                artifacts: BTreeMap::new()
+            },
+            metadata: Metadata {
+                name: String::from("Crater task"),
+                description: String::from("Testing Rust crates for Rust language regressions"),
+                owner: String::from("banderson@mozilla.com"),
+                source: String::from("http://github.com/brson/taskcluster-crater")
+            },
+            extra: Json::Object(crater)
+        }
+    }
+
+    const STATUS: &'static str = r#"{ "status": {
+        "taskId": "fXtU1zm9RKi1Ak0UuYeo8g", "provisionerId": "aws-provisioner-v1",
+        "workerType": "cratertest", "schedulerId": "-", "deadline": "2015-06-07T04:00:00Z",
+        "state": "pending", "retriesLeft": 5,
+        "runs": [{ "runId": 0, "state": "pending", "reasonCreated": "scheduled" }]
+    } }"#;
+
+    #[test]
+    fn hawk_mac() {
+        // The example from the Hawk README
+        let mac = hawk::mac("werxhqb98rpaxn39848xrunpaw3489ruxnpa98w4rxn", "GET",
+                            "/resource/1?b=1&a=2", "example.com", 8000, 1353832234,
+                            "j4h3g2", "some-app-ext-data");
+        assert_eq!(mac, "6R4rV5iE+NPoym+WwjeHzjAGXUtLNIxmo1vpMofpLAE=");
+    }
+
+    #[test]
+    fn split_urls() {
+        assert_eq!(split_url("https://queue.taskcluster.net/v1/task/x?a=b"),
+                   Some((String::from("queue.taskcluster.net"), 443,
+                         String::from("/v1/task/x?a=b"))));
+        assert_eq!(split_url("http://Localhost:8080"),
+                   Some((String::from("localhost"), 8080, String::from("/"))));
+        assert_eq!(split_url("ftp://localhost/"), None);
+    }
+
+    #[test]
+    fn slugids() {
+        let id = slugid();
+        assert_eq!(id.len(), 22);
+        assert!(!id.starts_with("-"));
+        assert!(id.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_'));
+        assert!(id != slugid());
+    }
+
+    #[test]
+    fn artifacts_serialize_type() {
+        let artifact = Artifact {
+            kind: String::from("file"),
+            path: String::from("/home/rust/build/dist/rustc-dev-x86_64-unknown-linux-gnu.tar.gz"),
+            expires: String::from("2015-08-05T12:00:00Z")
+        };
+        let s = json::encode(&artifact).unwrap();
+        assert!(s.contains(r#""type":"file""#));
+        assert_eq!(json::decode::<Artifact>(&s).unwrap(), artifact);
+    }
+
+    #[test]
+    fn artifact_url() {
+        let queue = queue(String::from(DEFAULT_QUEUE_URL), None);
+        assert_eq!(queue.artifact_url("abc", 1, "public/logs/terminal.log"),
+                   "https://queue.taskcluster.net/v1/task/abc/runs/1/artifacts/public/logs/terminal.log");
+    }
+
+    #[test]
+    fn create_task() {
+        let (url, rx) = mock_server("200 OK", STATUS);
+        let task = task_definition();
+        let status = queue(url, Some(credentials())).create_task("fXtU1zm9RKi1Ak0UuYeo8g", &task)
+            .unwrap();
+        assert_eq!(status.state, "pending");
+        assert_eq!(status.runs[0].runId, 0);
+
+        let req = rx.recv().unwrap();
+        assert_eq!(req.request_line, "PUT /v1/task/fXtU1zm9RKi1Ak0UuYeo8g HTTP/1.1");
+        assert!(req.authorization.unwrap().starts_with("Hawk id=\"crater\", ts=\""));
+        let sent = Json::from_str(&req.body).unwrap();
+        assert_eq!(sent.find_path(&["payload", "maxRunTime"]).unwrap().as_u64(), Some(600));
+        assert_eq!(sent.find("extra"), Some(&task.extra));
+    }
+
+    #[test]
+    fn get_task() {
+        let (url, rx) = mock_server("200 OK", r#"{
+            "provisionerId": "aws-provisioner-v1", "workerType": "cratertest",
+            "schedulerId": "-", "created": "2015-06-06T12:00:00Z",
+            "deadline": "2015-06-07T04:00:00Z", "retries": 5, "routes": ["crater.#"],
+            "payload": { "image": "brson/crater:3", "command": ["/bin/bash"],
+                         "env": { "CRATER_TASK_TYPE": "crate-build" },
+                         "maxRunTime": 600, "artifacts": {} },
+            "metadata": { "name": "Crater task",
+                          "description": "Testing Rust crates for Rust language regressions",
+                          "owner": "banderson@mozilla.com",
+                          "source": "http://github.com/brson/taskcluster-crater" },
+            "extra": { "crater": { "taskType": "crate-build" } }
+        }"#);
+        let task = queue(url, None).task("fXtU1zm9RKi1Ak0UuYeo8g").unwrap();
+        assert_eq!(task, task_definition());
+
+        let req = rx.recv().unwrap();
+        assert_eq!(req.request_line, "GET /v1/task/fXtU1zm9RKi1Ak0UuYeo8g HTTP/1.1");
+        assert_eq!(req.authorization, None);
+    }
+
+    #[test]
+    fn get_public_artifact() {
+        let (url, rx) = mock_server("200 OK", "Compiling num v0.1.0\n");
+        let log = queue(url, Some(credentials()))
+            .latest_public_artifact("abc", "public/logs/terminal.log", 1024).unwrap();
+        assert_eq!(log, "Compiling num v0.1.0\n");
+
+        let req = rx.recv().unwrap();
+        assert_eq!(req.request_line, "GET /v1/task/abc/artifacts/public/logs/terminal.log HTTP/1.1");
+        assert_eq!(req.authorization, None);
+    }
+
+    #[test]
+    fn get_capped_public_artifact() {
+        let (url, _rx) = mock_server("200 OK", "0123456789abcdef");
+        let log = queue(url, None)
+            .latest_public_artifact("abc", "public/logs/terminal.log", 8).unwrap();
+        assert_eq!(log, "0123\n[crater: skipped 8 bytes]\ncdef");
+    }
+
+    #[test]
+    fn keep_head_and_tail() {
+        let read = |s: &str, max_bytes| {
+            read_head_and_tail(&mut io::Cursor::new(s.as_bytes()), max_bytes).unwrap()
+        };
+        assert_eq!(read("abcdef", 8), (b"abcdef".to_vec(), 0));
+        assert_eq!(read("abcdefgh", 4), (b"abgh".to_vec(), 4));
+        assert_eq!(read("abcdefgh", 1), (b"h".to_vec(), 7));
+        assert_eq!(read("abcdefgh", 0), (Vec::new(), 8));
+    }
+
+    #[test]
+    fn get_status_errors() {
+        let (url, _rx) = mock_server("404 Not Found", r#"{ "message": "no such task" }"#);
+        match queue(url, None).status("nope") {
+            Err(Error::Status(404, ref body)) => assert!(body.contains("no such task")),
+            r => panic!("unexpected result {:?}", r)
+        }
+    }
+}